@@ -51,7 +51,18 @@ pub fn build(
     Err(Box::from(err))
 }
 
-pub fn target_output_file(build_args: &[&str], target_str: &str, crate_name: &str) -> PathBuf {
+/// Directory cargo puts a crate's build output in, honoring CARGO_TARGET_DIR
+pub fn target_dir(crate_name: &str) -> PathBuf {
+    let crate_dir = Path::new(crate_name);
+    match std::env::var_os("CARGO_TARGET_DIR") {
+        // Cargo resolves a relative path against the crate it builds
+        Some(dir) => crate_dir.join(dir),
+        None => crate_dir.join("target"),
+    }
+}
+
+/// Directory for a build's artifacts, given the arguments passed to cargo
+pub fn target_profile_dir(build_args: &[&str], target_str: &str, crate_name: &str) -> PathBuf {
     let mut profile = None;
     for arg in build_args {
         if *arg == "--release" {
@@ -66,12 +77,11 @@ pub fn target_output_file(build_args: &[&str], target_str: &str, crate_name: &st
     }
 
     let profile = profile.unwrap_or("debug");
+    target_dir(crate_name).join(target_str).join(profile)
+}
 
-    let path = format!(
-        "{}/target/{}/{}/{}",
-        crate_name, target_str, profile, crate_name
-    );
-    PathBuf::from(path)
+pub fn target_output_file(build_args: &[&str], target_str: &str, crate_name: &str) -> PathBuf {
+    target_profile_dir(build_args, target_str, crate_name).join(crate_name)
 }
 
 pub fn clean(dir: impl AsRef<Path>) -> Result<(), Box<dyn std::error::Error>> {
@@ -5,3 +5,15 @@ pub const TARGET: &str = "i686-unknown-none";
 pub const OUTPUT_BINARY: &str = "windsor.bin";
 
 pub const KERNEL_ELF_PATH_ENV: &str = "WINDSOR_KERNEL_IMG";
+
+/// Kernel section that build-tool fills with the symbol table
+pub const KSYMS_SECTION: &str = ".ksyms";
+pub const KSYMS_MAGIC: &[u8; 4] = b"KSYM";
+
+/// Uncompressed console font for the kernel to embed, staged with the kernel's build artifacts
+pub const CONSOLE_FONT_FILE: &str = "console.psf";
+pub const CONSOLE_FONT_ENV: &str = "WINDSOR_CONSOLE_FONT";
+
+/// Boot splash image for the kernel to embed, staged with the kernel's build artifacts
+pub const SPLASH_FILE: &str = "splash.img";
+pub const SPLASH_ENV: &str = "WINDSOR_SPLASH";
@@ -0,0 +1,23 @@
+use flate2::read::GzDecoder;
+use std::{boxed::Box, io::Read, path::Path, vec::Vec};
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const PSF2_MAGIC: [u8; 4] = [0x72, 0xb5, 0x4a, 0x86];
+
+/// Reads a PSF2 console font for the kernel to embed. Fonts are
+/// usually shipped gzipped, so those are decompressed first.
+pub fn load_psf2(path: &Path) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut data = std::fs::read(path)?;
+
+    if data.starts_with(&GZIP_MAGIC) {
+        let mut decompressed = Vec::new();
+        GzDecoder::new(data.as_slice()).read_to_end(&mut decompressed)?;
+        data = decompressed;
+    }
+
+    if !data.starts_with(&PSF2_MAGIC) {
+        return Err(Box::from("Console font is not a PSF2 font"));
+    }
+
+    Ok(data)
+}
@@ -4,3 +4,6 @@ extern crate std;
 pub mod binary;
 pub mod cargo;
 pub mod config;
+pub mod font;
+pub mod splash;
+pub mod symbols;
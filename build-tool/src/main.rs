@@ -2,7 +2,7 @@
 #![feature(restricted_std)]
 extern crate std;
 
-use build_tool_lib::{binary, cargo, config};
+use build_tool_lib::{binary, cargo, config, font, splash, symbols};
 use colored::Colorize;
 use std::path::Path;
 
@@ -79,10 +79,83 @@ fn build_boot(bargs: &Vec<String>, kernel_path: &String, toolchain: Option<Strin
     Ok(len)
 }
 
-fn build_kernel(kargs: &Vec<String>, toolchain: Option<String>) -> Result<String, String> {
+/// Files given on the command line for the kernel to embed
+#[derive(Default)]
+struct KernelAssets {
+    console_font: Option<String>,
+    splash: Option<String>,
+}
+
+/// Writes an asset next to the kernel's other build artifacts and
+/// points the kernel build script at it through `env`
+fn stage_asset(
+    target_dir: &Path,
+    file: &str,
+    env: &str,
+    data: &[u8],
+    krnl_envs: &mut Vec<(String, String)>,
+) -> Result<(), String> {
+    std::fs::create_dir_all(target_dir).map_err(|e| e.to_string())?;
+    let asset_path = target_dir.join(file);
+    std::fs::write(&asset_path, data).map_err(|e| e.to_string())?;
+
+    let asset_path = std::fs::canonicalize(asset_path).map_err(|e| e.to_string())?;
+    krnl_envs.push((
+        String::from(env),
+        String::from(asset_path.into_os_string().to_string_lossy()),
+    ));
+
+    Ok(())
+}
+
+fn build_kernel(
+    kargs: &Vec<String>,
+    toolchain: Option<String>,
+    assets: &KernelAssets,
+) -> Result<String, String> {
     let krnl_path = std::path::Path::new(config::KRNL_WORKSPACE_NAME);
     let krnl_args: Vec<&str> = kargs.iter().map(|s| s.as_str()).collect();
-    let krnl_envs: Vec<(String, String)> = std::env::vars().collect();
+    let mut krnl_envs: Vec<(String, String)> = std::env::vars().collect();
+    let asset_dir =
+        cargo::target_profile_dir(&krnl_args, config::TARGET, config::KRNL_WORKSPACE_NAME);
+
+    if let Some(console_font) = &assets.console_font {
+        println!(
+            "{} {}",
+            "Embedding console font".green().bold(),
+            console_font
+        );
+
+        let font_data = font::load_psf2(Path::new(console_font)).map_err(|e| e.to_string())?;
+        stage_asset(
+            &asset_dir,
+            config::CONSOLE_FONT_FILE,
+            config::CONSOLE_FONT_ENV,
+            &font_data,
+            &mut krnl_envs,
+        )?;
+    }
+
+    if let Some(splash) = &assets.splash {
+        let (image, format) = splash::load_splash(Path::new(splash)).map_err(|e| e.to_string())?;
+
+        // Staged raw, as it ends up in the ROM compressed along with the rest of the kernel
+        println!(
+            "{} {} ({}, {} bytes)",
+            "Embedding splash image".green().bold(),
+            splash,
+            format,
+            image.len()
+        );
+
+        stage_asset(
+            &asset_dir,
+            config::SPLASH_FILE,
+            config::SPLASH_ENV,
+            &image,
+            &mut krnl_envs,
+        )?;
+    }
 
     println!(
         "{} {}",
@@ -97,15 +170,38 @@ fn build_kernel(kargs: &Vec<String>, toolchain: Option<String>) -> Result<String
 
     cargo::build(krnl_path, &krnl_args, &krnl_envs, toolchain).map_err(|e| e.to_string())?;
 
-    cargo::target_output_file(&krnl_args, config::TARGET, config::KRNL_WORKSPACE_NAME)
-        .into_os_string()
-        .to_str()
-        .map(|s| String::from(s))
-        .ok_or(String::from("Failed to get kernel target output path"))
+    let kernel_elf_file =
+        cargo::target_output_file(&krnl_args, config::TARGET, config::KRNL_WORKSPACE_NAME)
+            .into_os_string()
+            .to_str()
+            .map(|s| String::from(s))
+            .ok_or(String::from("Failed to get kernel target output path"))?;
+
+    let (ksyms_size, left_out) =
+        symbols::embed_symbols(Path::new(&kernel_elf_file)).map_err(|e| e.to_string())?;
+    println!(
+        "{}: {} bytes",
+        "Embedded symbol table".green().bold(),
+        ksyms_size
+    );
+    if left_out > 0 {
+        println!(
+            "{}: {} functions left out of the symbol table",
+            "Warning".yellow().bold(),
+            left_out
+        );
+    }
+
+    Ok(kernel_elf_file)
 }
 
-fn build(kargs: &Vec<String>, bargs: &Vec<String>, toolchain: Option<String>) -> Result<(), String> {
-    let kernel_elf_file = build_kernel(&kargs, toolchain.clone())?;
+fn build(
+    kargs: &Vec<String>,
+    bargs: &Vec<String>,
+    toolchain: Option<String>,
+    assets: &KernelAssets,
+) -> Result<(), String> {
+    let kernel_elf_file = build_kernel(&kargs, toolchain.clone(), assets)?;
     let image_size = build_boot(&bargs, &kernel_elf_file, toolchain)?;
     rom_utilization(image_size, Path::new(&kernel_elf_file)).map_err(|e| e.to_string())?;
     Ok(())
@@ -118,10 +214,13 @@ fn clean() -> Result<(), String> {
     Ok(())
 }
 
-fn parse_args(args: &Vec<String>) -> Result<(Vec<String>, Vec<String>, Option<String>), String> {
+fn parse_args(
+    args: &Vec<String>,
+) -> Result<(Vec<String>, Vec<String>, Option<String>, KernelAssets), String> {
     let mut kernel_args: Vec<String> = vec![];
     let mut boot_args: Vec<String> = vec![];
     let mut toolchain = None;
+    let mut assets = KernelAssets::default();
 
     if args.len() >= 2 {
         let mut for_boot = false;
@@ -156,26 +255,42 @@ fn parse_args(args: &Vec<String>) -> Result<(Vec<String>, Vec<String>, Option<St
                         return Err(std::format!("No toolchain specified"));
                     }
                 }
+                "--font" => {
+                    if let Some(path) = args.get(i + 1) {
+                        assets.console_font = Some(path.clone());
+                        ignore = true;
+                    } else {
+                        return Err(String::from("No console font specified"));
+                    }
+                }
+                "--splash" => {
+                    if let Some(path) = args.get(i + 1) {
+                        assets.splash = Some(path.clone());
+                        ignore = true;
+                    } else {
+                        return Err(String::from("No splash image specified"));
+                    }
+                }
                 a => return Err(std::format!("Unknown argument {}", a)),
             }
         }
     }
 
-    Ok((kernel_args, boot_args, toolchain))
+    Ok((kernel_args, boot_args, toolchain, assets))
 }
 
 fn main() -> Result<(), String> {
     let args: Vec<String> = std::env::args().collect();
 
-    let (kernel_args, boot_args, toolchain) = parse_args(&args)?;
+    let (kernel_args, boot_args, toolchain, assets) = parse_args(&args)?;
 
     if args.len() <= 1 {
-        return build(&kernel_args, &boot_args, toolchain);
+        return build(&kernel_args, &boot_args, toolchain, &assets);
     }
 
     if args[1] == "clean" {
         return clean();
     }
 
-    build(&kernel_args, &boot_args, toolchain)
+    build(&kernel_args, &boot_args, toolchain, &assets)
 }
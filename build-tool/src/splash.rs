@@ -0,0 +1,22 @@
+use std::{boxed::Box, path::Path, vec::Vec};
+
+/// Magic numbers of the formats the kernel can decode
+const FORMATS: [(&str, &[u8]); 3] = [
+    ("QOI", b"qoif"),
+    ("PNG", b"\x89PNG\r\n\x1a\n"),
+    ("BMP", b"BM"),
+];
+
+/// Reads a boot splash image for the kernel to embed. The kernel is
+/// compressed as a whole, so the image is embedded as it is.
+/// Returns the image and the name of its format.
+pub fn load_splash(path: &Path) -> Result<(Vec<u8>, &'static str), Box<dyn std::error::Error>> {
+    let data = std::fs::read(path)?;
+
+    let (format, _) = FORMATS
+        .iter()
+        .find(|(_, magic)| data.starts_with(magic))
+        .ok_or("Splash image is not a QOI, PNG or BMP image")?;
+
+    Ok((data, format))
+}
@@ -0,0 +1,97 @@
+use object::{Object, ObjectSection, ObjectSymbol, SymbolKind};
+use std::{boxed::Box, format, path::Path, string::String, vec::Vec};
+
+use crate::config;
+
+/// Size of the table header, the magic followed by the symbol count
+const HEADER_SIZE: usize = 8;
+/// Size of an entry: address, size and name offset
+const ENTRY_SIZE: usize = 12;
+
+/// Builds the kernel symbol table from the functions in an ELF image,
+/// leaving out the longest names if it wouldn't fit in `max_size` bytes.
+/// Returns the table and the number of functions left out.
+///
+/// The table starts with `KSYMS_MAGIC` and the number of symbols, followed
+/// by entries sorted by address, each holding the address, the size and
+/// the offset of the name in the string area. The NUL terminated names
+/// come last. All fields are little endian u32s.
+pub fn symbol_table(
+    elf: &[u8],
+    max_size: usize,
+) -> Result<(Vec<u8>, usize), Box<dyn std::error::Error>> {
+    let obj = object::read::File::parse(elf)?;
+
+    let mut funcs: Vec<(u32, u32, String)> = obj
+        .symbols()
+        .filter(|s| s.kind() == SymbolKind::Text && s.is_definition() && s.size() > 0)
+        .filter_map(|s| {
+            let name = s.name().ok()?;
+            let name = format!("{:#}", rustc_demangle::demangle(name));
+            Some((s.address() as u32, s.size() as u32, name))
+        })
+        .collect();
+
+    // Identical functions may have been merged under several names
+    funcs.sort_by_key(|(addr, _, _)| *addr);
+    funcs.dedup_by_key(|(addr, _, _)| *addr);
+
+    // Debug builds have far more symbols than fit. The longest names
+    // are mostly generic instances, so they go first.
+    let total = funcs.len();
+    funcs.sort_by_key(|(_, _, name)| name.len());
+    let mut size = HEADER_SIZE;
+    let fits = funcs
+        .iter()
+        .take_while(|(_, _, name)| {
+            size += ENTRY_SIZE + name.len() + 1;
+            size <= max_size
+        })
+        .count();
+    funcs.truncate(fits);
+    funcs.sort_by_key(|(addr, _, _)| *addr);
+
+    let mut entries = Vec::with_capacity(funcs.len() * ENTRY_SIZE);
+    let mut strings = Vec::new();
+    for (addr, size, name) in funcs.iter() {
+        entries.extend_from_slice(&addr.to_le_bytes());
+        entries.extend_from_slice(&size.to_le_bytes());
+        entries.extend_from_slice(&(strings.len() as u32).to_le_bytes());
+        strings.extend_from_slice(name.as_bytes());
+        strings.push(0);
+    }
+
+    let mut table = Vec::with_capacity(HEADER_SIZE + entries.len() + strings.len());
+    table.extend_from_slice(config::KSYMS_MAGIC);
+    table.extend_from_slice(&(funcs.len() as u32).to_le_bytes());
+    table.extend_from_slice(&entries);
+    table.extend_from_slice(&strings);
+
+    Ok((table, total - funcs.len()))
+}
+
+/// Fills the space the kernel linker script reserves for the symbol table.
+/// Returns the size of the table and the number of functions left out.
+pub fn embed_symbols(kernel_elf: &Path) -> Result<(usize, usize), Box<dyn std::error::Error>> {
+    let mut elf = std::fs::read(kernel_elf)?;
+
+    let (offset, size) = {
+        let obj = object::read::File::parse(elf.as_slice())?;
+        let section = obj
+            .section_by_name(config::KSYMS_SECTION)
+            .ok_or("Kernel has no symbol table section")?;
+        section
+            .file_range()
+            .ok_or("Kernel symbol table section has no data")?
+    };
+
+    let (offset, size) = (offset as usize, size as usize);
+    let (table, left_out) = symbol_table(&elf, size)?;
+
+    let reserved = &mut elf[offset..offset + size];
+    reserved.fill(0);
+    reserved[..table.len()].copy_from_slice(&table);
+    std::fs::write(kernel_elf, elf)?;
+
+    Ok((table.len(), left_out))
+}
@@ -1,4 +1,35 @@
+use std::path::{Path, PathBuf};
+
+/// Path of an uncompressed PSF2 font to embed, set by build-tool
+const CONSOLE_FONT_ENV: &str = "WINDSOR_CONSOLE_FONT";
+/// Path of a QOI, PNG or BMP boot splash image to embed, set by build-tool
+const SPLASH_ENV: &str = "WINDSOR_SPLASH";
+
+/// Copies the file named by `env` into `out_dir`, or leaves an empty file
+/// if it's unset. The kernel treats empty assets as missing.
+fn embed_asset(env: &str, out_dir: &Path, name: &str) {
+    println!("cargo:rerun-if-env-changed={}", env);
+
+    let data = match std::env::var(env) {
+        Ok(path) => {
+            println!("cargo:rerun-if-changed={}", path);
+            std::fs::read(&path).unwrap_or_else(|e| panic!("Failed to read {}: {}", path, e))
+        }
+        Err(_) => Vec::new(),
+    };
+    std::fs::write(out_dir.join(name), data).unwrap();
+}
+
 fn main() {
     println!("cargo:rerun-if-changed=kernel.ld");
-    println!("cargo:rustc-link-arg=--script=kernel.ld");
+
+    // Host unit test builds link as regular executables
+    if std::env::var("CARGO_CFG_TARGET_OS").as_deref() == Ok("none") {
+        println!("cargo:rustc-link-arg=--script=kernel.ld");
+    }
+
+    // Without a font the kernel falls back to its built-in one
+    let out_dir = PathBuf::from(std::env::var("OUT_DIR").unwrap());
+    embed_asset(CONSOLE_FONT_ENV, &out_dir, "console.psf");
+    embed_asset(SPLASH_ENV, &out_dir, "splash.img");
 }
@@ -0,0 +1,796 @@
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::Range;
+
+use crate::gfx::{self, Rect};
+use crate::nv2a::accel;
+use crate::print::{self, Utf8Decoder, RGBA};
+use crate::{encoder, font};
+
+const TAB_WIDTH: usize = 8;
+const MAX_PARAMS: usize = 8;
+const ESC: char = '\x1b';
+
+/// ANSI colors 0-7, followed by their bright variants
+const PALETTE: [RGBA; 16] = [
+    RGBA::rgb(0x00, 0x00, 0x00),
+    RGBA::rgb(0xaa, 0x00, 0x00),
+    RGBA::rgb(0x00, 0xaa, 0x00),
+    RGBA::rgb(0xaa, 0x55, 0x00),
+    RGBA::rgb(0x00, 0x00, 0xaa),
+    RGBA::rgb(0xaa, 0x00, 0xaa),
+    RGBA::rgb(0x00, 0xaa, 0xaa),
+    RGBA::rgb(0xaa, 0xaa, 0xaa),
+    RGBA::rgb(0x55, 0x55, 0x55),
+    RGBA::rgb(0xff, 0x55, 0x55),
+    RGBA::rgb(0x55, 0xff, 0x55),
+    RGBA::rgb(0xff, 0xff, 0x55),
+    RGBA::rgb(0x55, 0x55, 0xff),
+    RGBA::rgb(0xff, 0x55, 0xff),
+    RGBA::rgb(0x55, 0xff, 0xff),
+    RGBA::rgb(0xff, 0xff, 0xff),
+];
+
+const DEFAULT_FG: RGBA = print::COLOR_WHITE;
+const DEFAULT_BG: RGBA = print::COLOR_BLACK;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+struct Cell {
+    ch: char,
+    fg: RGBA,
+    bg: RGBA,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum State {
+    Ground,
+    /// After ESC
+    Escape,
+    /// Inside a control sequence, after ESC [
+    Csi,
+}
+
+/// Character grid terminal drawn into a framebuffer, with
+/// scrolling and a subset of the ANSI/VT100 escape sequences.
+/// Input is UTF-8.
+pub struct Console {
+    fb: *mut u8,
+    /// Bytes from one framebuffer row to the next
+    pitch: usize,
+    format: gfx::PixelFormat,
+    /// Pixel position of the top left cell
+    origin: (usize, usize),
+    /// The framebuffer for the 2D engine, which scrolls and clears if set
+    accel: Option<accel::Surface>,
+    /// Rows the GPU may still be scrolling or filling, which
+    /// the CPU waits for before drawing into them
+    gpu_rows: Range<usize>,
+
+    font: &'static font::Font<'static>,
+    scale: u32,
+    /// Cell size in pixels, the font's scaled up
+    cell_size: (usize, usize),
+
+    cols: usize,
+    rows: usize,
+    cells: Vec<Cell>,
+
+    col: usize,
+    row: usize,
+    saved: (usize, usize),
+    cursor_visible: bool,
+
+    fg: RGBA,
+    bg: RGBA,
+    bold: bool,
+    /// Foreground palette index, kept so that bold can brighten it
+    fg_index: Option<usize>,
+
+    state: State,
+    utf8: Utf8Decoder,
+    params: [u16; MAX_PARAMS],
+    param_count: usize,
+    private: bool,
+}
+
+// The framebuffer pointer is owned by the console
+unsafe impl Send for Console {}
+
+impl Console {
+    /// Creates a console covering the area inside the video mode's
+    /// margins, and clears it. Text is drawn in the default font,
+    /// scaled up to keep about 80 columns.
+    pub fn new(fb: *mut u8, vm: &encoder::VideoModeInfo) -> Option<Self> {
+        let font = font::default();
+        let scale = font.scale_for(vm.width.saturating_sub(2 * vm.xmargin));
+        Self::with_font(fb, vm, font, scale)
+    }
+
+    /// Creates a console `rows` rows tall along the bottom of the area
+    /// inside the video mode's margins, in the same font as `new`
+    pub fn with_rows(fb: *mut u8, vm: &encoder::VideoModeInfo, rows: u32) -> Option<Self> {
+        let font = font::default();
+        let inner = inner_area(vm);
+        let scale = font.scale_for(inner.width);
+        let height = (rows * font.height() * scale).min(inner.height);
+        let area = Rect::new(inner.x, inner.bottom() - height as i32, inner.width, height);
+        Self::in_area(fb, vm, area, font, scale)
+    }
+
+    /// Creates a console drawing text in `font`, `scale` times its size.
+    /// Returns None if not even one cell fits inside the margins.
+    pub fn with_font(
+        fb: *mut u8,
+        vm: &encoder::VideoModeInfo,
+        font: &'static font::Font<'static>,
+        scale: u32,
+    ) -> Option<Self> {
+        Self::in_area(fb, vm, inner_area(vm), font, scale)
+    }
+
+    /// Creates a console covering as many cells as fit in `area`
+    fn in_area(
+        fb: *mut u8,
+        vm: &encoder::VideoModeInfo,
+        area: Rect,
+        font: &'static font::Font<'static>,
+        scale: u32,
+    ) -> Option<Self> {
+        let scale = scale.max(1);
+        let cell_size = (
+            (font.width() * scale) as usize,
+            (font.height() * scale) as usize,
+        );
+        let cols = area.width as usize / cell_size.0;
+        let rows = area.height as usize / cell_size.1;
+        if cols == 0 || rows == 0 {
+            return None;
+        }
+
+        let blank = Cell {
+            ch: ' ',
+            fg: DEFAULT_FG,
+            bg: DEFAULT_BG,
+        };
+        let mut console = Self {
+            fb,
+            pitch: vm.pitch() as usize,
+            format: vm.pixel_format,
+            origin: (area.x as usize, area.y as usize),
+            accel: None,
+            gpu_rows: 0..0,
+            font,
+            scale,
+            cell_size,
+            cols,
+            rows,
+            cells: alloc::vec![blank; cols * rows],
+            col: 0,
+            row: 0,
+            saved: (0, 0),
+            cursor_visible: true,
+            fg: DEFAULT_FG,
+            bg: DEFAULT_BG,
+            bold: false,
+            fg_index: None,
+            state: State::Ground,
+            utf8: Utf8Decoder::new(),
+            params: [0; MAX_PARAMS],
+            param_count: 0,
+            private: false,
+        };
+
+        console.redraw();
+        Some(console)
+    }
+
+    /// Scrolls and clears through the 2D engine, given
+    /// the buffer the console draws into as its surface
+    pub fn set_accel(&mut self, surface: Option<accel::Surface>) {
+        self.accel = surface;
+    }
+
+    /// Moves the console to another buffer of the same video mode,
+    /// given with its surface for the 2D engine, and redraws it there
+    pub fn retarget(&mut self, fb: *mut u8, accel: Option<accel::Surface>) {
+        // The new buffer may be the one the GPU is still drawing into
+        self.sync_gpu(self.gpu_rows.start);
+
+        self.fb = fb;
+        self.accel = accel;
+        self.redraw();
+    }
+
+    /// Pixel area the cells cover
+    pub fn area(&self) -> Rect {
+        self.rows_rect(0..self.rows)
+    }
+
+    pub fn cols(&self) -> usize {
+        self.cols
+    }
+
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    /// Column and row of the cursor
+    pub fn cursor(&self) -> (usize, usize) {
+        (self.col.min(self.cols - 1), self.row)
+    }
+
+    /// Redraws every cell, for example after something else drew over the console
+    pub fn redraw(&mut self) {
+        self.draw_all();
+        self.draw_cursor(true);
+    }
+
+    fn draw_all(&mut self) {
+        for idx in 0..self.cells.len() {
+            self.draw_cell(idx % self.cols, idx / self.cols, false);
+        }
+    }
+
+    /// Empty cell in the current colors
+    fn blank(&self) -> Cell {
+        Cell {
+            ch: ' ',
+            fg: self.fg,
+            bg: self.bg,
+        }
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.draw_cursor(false);
+        for &byte in bytes {
+            self.write_byte(byte);
+        }
+        self.draw_cursor(true);
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        match self.state {
+            State::Ground => {
+                let mut utf8 = self.utf8;
+                utf8.push(byte, |ch| self.ground(ch));
+                self.utf8 = utf8;
+            }
+            State::Escape => self.escape(byte),
+            State::Csi => self.csi(byte),
+        }
+    }
+
+    fn ground(&mut self, ch: char) {
+        match ch {
+            '\n' => {
+                self.col = 0;
+                self.line_feed();
+            }
+            '\r' => self.col = 0,
+            '\t' => self.col = ((self.col / TAB_WIDTH + 1) * TAB_WIDTH).min(self.cols - 1),
+            '\x08' => self.col = self.col.min(self.cols - 1).saturating_sub(1),
+            ESC => self.state = State::Escape,
+            _ if ch.is_control() => {}
+            _ => self.put_char(ch),
+        }
+    }
+
+    fn escape(&mut self, byte: u8) {
+        self.state = State::Ground;
+        match byte {
+            b'[' => {
+                self.state = State::Csi;
+                self.params = [0; MAX_PARAMS];
+                self.param_count = 0;
+                self.private = false;
+            }
+            b'c' => self.reset(),
+            b'7' => self.saved = (self.col, self.row),
+            b'8' => (self.col, self.row) = self.saved,
+            _ => {}
+        }
+    }
+
+    fn csi(&mut self, byte: u8) {
+        match byte {
+            b'0'..=b'9' => {
+                let idx = self.param_count.max(1) - 1;
+                self.param_count = self.param_count.max(1);
+                if let Some(param) = self.params.get_mut(idx) {
+                    *param = param
+                        .saturating_mul(10)
+                        .saturating_add((byte - b'0') as u16);
+                }
+            }
+            b';' => self.param_count = (self.param_count.max(1) + 1).min(MAX_PARAMS),
+            b'?' => self.private = true,
+            0x40..=0x7e => {
+                self.state = State::Ground;
+                self.dispatch(byte);
+            }
+            // Intermediate bytes, or garbage
+            _ => {}
+        }
+    }
+
+    /// Parameter `idx`, or `default` if it was left out or zero
+    fn param(&self, idx: usize, default: usize) -> usize {
+        match self.params[idx] {
+            0 => default,
+            n => n as usize,
+        }
+    }
+
+    fn dispatch(&mut self, byte: u8) {
+        if self.private {
+            // Only cursor visibility, ESC [ ? 25 h / l
+            if self.params[0] == 25 {
+                self.cursor_visible = byte == b'h';
+            }
+            return;
+        }
+
+        let n = self.param(0, 1);
+        match byte {
+            b'A' => self.row = self.row.saturating_sub(n),
+            b'B' => self.row = (self.row + n).min(self.rows - 1),
+            b'C' => self.col = (self.col + n).min(self.cols - 1),
+            b'D' => self.col = self.col.min(self.cols - 1).saturating_sub(n),
+            b'G' => self.col = (n - 1).min(self.cols - 1),
+            b'H' | b'f' => {
+                self.row = (self.param(0, 1) - 1).min(self.rows - 1);
+                self.col = (self.param(1, 1) - 1).min(self.cols - 1);
+            }
+            b'J' => {
+                let cursor = self.row * self.cols + self.col.min(self.cols - 1);
+                match self.params[0] {
+                    0 => self.clear(cursor..self.cells.len()),
+                    1 => self.clear(0..cursor + 1),
+                    _ => self.clear(0..self.cells.len()),
+                }
+            }
+            b'K' => {
+                let start = self.row * self.cols;
+                let cursor = start + self.col.min(self.cols - 1);
+                match self.params[0] {
+                    0 => self.clear(cursor..start + self.cols),
+                    1 => self.clear(start..cursor + 1),
+                    _ => self.clear(start..start + self.cols),
+                }
+            }
+            b'm' => self.select_graphic_rendition(),
+            b's' => self.saved = (self.col, self.row),
+            b'u' => (self.col, self.row) = self.saved,
+            _ => {}
+        }
+    }
+
+    fn select_graphic_rendition(&mut self) {
+        for idx in 0..self.param_count.max(1) {
+            match self.params[idx] {
+                0 => {
+                    self.fg = DEFAULT_FG;
+                    self.bg = DEFAULT_BG;
+                    self.bold = false;
+                    self.fg_index = None;
+                }
+                1 => {
+                    self.bold = true;
+                    if let Some(color) = self.fg_index {
+                        self.fg = PALETTE[color | 8];
+                    }
+                }
+                22 => {
+                    self.bold = false;
+                    if let Some(color) = self.fg_index {
+                        self.fg = PALETTE[color];
+                    }
+                }
+                n @ 30..=37 => {
+                    let color = (n - 30) as usize;
+                    self.fg_index = Some(color);
+                    self.fg = PALETTE[if self.bold { color | 8 } else { color }];
+                }
+                39 => {
+                    self.fg = DEFAULT_FG;
+                    self.fg_index = None;
+                }
+                n @ 40..=47 => self.bg = PALETTE[(n - 40) as usize],
+                49 => self.bg = DEFAULT_BG,
+                n @ 90..=97 => {
+                    self.fg_index = None;
+                    self.fg = PALETTE[(n - 90) as usize | 8];
+                }
+                n @ 100..=107 => self.bg = PALETTE[(n - 100) as usize | 8],
+                _ => {}
+            }
+        }
+    }
+
+    /// Back to the initial state, clearing the screen
+    fn reset(&mut self) {
+        self.fg = DEFAULT_FG;
+        self.bg = DEFAULT_BG;
+        self.bold = false;
+        self.fg_index = None;
+        self.cursor_visible = true;
+        self.col = 0;
+        self.row = 0;
+        self.clear(0..self.cells.len());
+    }
+
+    fn put_char(&mut self, ch: char) {
+        // Wrapping is deferred until the next character,
+        // so that filling the last column doesn't scroll
+        if self.col >= self.cols {
+            self.col = 0;
+            self.line_feed();
+        }
+
+        self.cells[self.row * self.cols + self.col] = Cell {
+            ch,
+            fg: self.fg,
+            bg: self.bg,
+        };
+        self.draw_cell(self.col, self.row, false);
+        self.col += 1;
+    }
+
+    fn line_feed(&mut self) {
+        if self.row + 1 < self.rows {
+            self.row += 1;
+            return;
+        }
+
+        self.cells.copy_within(self.cols.., 0);
+        let last_row = (self.rows - 1) * self.cols;
+        let blank = self.blank();
+        self.cells[last_row..].fill(blank);
+
+        // Without the GPU, redrawing from the cell grid avoids
+        // reading back the framebuffer, which is mapped uncached
+        if self.accel_scroll().is_err() {
+            self.draw_all();
+        }
+    }
+
+    fn clear(&mut self, range: Range<usize>) {
+        let blank = self.blank();
+        self.cells[range.clone()].fill(blank);
+
+        let whole_rows = range.start % self.cols == 0 && range.end % self.cols == 0;
+        if whole_rows
+            && self
+                .accel_fill_rows(range.start / self.cols..range.end / self.cols)
+                .is_ok()
+        {
+            return;
+        }
+
+        for idx in range {
+            self.draw_cell(idx % self.cols, idx / self.cols, false);
+        }
+    }
+
+    /// Pixel area of `rows`
+    fn rows_rect(&self, rows: Range<usize>) -> Rect {
+        let (width, height) = self.cell_size;
+        Rect::new(
+            self.origin.0 as i32,
+            (self.origin.1 + rows.start * height) as i32,
+            (self.cols * width) as u32,
+            (rows.len() * height) as u32,
+        )
+    }
+
+    /// Moves the rows up by one on the GPU, blanking the last
+    fn accel_scroll(&mut self) -> Result<(), ()> {
+        let surface = self.accel.ok_or(())?;
+        let to = self.rows_rect(0..self.rows - 1);
+        let from = (to.x, to.y + self.cell_size.1 as i32);
+
+        accel::lock()
+            .ok_or(())?
+            .copy_rect(&surface, from, &surface, to)?;
+        self.gpu_drawing(0..self.rows - 1);
+        self.accel_fill_rows(self.rows - 1..self.rows)
+    }
+
+    /// Fills `rows` with the background color on the GPU, without
+    /// waiting for it, as the next cell drawn into them does
+    fn accel_fill_rows(&mut self, rows: Range<usize>) -> Result<(), ()> {
+        let surface = self.accel.ok_or(())?;
+        accel::lock()
+            .ok_or(())?
+            .fill_rect(&surface, self.rows_rect(rows.clone()), self.bg)?;
+        self.gpu_drawing(rows);
+        Ok(())
+    }
+
+    /// Notes that the GPU is drawing into `rows`
+    fn gpu_drawing(&mut self, rows: Range<usize>) {
+        self.gpu_rows = if self.gpu_rows.is_empty() {
+            rows
+        } else {
+            self.gpu_rows.start.min(rows.start)..self.gpu_rows.end.max(rows.end)
+        };
+    }
+
+    /// Waits for the GPU to finish with `row` before the CPU draws into it
+    fn sync_gpu(&mut self, row: usize) {
+        if !self.gpu_rows.contains(&row) {
+            return;
+        }
+
+        self.gpu_rows = 0..0;
+        let finished = accel::lock().ok_or(()).and_then(|mut accel| accel.finish());
+        if finished.is_err() {
+            // Whatever the GPU didn't get to is redrawn from the cell grid
+            self.draw_all();
+        }
+    }
+
+    fn draw_cursor(&mut self, visible: bool) {
+        if self.cursor_visible {
+            let (col, row) = self.cursor();
+            self.draw_cell(col, row, visible);
+        }
+    }
+
+    fn draw_cell(&mut self, col: usize, row: usize, inverted: bool) {
+        self.sync_gpu(row);
+
+        let cell = self.cells[row * self.cols + col];
+        let (fg, bg) = if inverted {
+            (cell.bg, cell.fg)
+        } else {
+            (cell.fg, cell.bg)
+        };
+
+        let (width, height) = self.cell_size;
+        let x = self.origin.0 + col * width;
+        let y = self.origin.1 + row * height;
+
+        let bytes_pp = self.format.bytes_per_pixel();
+        let bg = self.format.encode(bg);
+
+        unsafe {
+            let dest = self.fb.add(y * self.pitch + x * bytes_pp);
+            for line in 0..height {
+                let line = dest.add(line * self.pitch);
+                for px in 0..width {
+                    self.format.write(line.add(px * bytes_pp), bg);
+                }
+            }
+
+            if cell.ch != ' ' {
+                print::draw_char(
+                    dest,
+                    self.pitch,
+                    self.format,
+                    self.font,
+                    self.scale,
+                    fg,
+                    cell.ch,
+                );
+            }
+        }
+    }
+}
+
+/// Area inside the video mode's margins
+fn inner_area(vm: &encoder::VideoModeInfo) -> Rect {
+    Rect::new(
+        vm.xmargin as i32,
+        vm.ymargin as i32,
+        vm.width.saturating_sub(2 * vm.xmargin),
+        vm.height.saturating_sub(2 * vm.ymargin),
+    )
+}
+
+impl fmt::Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_bytes(s.as_bytes());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WIDTH: u32 = 10 * font::WIDTH;
+    const HEIGHT: u32 = 3 * font::HEIGHT;
+
+    fn new_console(fb: &mut Vec<u32>) -> Console {
+        *fb = alloc::vec![0; (WIDTH * HEIGHT) as usize];
+        Console::new(fb.as_mut_ptr().cast(), &video_mode()).unwrap()
+    }
+
+    fn video_mode() -> encoder::VideoModeInfo {
+        encoder::VideoModeInfo {
+            width: WIDTH,
+            height: HEIGHT,
+            xmargin: 0,
+            ymargin: 0,
+            nvhtotal: 0,
+            nvvtotal: 0,
+            nvhstart: 0,
+            nvvstart: 0,
+            pixel_format: gfx::PixelFormat::Argb8888,
+            pixel_clock: None,
+            crtc_hend: 0,
+            crtc_vstart: 0,
+            crtc_vtotal: 0,
+        }
+    }
+
+    fn row_text(console: &Console, row: usize) -> alloc::string::String {
+        let cells = &console.cells[row * console.cols..(row + 1) * console.cols];
+        cells.iter().map(|c| c.ch).collect()
+    }
+
+    #[test]
+    fn grid_is_sized_from_the_video_mode() {
+        let mut fb = Vec::new();
+        let console = new_console(&mut fb);
+        assert_eq!((console.cols(), console.rows()), (10, 3));
+    }
+
+    #[test]
+    fn long_lines_wrap_and_scroll() {
+        let mut fb = Vec::new();
+        let mut console = new_console(&mut fb);
+        console.write_bytes(b"first\nsecond\n0123456789abc");
+
+        assert_eq!(row_text(&console, 0), "second    ");
+        assert_eq!(row_text(&console, 1), "0123456789");
+        assert_eq!(row_text(&console, 2), "abc       ");
+        assert_eq!(console.cursor(), (3, 2));
+    }
+
+    #[test]
+    fn filling_the_last_column_defers_the_wrap() {
+        let mut fb = Vec::new();
+        let mut console = new_console(&mut fb);
+        console.write_bytes(b"0123456789");
+        assert_eq!(console.cursor(), (9, 0));
+    }
+
+    #[test]
+    fn control_characters_move_the_cursor() {
+        let mut fb = Vec::new();
+        let mut console = new_console(&mut fb);
+        console.write_bytes(b"ab\tc\x08d\rX");
+        assert_eq!(row_text(&console, 0), "Xb      d ");
+    }
+
+    #[test]
+    fn cursor_movement_and_erase_sequences() {
+        let mut fb = Vec::new();
+        let mut console = new_console(&mut fb);
+        console.write_bytes(b"aaaaaaaaaa\x1b[2;5Hb\x1b[A\x1b[2Dc\x1b[K");
+
+        assert_eq!(row_text(&console, 0), "aaac      ");
+        assert_eq!(row_text(&console, 1), "    b     ");
+
+        console.write_bytes(b"\x1b[2J");
+        assert!((0..3).all(|row| row_text(&console, row).trim().is_empty()));
+    }
+
+    #[test]
+    fn graphic_rendition_sets_colors() {
+        let mut fb = Vec::new();
+        let mut console = new_console(&mut fb);
+        console.write_bytes(b"\x1b[31;44ma\x1b[1mb\x1b[0mc");
+
+        assert_eq!(console.cells[0].fg, PALETTE[1]);
+        assert_eq!(console.cells[0].bg, PALETTE[4]);
+        assert_eq!(console.cells[1].fg, PALETTE[9]);
+        assert_eq!(console.cells[2].fg, DEFAULT_FG);
+        assert_eq!(console.cells[2].bg, DEFAULT_BG);
+
+        // Backgrounds are drawn into the framebuffer
+        assert_eq!(fb[0], PALETTE[4].to_pixel());
+    }
+
+    #[test]
+    fn utf8_characters_take_one_cell() {
+        let mut fb = Vec::new();
+        let mut console = new_console(&mut fb);
+
+        // Sequences may be split between writes
+        let arrow = "→".as_bytes();
+        console.write_bytes("é✓".as_bytes());
+        console.write_bytes(&arrow[..1]);
+        console.write_bytes(&arrow[1..]);
+        console.write_bytes(b"\xffa");
+
+        assert_eq!(row_text(&console, 0), "é✓→\u{fffd}a     ");
+        assert_eq!(console.cursor(), (5, 0));
+    }
+
+    #[test]
+    fn scaled_fonts_use_larger_cells() {
+        let mut fb = alloc::vec![0; (WIDTH * HEIGHT) as usize];
+        let mut console =
+            Console::with_font(fb.as_mut_ptr().cast(), &video_mode(), &font::BUILTIN, 2).unwrap();
+        assert_eq!((console.cols(), console.rows()), (5, 1));
+
+        // Cells are 16x32 pixels, and the last 16 lines are left over
+        console.write_bytes(b"\x1b[44m ");
+        let bg = PALETTE[4].to_pixel();
+        let width = WIDTH as usize;
+        assert_eq!((fb[15], fb[31 * width + 15]), (bg, bg));
+        assert_eq!(fb[32 * width], 0);
+    }
+
+    #[test]
+    fn cells_are_drawn_in_16_bit_formats() {
+        let vm = encoder::VideoModeInfo {
+            pixel_format: gfx::PixelFormat::Rgb565,
+            ..video_mode()
+        };
+        let mut fb = alloc::vec![0u16; (WIDTH * HEIGHT) as usize];
+        let mut console = Console::new(fb.as_mut_ptr().cast(), &vm).unwrap();
+
+        // The second cell starts a font width in, and the background
+        // covers the rest of the row without spilling into the next
+        console.write_bytes(b"a\x1b[44m \x1b[0m");
+        let bg = gfx::PixelFormat::Rgb565.encode(PALETTE[4]) as u16;
+        let (width, cell_width) = (WIDTH as usize, font::WIDTH as usize);
+        assert_eq!(fb[cell_width], bg);
+        assert_eq!(
+            fb[(font::HEIGHT as usize - 1) * width + 2 * cell_width - 1],
+            bg
+        );
+        assert_ne!(fb[2 * cell_width], bg);
+    }
+
+    #[test]
+    fn consoles_can_take_the_bottom_rows() {
+        let mut fb = alloc::vec![0u32; (WIDTH * HEIGHT) as usize];
+        let mut console = Console::with_rows(fb.as_mut_ptr().cast(), &video_mode(), 1).unwrap();
+        assert_eq!((console.cols(), console.rows()), (10, 1));
+        assert_eq!(
+            console.area(),
+            Rect::new(0, 2 * font::HEIGHT as i32, WIDTH, font::HEIGHT)
+        );
+
+        // Only the last row of cells is drawn into
+        console.write_bytes(b"\x1b[44m ");
+        let bg = PALETTE[4].to_pixel();
+        let width = WIDTH as usize;
+        assert_eq!(fb[2 * font::HEIGHT as usize * width], bg);
+        assert_eq!(fb[(2 * font::HEIGHT as usize - 1) * width], 0);
+    }
+
+    #[test]
+    fn retargeting_redraws_into_the_new_buffer() {
+        let mut fb = Vec::new();
+        let mut console = new_console(&mut fb);
+        console.write_bytes(b"\x1b[44ma");
+
+        let mut other = alloc::vec![0u32; (WIDTH * HEIGHT) as usize];
+        console.retarget(other.as_mut_ptr().cast(), None);
+        assert_eq!(other[0], PALETTE[4].to_pixel());
+
+        // Later output only goes to the new buffer
+        fb.fill(0);
+        console.write_bytes(b"b");
+        assert_eq!(other[font::WIDTH as usize], PALETTE[4].to_pixel());
+        assert_eq!(fb[font::WIDTH as usize], 0);
+    }
+
+    #[test]
+    fn consoles_need_room_for_a_cell() {
+        let mut fb = alloc::vec![0u32; (WIDTH * HEIGHT) as usize];
+        let mut vm = video_mode();
+        vm.xmargin = WIDTH / 2;
+        assert!(Console::new(fb.as_mut_ptr().cast(), &vm).is_none());
+
+        let vm = video_mode();
+        let font = &font::BUILTIN;
+        assert!(Console::with_font(fb.as_mut_ptr().cast(), &vm, font, 4).is_none());
+    }
+}
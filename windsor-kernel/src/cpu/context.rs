@@ -0,0 +1,43 @@
+/// Callee-saved registers pushed by `switch`, plus the return address
+const SAVED_WORDS: usize = 5;
+
+/// Prepares a fresh stack so that switching to it starts `entry`.
+/// Returns the initial stack pointer to pass to `switch`.
+pub fn init_stack(stack: &mut [u32], entry: extern "C" fn() -> !) -> u32 {
+    // The ABI expects esp + 4 to be 16 byte aligned on function entry
+    let end = stack.as_ptr_range().end as usize;
+    let top = stack.len() - (end % 16) / 4;
+
+    // Fake return address for `entry`, which never returns
+    stack[top - 1] = 0;
+    stack[top - 2] = entry as u32;
+    // ebp, ebx, esi and edi start out zeroed
+    stack[top - 2 - (SAVED_WORDS - 1)..top - 2].fill(0);
+
+    &stack[top - 1 - SAVED_WORDS] as *const u32 as u32
+}
+
+/// Saves the callee-saved registers on the current stack, stores the
+/// stack pointer to `old_esp` and resumes the context saved at `new_esp`.
+/// Returns once another `switch` resumes the saved context.
+/// Safety: `new_esp` must come from `init_stack` or an earlier `switch`,
+/// and interrupts must be disabled.
+#[naked]
+pub unsafe extern "C" fn switch(old_esp: *mut u32, new_esp: u32) {
+    core::arch::asm!(
+        "mov eax, [esp + 4]",
+        "mov edx, [esp + 8]",
+        "push ebp",
+        "push ebx",
+        "push esi",
+        "push edi",
+        "mov [eax], esp",
+        "mov esp, edx",
+        "pop edi",
+        "pop esi",
+        "pop ebx",
+        "pop ebp",
+        "ret",
+        options(noreturn),
+    );
+}
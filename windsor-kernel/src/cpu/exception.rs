@@ -0,0 +1,388 @@
+use core::fmt;
+
+use super::idt;
+use crate::sync::OnceCell;
+
+/// Number of vectors reserved for architectural exceptions
+pub const EXCEPTION_COUNT: usize = 32;
+
+/// Register state captured on entry to an exception handler.
+/// Fields are in stack order, starting from what the common
+/// entry stub pushes last.
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct ExceptionFrame {
+    pub cr3: u32,
+    pub cr2: u32,
+
+    // Pushed by pushad
+    pub edi: u32,
+    pub esi: u32,
+    pub ebp: u32,
+    esp_pushad: u32,
+    pub ebx: u32,
+    pub edx: u32,
+    pub ecx: u32,
+    pub eax: u32,
+
+    // Pushed by the per-vector stub, error code is 0 if the
+    // exception does not provide one
+    pub vector: u32,
+    pub error_code: u32,
+
+    // Pushed by the CPU
+    pub eip: u32,
+    pub cs: u32,
+    pub eflags: u32,
+}
+
+impl ExceptionFrame {
+    /// Stack pointer at the time of the exception.
+    /// pushad saves ESP as it points at the vector, and there is no
+    /// privilege change in the kernel, so the CPU does not push SS:ESP.
+    /// The interrupted stack continues right after EFLAGS.
+    pub fn esp(&self) -> u32 {
+        self.esp_pushad + 5 * 4
+    }
+
+    /// Builds a frame from the state saved in a TSS by a task switch.
+    /// Task switches don't save CR3, but the kernel only has one
+    /// address space, so the current one is the interrupted one.
+    pub(super) fn from_tss(
+        tss: &super::tss::TaskStateSegment,
+        vector: u32,
+        error_code: u32,
+    ) -> Self {
+        let cr2: u32;
+        let cr3 = unsafe {
+            core::arch::asm!("mov {}, cr2", out(reg) cr2);
+            super::mmu::CR3::current()
+        };
+
+        Self {
+            cr3: cr3.0,
+            cr2,
+            edi: tss.edi,
+            esi: tss.esi,
+            ebp: tss.ebp,
+            esp_pushad: tss.esp - 5 * 4,
+            ebx: tss.ebx,
+            edx: tss.edx,
+            ecx: tss.ecx,
+            eax: tss.eax,
+            vector,
+            error_code,
+            eip: tss.eip,
+            cs: tss.cs,
+            eflags: tss.eflags,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        exception_name(self.vector)
+    }
+
+    /// Prints the frame, decoding the error code where applicable
+    pub fn dump(&self, out: &mut impl fmt::Write) -> fmt::Result {
+        if let Some(reason) = last_reason() {
+            writeln!(out, "{}", reason)?;
+        }
+
+        writeln!(out, "{} at EIP {:08x}", self.name(), self.eip)?;
+
+        if has_error_code(self.vector) {
+            write!(out, "Error code {:x}", self.error_code)?;
+            self.dump_error_code(out)?;
+            writeln!(out)?;
+        }
+
+        writeln!(out)?;
+
+        let regs = [
+            ("EAX", self.eax),
+            ("EBX", self.ebx),
+            ("ECX", self.ecx),
+            ("EDX", self.edx),
+            ("ESI", self.esi),
+            ("EDI", self.edi),
+            ("EBP", self.ebp),
+            ("ESP", self.esp()),
+            ("EIP", self.eip),
+            ("EFL", self.eflags),
+            ("CS", self.cs),
+            ("CR2", self.cr2),
+            ("CR3", self.cr3),
+        ];
+
+        for (idx, (name, val)) in regs.iter().enumerate() {
+            write!(out, "{:<3} {:08x}", name, val)?;
+
+            let sep = if idx % 4 == 3 { "\n" } else { "  " };
+            out.write_str(sep)?;
+        }
+
+        writeln!(out)
+    }
+
+    fn dump_error_code(&self, out: &mut impl fmt::Write) -> fmt::Result {
+        match self.vector {
+            PAGE_FAULT => {
+                let code = PageFaultError(self.error_code);
+                let access = if code.instruction_fetch() {
+                    "fetch"
+                } else if code.write() {
+                    "write"
+                } else {
+                    "read"
+                };
+
+                let cause = if code.reserved_bit() {
+                    "reserved bit set"
+                } else if code.present() {
+                    "protection violation"
+                } else {
+                    "not present"
+                };
+                write!(out, " ({}, {}", access, cause)?;
+
+                if code.user() {
+                    out.write_str(", user")?;
+                }
+                out.write_str(")")
+            }
+            INVALID_TSS | SEGMENT_NOT_PRESENT | STACK_FAULT | GENERAL_PROTECTION => {
+                if self.error_code == 0 {
+                    return Ok(());
+                }
+
+                let code = SelectorError(self.error_code);
+                let table = match (code.idt(), code.ldt()) {
+                    (true, _) => "IDT",
+                    (false, true) => "LDT",
+                    (false, false) => "GDT",
+                };
+                write!(out, " ({} index {:x}", table, code.index())?;
+
+                if code.external() {
+                    out.write_str(", external")?;
+                }
+                out.write_str(")")
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+proc_bitfield::bitfield!(
+#[derive(Copy, Clone)]
+pub struct PageFaultError(pub u32) {
+    pub present: bool @ 0,
+    pub write: bool @ 1,
+    pub user: bool @ 2,
+    pub reserved_bit: bool @ 3,
+    pub instruction_fetch: bool @ 4,
+}
+);
+
+proc_bitfield::bitfield!(
+#[derive(Copy, Clone)]
+pub struct SelectorError(pub u32) {
+    pub external: bool @ 0,
+    pub idt: bool @ 1,
+    pub ldt: bool @ 2,
+    pub index: u16 @ 3..=15,
+}
+);
+
+pub const DIVIDE_ERROR: u32 = 0;
+pub const DEBUG: u32 = 1;
+pub const NMI: u32 = 2;
+pub const BREAKPOINT: u32 = 3;
+pub const OVERFLOW: u32 = 4;
+pub const BOUND_RANGE: u32 = 5;
+pub const INVALID_OPCODE: u32 = 6;
+pub const DEVICE_NOT_AVAILABLE: u32 = 7;
+pub const DOUBLE_FAULT: u32 = 8;
+pub const COPROCESSOR_SEGMENT_OVERRUN: u32 = 9;
+pub const INVALID_TSS: u32 = 10;
+pub const SEGMENT_NOT_PRESENT: u32 = 11;
+pub const STACK_FAULT: u32 = 12;
+pub const GENERAL_PROTECTION: u32 = 13;
+pub const PAGE_FAULT: u32 = 14;
+pub const X87_FLOATING_POINT: u32 = 16;
+pub const ALIGNMENT_CHECK: u32 = 17;
+pub const MACHINE_CHECK: u32 = 18;
+pub const SIMD_FLOATING_POINT: u32 = 19;
+
+pub fn exception_name(vector: u32) -> &'static str {
+    match vector {
+        DIVIDE_ERROR => "Divide Error (#DE)",
+        DEBUG => "Debug (#DB)",
+        NMI => "Non-Maskable Interrupt",
+        BREAKPOINT => "Breakpoint (#BP)",
+        OVERFLOW => "Overflow (#OF)",
+        BOUND_RANGE => "BOUND Range Exceeded (#BR)",
+        INVALID_OPCODE => "Invalid Opcode (#UD)",
+        DEVICE_NOT_AVAILABLE => "Device Not Available (#NM)",
+        DOUBLE_FAULT => "Double Fault (#DF)",
+        COPROCESSOR_SEGMENT_OVERRUN => "Coprocessor Segment Overrun",
+        INVALID_TSS => "Invalid TSS (#TS)",
+        SEGMENT_NOT_PRESENT => "Segment Not Present (#NP)",
+        STACK_FAULT => "Stack-Segment Fault (#SS)",
+        GENERAL_PROTECTION => "General Protection Fault (#GP)",
+        PAGE_FAULT => "Page Fault (#PF)",
+        X87_FLOATING_POINT => "x87 Floating-Point Error (#MF)",
+        ALIGNMENT_CHECK => "Alignment Check (#AC)",
+        MACHINE_CHECK => "Machine Check (#MC)",
+        SIMD_FLOATING_POINT => "SIMD Floating-Point Exception (#XM)",
+        _ => "Reserved Exception",
+    }
+}
+
+fn has_error_code(vector: u32) -> bool {
+    matches!(
+        vector,
+        DOUBLE_FAULT
+            | INVALID_TSS
+            | SEGMENT_NOT_PRESENT
+            | STACK_FAULT
+            | GENERAL_PROTECTION
+            | PAGE_FAULT
+            | ALIGNMENT_CHECK
+    )
+}
+
+static LAST_EXCEPTION: OnceCell<ExceptionFrame> = OnceCell::new();
+static LAST_REASON: OnceCell<&'static str> = OnceCell::new();
+
+/// Frame of the exception that caused a panic, if any
+pub fn last_exception() -> Option<ExceptionFrame> {
+    LAST_EXCEPTION.get().copied()
+}
+
+/// Diagnosis of the exception that caused a panic, if one was made
+pub fn last_reason() -> Option<&'static str> {
+    LAST_REASON.get().copied()
+}
+
+pub(super) fn fatal(frame: &ExceptionFrame) -> ! {
+    // Only the first fatal exception is kept, later ones come from the panic itself
+    let _ = LAST_EXCEPTION.set(*frame);
+
+    panic!("Unhandled CPU exception");
+}
+
+pub(super) fn fatal_with_reason(frame: &ExceptionFrame, reason: &'static str) -> ! {
+    let _ = LAST_REASON.set(reason);
+
+    fatal(frame);
+}
+
+extern "C" fn exception_dispatch(frame: &mut ExceptionFrame) {
+    if frame.vector == PAGE_FAULT && super::pagefault::handle(frame) {
+        return;
+    }
+
+    fatal(frame);
+}
+
+/// Common entry point for exception stubs, with the vector and
+/// error code on the stack above the CPU-pushed frame
+#[naked]
+unsafe extern "C" fn exception_common() {
+    core::arch::asm!(
+        "pushad",
+        "mov eax, cr2",
+        "push eax",
+        "mov eax, cr3",
+        "push eax",
+        "cld",
+        "push esp",
+        "call {dispatch}",
+        // Discard the argument, CR3 and CR2
+        "add esp, 12",
+        "popad",
+        // Discard the vector and error code
+        "add esp, 8",
+        "iretd",
+        dispatch = sym exception_dispatch,
+        options(noreturn),
+    );
+}
+
+macro_rules! exception_stubs {
+    ($($name:ident => $vec:expr, $has_code:tt);* $(;)?) => {
+        $(exception_stubs!(@stub $name, $vec, $has_code);)*
+
+        const EXCEPTION_STUBS: [unsafe extern "C" fn(); EXCEPTION_COUNT] = [$($name),*];
+    };
+
+    (@stub $name:ident, $vec:expr, true) => {
+        #[naked]
+        unsafe extern "C" fn $name() {
+            core::arch::asm!(
+                "push {vec}",
+                "jmp {common}",
+                vec = const $vec,
+                common = sym exception_common,
+                options(noreturn),
+            );
+        }
+    };
+
+    (@stub $name:ident, $vec:expr, false) => {
+        #[naked]
+        unsafe extern "C" fn $name() {
+            core::arch::asm!(
+                "push 0",
+                "push {vec}",
+                "jmp {common}",
+                vec = const $vec,
+                common = sym exception_common,
+                options(noreturn),
+            );
+        }
+    };
+}
+
+exception_stubs!(
+    exc_de => 0, false;
+    exc_db => 1, false;
+    exc_nmi => 2, false;
+    exc_bp => 3, false;
+    exc_of => 4, false;
+    exc_br => 5, false;
+    exc_ud => 6, false;
+    exc_nm => 7, false;
+    exc_df => 8, true;
+    exc_cso => 9, false;
+    exc_ts => 10, true;
+    exc_np => 11, true;
+    exc_ss => 12, true;
+    exc_gp => 13, true;
+    exc_pf => 14, true;
+    exc_15 => 15, false;
+    exc_mf => 16, false;
+    exc_ac => 17, true;
+    exc_mc => 18, false;
+    exc_xm => 19, false;
+    exc_20 => 20, false;
+    exc_21 => 21, false;
+    exc_22 => 22, false;
+    exc_23 => 23, false;
+    exc_24 => 24, false;
+    exc_25 => 25, false;
+    exc_26 => 26, false;
+    exc_27 => 27, false;
+    exc_28 => 28, false;
+    exc_29 => 29, false;
+    exc_30 => 30, false;
+    exc_31 => 31, false;
+);
+
+/// Installs the exception stubs into the first EXCEPTION_COUNT IDT entries
+pub unsafe fn install(idt: &mut [u64; 256]) {
+    for (vec, stub) in EXCEPTION_STUBS.iter().enumerate() {
+        idt[vec] = idt::Entry::new(*stub as u32, idt::GateType::Interrupt, 0x8).raw_value();
+    }
+}
@@ -1,6 +1,7 @@
 use core::arch::asm;
 use bitbybit::bitfield;
 use arbitrary_int::{u2, u4, u24};
+use crate::sync::OnceCell;
 
 #[bitfield(u64, default: 0)]
 pub struct GDTSegment {
@@ -69,8 +70,26 @@ impl GDTSegment {
             .with_limit_hi(u4::new(0xf))
             .with_limit_lo(0xffff)
     }
+
+    /// Available 32-bit TSS, with byte granularity
+    pub const fn tss(base: u32, limit: u32) -> Self {
+        Self::null()
+            .with_access_byte(0x89)
+            .with_flags(u4::new(0x0))
+            .with_base_lo(u24::new(base & 0xff_ffff))
+            .with_base_hi((base >> 24) as u8)
+            .with_limit_lo((limit & 0xffff) as u16)
+            .with_limit_hi(u4::new(((limit >> 16) & 0xf) as u8))
+    }
 }
 
+pub const GDT_ENTRIES: usize = 7;
+pub const TSS_INDEX: usize = 5;
+pub const DOUBLE_FAULT_TSS_INDEX: usize = 6;
+
+pub const KERNEL_CS: u16 = 0x8;
+pub const KERNEL_DS: u16 = 0x10;
+
 #[repr(C)]
 #[repr(packed)]
 pub struct GDTDesc {
@@ -79,21 +98,30 @@ pub struct GDTDesc {
     pad: u16,
 }
 
-pub static mut GDTR: GDTDesc = GDTDesc {
-    size: 5 * 64 - 1,
-    offset: 0,
-    pad: 0,
-};
+static GDT: OnceCell<[GDTSegment; GDT_ENTRIES]> = OnceCell::new();
+static GDTR: OnceCell<GDTDesc> = OnceCell::new();
 
-pub const GDT: &'static [GDTSegment; 5] = &[
+// TSS descriptors are filled in by `tss::install`, as they
+// need the runtime address of the TSS
+pub const INITIAL_GDT: [GDTSegment; GDT_ENTRIES] = [
     GDTSegment::null(),
     GDTSegment::flat(true, false),
     GDTSegment::flat(true, true),
     GDTSegment::flat(false, false),
     GDTSegment::flat(false, true),
+    GDTSegment::null(),
+    GDTSegment::null(),
 ];
 
-pub unsafe fn lgdt(gdtr: &'static mut GDTDesc, gdt: &'static [GDTSegment; 5]) {
-    gdtr.offset = gdt.as_ptr() as u32;
+/// Moves the GDT to its final location and loads it.
+/// Can only be called once.
+pub unsafe fn lgdt(gdt: [GDTSegment; GDT_ENTRIES]) {
+    let gdt = GDT.set(gdt).ok().expect("GDT should only be loaded once");
+    let gdtr = GDTR.get_or_init(|| GDTDesc {
+        size: (GDT_ENTRIES * 8 - 1) as u16,
+        offset: gdt.as_ptr() as u32,
+        pad: 0,
+    });
+
     asm!("lgdt [eax]", in("eax") gdtr);
 }
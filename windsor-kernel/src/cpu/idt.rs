@@ -3,6 +3,7 @@ use bitbybit::bitfield;
 use arbitrary_int::{u2, u4};
 
 pub enum GateType {
+    Task = 0x5,
     Interrupt = 0xe,
     Trap = 0xf,
 }
@@ -65,6 +66,11 @@ impl Entry {
             .with_segment(segment)
             .with_present(true)
     }
+
+    /// Task gate, switching to the task described by the TSS at `tss_selector`
+    pub const fn task(tss_selector: u16) -> Self {
+        Self::new(0, GateType::Task, tss_selector)
+    }
 }
 
 pub unsafe fn lidt(idtr: &'static Descriptor) {
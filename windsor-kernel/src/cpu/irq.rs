@@ -1,19 +1,31 @@
-use super::idt;
+use super::{exception, idt, pic, tss};
+use crate::sync::OnceCell;
 
-pub static mut IDT: [u64; 256] = [0; 256];
-pub static mut IDTR: idt::Descriptor = idt::Descriptor::zero();
+static IDT: OnceCell<[u64; 256]> = OnceCell::new();
+static IDTR: OnceCell<idt::Descriptor> = OnceCell::new();
 
 pub fn setup_irq() {
-    unsafe {
-        for i in 0..=255 {
-            IDT[i] = idt::Entry::new(irq_unhandled as u32, idt::GateType::Interrupt, 0x8).raw_value();
-        }
+    let mut table = [0; 256];
+    for entry in table.iter_mut() {
+        *entry = idt::Entry::new(irq_unhandled as u32, idt::GateType::Interrupt, 0x8).raw_value();
+    }
+
+    unsafe { exception::install(&mut table) };
 
-        IDT[8] = idt::Entry::new(irq_gpf as u32, idt::GateType::Interrupt, 0x8).raw_value();
-        IDTR = idt::Descriptor::new(256 * 8, IDT.as_ptr() as u32);
+    // Double faults switch to a separate task and stack, so that
+    // a corrupted kernel stack can still reach the panic screen
+    table[exception::DOUBLE_FAULT as usize] =
+        idt::Entry::task(tss::DOUBLE_FAULT_TSS_SELECTOR).raw_value();
 
-        idt::lidt(&IDTR);
+    for (irq, stub) in PIC_STUBS.iter().enumerate() {
+        let vec = pic::IRQ_BASE as usize + irq;
+        table[vec] = idt::Entry::new(*stub as u32, idt::GateType::Interrupt, 0x8).raw_value();
     }
+
+    let table = IDT.set(table).ok().expect("IDT should only be set up once");
+    let idtr = IDTR.get_or_init(|| idt::Descriptor::new(256 * 8, table.as_ptr() as u32));
+
+    unsafe { idt::lidt(idtr) };
 }
 
 #[derive(Copy, Clone)]
@@ -39,15 +51,39 @@ impl StackFrame {
     }
 }
 
-pub extern "x86-interrupt" fn irq_nv2a(_sf: StackFrame) {
-    super::pic::reset();
+macro_rules! pic_stubs {
+    ($($name:ident => $irq:expr),* $(,)?) => {
+        $(
+            extern "x86-interrupt" fn $name(_sf: StackFrame) {
+                pic::dispatch($irq);
+                crate::thread::preempt();
+            }
+        )*
+
+        const PIC_STUBS: [extern "x86-interrupt" fn(StackFrame); pic::IRQ_COUNT as usize] =
+            [$($name),*];
+    };
 }
 
+pic_stubs!(
+    irq_pic0 => 0,
+    irq_pic1 => 1,
+    irq_pic2 => 2,
+    irq_pic3 => 3,
+    irq_pic4 => 4,
+    irq_pic5 => 5,
+    irq_pic6 => 6,
+    irq_pic7 => 7,
+    irq_pic8 => 8,
+    irq_pic9 => 9,
+    irq_pic10 => 10,
+    irq_pic11 => 11,
+    irq_pic12 => 12,
+    irq_pic13 => 13,
+    irq_pic14 => 14,
+    irq_pic15 => 15,
+);
+
 pub extern "x86-interrupt" fn irq_unhandled(_sf: StackFrame) {
     //panic!("Unhandled interrupt");
-    super::pic::reset();
-}
-
-pub extern "x86-interrupt" fn irq_gpf(_sf: StackFrame, _code: u32) {
-    panic!("Unhandled GPF");
 }
@@ -1,6 +1,9 @@
 use core::arch::asm;
 use proc_bitfield::bitfield;
 
+use crate::physram;
+use crate::sync::{OnceCell, SpinLock, SpinLockGuard};
+
 unsafe fn invalidate_page(vaddr: *const u8) {
     asm!("invlpg {}", in(reg) vaddr);
 }
@@ -13,6 +16,54 @@ unsafe fn invalidate_all() {
     ", out("eax") _);
 }
 
+pub const PAGE_SIZE: u32 = 0x1000;
+pub const LARGE_PAGE_SIZE: u32 = 0x40_0000;
+const PAGE_MASK: u32 = PAGE_SIZE - 1;
+const LARGE_PAGE_MASK: u32 = LARGE_PAGE_SIZE - 1;
+
+/// Virtual range handed out by `Mapping::map_anywhere`, above the
+/// page table window at 0xC000_0000 and below the kernel heap
+const DYNAMIC_START: u32 = 0xC040_0000;
+const DYNAMIC_END: u32 = 0xE000_0000;
+
+/// The boot stage identity maps everything from here up with large pages
+const BOOTSTRAP_MMIO_START: u32 = 0xF000_0000;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum CacheType {
+    WriteBack,
+    WriteThrough,
+    Uncached,
+}
+
+impl CacheType {
+    fn writethrough(self) -> bool {
+        self == Self::WriteThrough
+    }
+
+    fn disable_cache(self) -> bool {
+        self == Self::Uncached
+    }
+
+    fn cacheable(self) -> bool {
+        !self.disable_cache()
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum MapError {
+    /// Addresses and sizes must be page aligned
+    Unaligned,
+    /// The range runs past the end of the address space
+    Overflow,
+    AlreadyMapped(u32),
+    NotMapped(u32),
+    /// Part of a large page was asked to be unmapped
+    LargePageSplit(u32),
+    OutOfMemory,
+    OutOfAddressSpace,
+}
+
 pub struct ContiguousPhysicalMemory {
     addr: u32,
     frames: u32,
@@ -106,6 +157,10 @@ impl PageTableEntry {
     pub unsafe fn set_frame_addr(&mut self, paddr: u32) {
         self.set_frame_addr_raw(paddr >> 12);
     }
+
+    pub fn frame_addr(&self) -> u32 {
+        self.frame_addr_raw() << 12
+    }
 }
 
 bitfield!(
@@ -292,6 +347,41 @@ impl<'tab> ActivePageTable<'tab> {
     }
 }
 
+/// Kernel address space, set up from the bootstrap page tables in `kmain`
+pub static KERNEL_MAPPING: OnceCell<SpinLock<Mapping>> = OnceCell::new();
+
+/// Locks the kernel address space. When the physical allocator is
+/// needed too, it must be locked after this.
+pub fn kernel_mapping() -> SpinLockGuard<'static, Mapping> {
+    KERNEL_MAPPING
+        .get()
+        .expect("Kernel mapping should be initialized")
+        .lock()
+}
+
+/// Maps a device's physical MMIO range uncached into the kernel address
+/// space, returning the virtual address corresponding to `paddr`.
+/// Safety: `paddr` must not be RAM in use elsewhere
+pub unsafe fn ioremap(paddr: u32, size: u32) -> Result<u32, MapError> {
+    let base = paddr & !PAGE_MASK;
+    let size = (paddr - base + size).next_multiple_of(PAGE_SIZE);
+
+    let mut mapping = kernel_mapping();
+    let mut pm = physram::ALLOCATOR.lock();
+    let vaddr = mapping.map_anywhere(base, size, true, CacheType::Uncached, &mut *pm)?;
+    Ok(vaddr + (paddr - base))
+}
+
+/// Releases a range mapped with `ioremap`
+pub unsafe fn iounmap(vaddr: u32, size: u32) -> Result<(), MapError> {
+    let base = vaddr & !PAGE_MASK;
+    let size = (vaddr - base + size).next_multiple_of(PAGE_SIZE);
+
+    let mut mapping = kernel_mapping();
+    let mut pm = physram::ALLOCATOR.lock();
+    mapping.unmap(base, size, &mut *pm)
+}
+
 /// Adjusts the bootstrap page tables to a more readily usable state
 /// Also initializes physram allocator with currently used pages
 unsafe fn bootstrap_setup(
@@ -300,8 +390,6 @@ unsafe fn bootstrap_setup(
 ) -> (*mut PageDirectoryEntry, *mut PageTableEntry) {
     // Reserve some parts of the first 4MB in the allocator
     {
-        // Zero page is used by the GPU
-        physram.mark_allocated(0);
         physram.mark_allocated(pd_paddr);
 
         let kernel_data = crate::kernel_region();
@@ -385,7 +473,7 @@ impl Mapping {
 
         // Remap kernel pages using PTE instead of large pages
         {
-            let mut pt = mapping.new_pt_mapped(physram);
+            let mut pt = mapping.new_pt_mapped(physram).unwrap();
             pt.map_vaddr(0x8000_0000, 0x0, true, false, true);
             pt.map_vaddr(0x8000_0000 | pd_paddr, pd_paddr, true, true, false);
 
@@ -424,6 +512,14 @@ impl Mapping {
             mapping.pd[i].set_entry(0);
         }
 
+        // Devices are mapped on demand through `ioremap`
+        let mmio_start = (BOOTSTRAP_MMIO_START >> 22) as usize;
+        for pde in mapping.pd[mmio_start..].iter_mut() {
+            pde.set_entry(0);
+        }
+
+        invalidate_all();
+
         mapping
     }
 
@@ -437,17 +533,271 @@ impl Mapping {
         &mut self.pd[pde_index as usize]
     }
 
+    /// Maps `size` bytes of physical memory at `paddr` to `vaddr`.
+    /// Ranges that are 4 MiB aligned in both address spaces use large
+    /// pages, everything else is mapped with page tables created on demand.
+    pub unsafe fn map(
+        &mut self,
+        vaddr: u32,
+        paddr: u32,
+        size: u32,
+        writable: bool,
+        cache: CacheType,
+        pm: &mut impl PhysramAllocator,
+    ) -> Result<(), MapError> {
+        if (vaddr | paddr | size) & PAGE_MASK != 0 {
+            return Err(MapError::Unaligned);
+        }
+        if size == 0 {
+            return Ok(());
+        }
+
+        // Checked inclusively, so that the top page can be mapped
+        vaddr.checked_add(size - 1).ok_or(MapError::Overflow)?;
+        paddr.checked_add(size - 1).ok_or(MapError::Overflow)?;
+        self.check_unmapped(vaddr, size)?;
+
+        let mut offset = 0;
+        while offset < size {
+            let va = vaddr + offset;
+            let pa = paddr + offset;
+            let remaining = size - offset;
+
+            if (va | pa) & LARGE_PAGE_MASK == 0 && remaining >= LARGE_PAGE_SIZE {
+                let mut pde = PDEDirect(0);
+                pde.set_paddr(pa);
+                pde.set_allow_writes(writable);
+                pde.set_writethrough(cache.writethrough());
+                pde.set_disable_cache(cache.disable_cache());
+                pde.set_pat(false);
+                pde.set_allow_usermode(false);
+                pde.set_present(true);
+                *self.pde_walk_mut(va) = pde.into();
+
+                invalidate_page(va as *const u8);
+                offset += LARGE_PAGE_SIZE;
+                continue;
+            }
+
+            let mut pt = match self.pt_for_vaddr(va, pm) {
+                Some(pt) => pt,
+                None => {
+                    // Leave nothing behind from a failed map
+                    if offset > 0 {
+                        self.unmap(vaddr, offset, pm)
+                            .expect("Partially mapped range should unmap");
+                    }
+                    return Err(MapError::OutOfMemory);
+                }
+            };
+
+            // Fill the rest of this page table in one go
+            let chunk = remaining.min(LARGE_PAGE_SIZE - (va & LARGE_PAGE_MASK));
+            for page_offset in (0..chunk).step_by(PAGE_SIZE as usize) {
+                let page = va + page_offset;
+                pt.map_vaddr(
+                    page,
+                    pa + page_offset,
+                    writable,
+                    cache.writethrough(),
+                    cache.cacheable(),
+                );
+                invalidate_page(page as *const u8);
+            }
+
+            offset += chunk;
+        }
+
+        Ok(())
+    }
+
+    /// Maps `size` bytes of physical memory at `paddr` into a free range of
+    /// the kernel's dynamic address space, returning the chosen address
+    pub unsafe fn map_anywhere(
+        &mut self,
+        paddr: u32,
+        size: u32,
+        writable: bool,
+        cache: CacheType,
+        pm: &mut impl PhysramAllocator,
+    ) -> Result<u32, MapError> {
+        if (paddr | size) & PAGE_MASK != 0 {
+            return Err(MapError::Unaligned);
+        }
+
+        // Keep large page alignment where possible
+        let align = if (paddr | size) & LARGE_PAGE_MASK == 0 {
+            LARGE_PAGE_SIZE
+        } else {
+            PAGE_SIZE
+        };
+
+        let vaddr = self.find_free(size, align)?;
+        self.map(vaddr, paddr, size, writable, cache, pm)?;
+        Ok(vaddr)
+    }
+
+    /// Unmaps `size` bytes starting at `vaddr`, freeing page tables that
+    /// become empty. Large pages must be unmapped in their entirety.
+    pub unsafe fn unmap(
+        &mut self,
+        vaddr: u32,
+        size: u32,
+        pm: &mut impl PhysramAllocator,
+    ) -> Result<(), MapError> {
+        if (vaddr | size) & PAGE_MASK != 0 {
+            return Err(MapError::Unaligned);
+        }
+        if size == 0 {
+            return Ok(());
+        }
+
+        vaddr.checked_add(size - 1).ok_or(MapError::Overflow)?;
+
+        let mut offset = 0;
+        while offset < size {
+            let va = vaddr + offset;
+            let pde_offset = va & LARGE_PAGE_MASK;
+            let chunk = (size - offset).min(LARGE_PAGE_SIZE - pde_offset);
+
+            match self.pde_walk(va).to_pde() {
+                PDEType::None => return Err(MapError::NotMapped(va)),
+                PDEType::Direct(_) => {
+                    if pde_offset != 0 || chunk != LARGE_PAGE_SIZE {
+                        return Err(MapError::LargePageSplit(va));
+                    }
+
+                    self.pde_walk_mut(va).set_entry(0);
+                    invalidate_page(va as *const u8);
+                }
+                PDEType::Table(pde) => {
+                    let mut pt = self.map_pt(pde.pt_address());
+
+                    for page_offset in (0..chunk).step_by(PAGE_SIZE as usize) {
+                        let page = va + page_offset;
+                        if !pt.pte_mut(page).present() {
+                            return Err(MapError::NotMapped(page));
+                        }
+
+                        pt.unmap_vaddr(page);
+                        invalidate_page(page as *const u8);
+                    }
+
+                    if pt.pt.iter().all(|pte| !pte.present()) {
+                        let pt_paddr = pt.paddr();
+                        pt.unmap();
+                        self.pde_walk_mut(va).set_entry(0);
+                        pm.free(pt_paddr);
+                    }
+                }
+            }
+
+            offset += chunk;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the physical address that `vaddr` translates to, if mapped
+    pub fn translate(&mut self, vaddr: u32) -> Option<u32> {
+        match self.pde_walk(vaddr).to_pde() {
+            PDEType::None => None,
+            PDEType::Direct(pde) => Some(pde.get_paddr() | (vaddr & LARGE_PAGE_MASK)),
+            PDEType::Table(pde) => {
+                let mut pt = self.map_pt(pde.pt_address());
+                let pte = *pt.pte_mut(vaddr);
+                if !pte.present() {
+                    return None;
+                }
+
+                Some(pte.frame_addr() | (vaddr & PAGE_MASK))
+            }
+        }
+    }
+
+    fn check_unmapped(&mut self, vaddr: u32, size: u32) -> Result<(), MapError> {
+        let mut page = vaddr;
+        while page.wrapping_sub(vaddr) < size {
+            match self.pde_walk(page).to_pde() {
+                PDEType::None => {
+                    // Skip to the next page directory entry
+                    page = (page & !LARGE_PAGE_MASK).wrapping_add(LARGE_PAGE_SIZE);
+                    if page == 0 {
+                        break;
+                    }
+                    continue;
+                }
+                PDEType::Direct(_) => return Err(MapError::AlreadyMapped(page)),
+                PDEType::Table(_) => {
+                    if self.translate(page).is_some() {
+                        return Err(MapError::AlreadyMapped(page));
+                    }
+                }
+            }
+
+            page = page.wrapping_add(PAGE_SIZE);
+            if page == 0 {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// First-fit search for an unmapped range in the dynamic region
+    fn find_free(&mut self, size: u32, align: u32) -> Result<u32, MapError> {
+        let mut candidate = DYNAMIC_START;
+        while candidate < DYNAMIC_END && DYNAMIC_END - candidate >= size {
+            match self.check_unmapped(candidate, size) {
+                Ok(()) => return Ok(candidate),
+                Err(MapError::AlreadyMapped(used)) => {
+                    candidate = (used + PAGE_SIZE).next_multiple_of(align);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        Err(MapError::OutOfAddressSpace)
+    }
+
+    /// Returns the page table covering `vaddr`, allocating one if the
+    /// page directory entry is empty
+    fn pt_for_vaddr(
+        &mut self,
+        vaddr: u32,
+        pm: &mut impl PhysramAllocator,
+    ) -> Option<ActivePageTable<'_>> {
+        match self.pde_walk(vaddr).to_pde() {
+            PDEType::Table(pde) => Some(self.map_pt(pde.pt_address())),
+            PDEType::Direct(_) => panic!("Address is mapped by a large page"),
+            PDEType::None => {
+                let pt = self.new_pt_mapped(pm)?;
+
+                let mut pde = PDETable(0);
+                pde.set_allow_writes(true);
+                pde.set_writethrough(true);
+                pde.set_disable_cache(true);
+                pde.set_allow_usermode(false);
+                unsafe { pde.set_pt_address(pt.paddr()) };
+                pde.set_present(true);
+
+                *pt.mapping.pde_walk_mut(vaddr) = pde.into();
+                Some(pt)
+            }
+        }
+    }
+
     fn new_pt_mapped<'a, 'b>(
         &'a mut self,
         pm: &'b mut impl PhysramAllocator,
-    ) -> ActivePageTable<'a> {
-        let frame = pm.alloc().unwrap();
+    ) -> Option<ActivePageTable<'a>> {
+        let frame = pm.alloc()?;
         let active_pte = self.map_pt(frame);
         active_pte.pt.fill(PageTableEntry(0));
-        active_pte
+        Some(active_pte)
     }
 
-    unsafe fn map_pt_vaddr(&mut self, frame: u32, idx: usize) -> ActivePageTable {
+    unsafe fn map_pt_vaddr(&mut self, frame: u32, idx: usize) -> ActivePageTable<'_> {
         let mapping_ent = &mut self.mapping_pt[idx + 2];
 
         mapping_ent.set_frame_addr(frame);
@@ -458,6 +808,8 @@ impl Mapping {
         mapping_ent.set_present(true);
 
         let pt_addr = 0xC000_2000 + idx * 0x1000;
+        invalidate_page(pt_addr as *const u8);
+
         let pt = &mut *(pt_addr as *mut [PageTableEntry; 1024]);
         ActivePageTable {
             mapping: self,
@@ -467,7 +819,7 @@ impl Mapping {
     }
 
     /// Maps a PTE, returning a handle to the PTE in virtual address space
-    fn map_pt(&mut self, pt_frame: u32) -> ActivePageTable {
+    fn map_pt(&mut self, pt_frame: u32) -> ActivePageTable<'_> {
         let mut free_idx = None;
         for idx in 0..(self.active_pt_indexes.len()) {
             let active_pt = &mut self.active_pt_indexes[idx];
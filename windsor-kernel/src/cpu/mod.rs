@@ -1,9 +1,14 @@
+pub mod context;
+pub mod exception;
 pub mod gdt;
 pub mod idt;
 pub mod io;
 pub mod irq;
 pub mod mmu;
+pub mod pagefault;
 pub mod pic;
+pub mod pit;
+pub mod tss;
 
 use core::arch::asm;
 
@@ -18,3 +23,46 @@ pub fn sti() {
         asm!("sti");
     }
 }
+
+/// Enables interrupts and halts until the next one arrives.
+/// The interrupt shadow of `sti` ensures that an interrupt becoming
+/// pending between the two instructions still wakes the CPU.
+pub fn sti_hlt() {
+    unsafe {
+        asm!("sti", "hlt");
+    }
+}
+
+/// Reads the time stamp counter
+pub fn rdtsc() -> u64 {
+    let lo: u32;
+    let hi: u32;
+    unsafe {
+        asm!("rdtsc", out("eax") lo, out("edx") hi, options(nomem, nostack));
+    }
+
+    ((hi as u64) << 32) | lo as u64
+}
+
+pub fn interrupts_enabled() -> bool {
+    let eflags: u32;
+    unsafe {
+        asm!("pushfd", "pop {}", out(reg) eflags);
+    }
+
+    eflags & (1 << 9) != 0
+}
+
+/// Runs `f` with interrupts disabled, restoring the previous state after
+pub fn without_interrupts<R>(f: impl FnOnce() -> R) -> R {
+    let enabled = interrupts_enabled();
+    cli();
+
+    let ret = f();
+
+    if enabled {
+        sti();
+    }
+
+    ret
+}
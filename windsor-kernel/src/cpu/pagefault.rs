@@ -0,0 +1,136 @@
+use core::ptr;
+
+use super::exception::{self, ExceptionFrame, PageFaultError};
+use super::mmu::{self, PhysramAllocator};
+use crate::physram;
+use crate::sync::SpinLock;
+
+/// Room for a guard page per thread stack, and the kernel's other regions
+const MAX_REGIONS: usize = 48;
+const PAGE_SIZE: u32 = 0x1000;
+
+#[derive(Copy, Clone)]
+pub enum RegionKind {
+    /// Backed by zeroed frames on first access
+    DemandZero,
+
+    /// Never mapped, any access is reported with the given description
+    Guard(&'static str),
+}
+
+#[derive(Copy, Clone)]
+pub struct Region {
+    start: u32,
+    end: u32,
+    kind: RegionKind,
+}
+
+impl Region {
+    fn contains(&self, vaddr: u32) -> bool {
+        vaddr >= self.start && vaddr < self.end
+    }
+}
+
+static REGIONS: SpinLock<[Option<Region>; MAX_REGIONS]> = SpinLock::new([None; MAX_REGIONS]);
+
+/// Registers a virtual region for the page fault handler.
+/// `start` and `size` are rounded out to page boundaries.
+pub fn register(start: u32, size: u32, kind: RegionKind) -> Result<(), ()> {
+    let region = Region {
+        start: start & !(PAGE_SIZE - 1),
+        end: (start + size).next_multiple_of(PAGE_SIZE),
+        kind,
+    };
+
+    let mut regions = REGIONS.lock();
+    let slot = regions.iter_mut().find(|r| r.is_none()).ok_or(())?;
+    *slot = Some(region);
+    Ok(())
+}
+
+/// Removes the region starting at `start`. Frames backing a
+/// demand-zero region are left mapped for the caller to release.
+pub fn unregister(start: u32) {
+    let start = start & !(PAGE_SIZE - 1);
+
+    for region in REGIONS.lock().iter_mut() {
+        if matches!(region, Some(r) if r.start == start) {
+            *region = None;
+        }
+    }
+}
+
+/// None if the fault interrupted `register`, leaving the
+/// region table locked, as the faulting access then isn't in one
+fn find_region(vaddr: u32) -> Option<Region> {
+    REGIONS
+        .try_lock()?
+        .iter()
+        .flatten()
+        .find(|r| r.contains(vaddr))
+        .copied()
+}
+
+/// Description of the guard region containing `vaddr`, if any
+pub fn guard_for(vaddr: u32) -> Option<&'static str> {
+    match find_region(vaddr)?.kind {
+        RegionKind::Guard(desc) => Some(desc),
+        RegionKind::DemandZero => None,
+    }
+}
+
+/// Maps a zeroed frame at `page`, or returns why it couldn't
+unsafe fn back_page(page: u32) -> Result<(), &'static str> {
+    // The fault may have interrupted code holding either lock
+    const LOCKED: &str = "Demand-zero fault with the page tables locked";
+    let mut mapping = mmu::KERNEL_MAPPING
+        .get()
+        .and_then(|mapping| mapping.try_lock())
+        .ok_or(LOCKED)?;
+    let mut pm = physram::ALLOCATOR.try_lock().ok_or(LOCKED)?;
+
+    let frame_paddr = pm.alloc().ok_or("Out of memory backing demand-zero page")?;
+    let mapped = mapping.map(
+        page,
+        frame_paddr,
+        PAGE_SIZE,
+        true,
+        mmu::CacheType::WriteBack,
+        &mut *pm,
+    );
+
+    if mapped.is_err() {
+        pm.free(frame_paddr);
+        return Err("Failed to map demand-zero page");
+    }
+
+    ptr::write_bytes(page as *mut u8, 0, PAGE_SIZE as usize);
+    Ok(())
+}
+
+/// Attempts to resolve a page fault, returning whether the faulting
+/// access can be retried. Faults in guard regions are fatal.
+pub fn handle(frame: &ExceptionFrame) -> bool {
+    let code = PageFaultError(frame.error_code);
+    let vaddr = frame.cr2;
+
+    let region = match find_region(vaddr) {
+        Some(region) => region,
+        None => return false,
+    };
+
+    match region.kind {
+        RegionKind::Guard(desc) => exception::fatal_with_reason(frame, desc),
+        RegionKind::DemandZero => {
+            // Protection violations are real bugs, not lazily backed pages
+            if code.present() {
+                return false;
+            }
+
+            match unsafe { back_page(vaddr & !(PAGE_SIZE - 1)) } {
+                Ok(()) => true,
+                Err(reason) => exception::fatal_with_reason(frame, reason),
+            }
+        }
+    }
+}
@@ -1,15 +1,162 @@
+use super::io;
+use crate::sync::SpinLock;
+
+const MASTER_CMD: u16 = 0x20;
+const MASTER_DATA: u16 = 0x21;
+const SLAVE_CMD: u16 = 0xa0;
+const SLAVE_DATA: u16 = 0xa1;
+
+const ICW1_ICW4: u8 = 0x01;
+const ICW1_INIT: u8 = 0x10;
+const ICW4_8086: u8 = 0x01;
+const OCW3_READ_ISR: u8 = 0x0b;
+const EOI: u8 = 0x20;
+
+/// Vector that IRQ0 is remapped to, with IRQ8 following at IRQ_BASE + 8
+pub const IRQ_BASE: u8 = 0x20;
+pub const IRQ_COUNT: u8 = 16;
+
+/// Master line that the slave PIC is cascaded through
+const CASCADE_IRQ: u8 = 2;
+
+const MAX_HANDLERS: usize = 4;
+
+static HANDLERS: SpinLock<[[Option<fn()>; MAX_HANDLERS]; IRQ_COUNT as usize]> =
+    SpinLock::new([[None; MAX_HANDLERS]; IRQ_COUNT as usize]);
+
+/// Identifies a registered handler, for use with `unregister`
+#[derive(Copy, Clone, Debug)]
+pub struct HandlerId {
+    irq: u8,
+    slot: usize,
+}
+
+/// Remaps both PICs to IRQ_BASE and masks every line except the cascade
 pub fn init() {
     unsafe {
-        super::io::write_u8(0x20, 0x15);
-        super::io::write_u8(0x21, 0x20);
-        super::io::write_u8(0x21, 0x04);
-        super::io::write_u8(0x21, 0x01);
-        super::io::write_u8(0x21, 0x00);
+        io::write_u8(MASTER_CMD, ICW1_INIT | ICW1_ICW4);
+        io::write_u8(SLAVE_CMD, ICW1_INIT | ICW1_ICW4);
+
+        io::write_u8(MASTER_DATA, IRQ_BASE);
+        io::write_u8(SLAVE_DATA, IRQ_BASE + 8);
+
+        io::write_u8(MASTER_DATA, 1 << CASCADE_IRQ);
+        io::write_u8(SLAVE_DATA, CASCADE_IRQ);
+
+        io::write_u8(MASTER_DATA, ICW4_8086);
+        io::write_u8(SLAVE_DATA, ICW4_8086);
+
+        io::write_u8(MASTER_DATA, !(1 << CASCADE_IRQ));
+        io::write_u8(SLAVE_DATA, 0xff);
+    }
+}
+
+fn data_port(irq: u8) -> (u16, u8) {
+    assert!(irq < IRQ_COUNT, "Invalid IRQ line");
+
+    if irq < 8 {
+        (MASTER_DATA, irq)
+    } else {
+        (SLAVE_DATA, irq - 8)
+    }
+}
+
+pub fn mask(irq: u8) {
+    let (port, bit) = data_port(irq);
+    super::without_interrupts(|| unsafe {
+        let mask = io::read_u8(port);
+        io::write_u8(port, mask | (1 << bit));
+    });
+}
+
+pub fn unmask(irq: u8) {
+    let (port, bit) = data_port(irq);
+    super::without_interrupts(|| unsafe {
+        let mask = io::read_u8(port);
+        io::write_u8(port, mask & !(1 << bit));
+    });
+}
+
+/// Returns the in-service register of both PICs, slave in the high byte
+fn read_isr() -> u16 {
+    unsafe {
+        io::write_u8(MASTER_CMD, OCW3_READ_ISR);
+        io::write_u8(SLAVE_CMD, OCW3_READ_ISR);
+        ((io::read_u8(SLAVE_CMD) as u16) << 8) | (io::read_u8(MASTER_CMD) as u16)
     }
 }
 
-pub fn reset() {
+/// Signals the end of an interrupt on the given line
+pub fn eoi(irq: u8) {
     unsafe {
-        super::io::write_u8(0x20, 0x20);
+        if irq >= 8 {
+            io::write_u8(SLAVE_CMD, EOI);
+        }
+
+        io::write_u8(MASTER_CMD, EOI);
+    }
+}
+
+/// Checks whether an IRQ7 or IRQ15 was raised without a line actually
+/// being in service. A spurious IRQ15 still needs an EOI sent to the
+/// master PIC, since the master did see a real request on the cascade.
+fn handle_spurious(irq: u8) -> bool {
+    if irq != 7 && irq != 15 {
+        return false;
+    }
+
+    if read_isr() & (1 << irq) != 0 {
+        return false;
+    }
+
+    if irq == 15 {
+        unsafe { io::write_u8(MASTER_CMD, EOI) };
+    }
+
+    true
+}
+
+/// Attaches a handler to an IRQ line and unmasks it.
+/// Lines may be shared by up to MAX_HANDLERS handlers.
+pub fn register(irq: u8, handler: fn()) -> Result<HandlerId, ()> {
+    assert!(irq < IRQ_COUNT, "Invalid IRQ line");
+
+    let id = {
+        let handlers = &mut HANDLERS.lock()[irq as usize];
+        let slot = handlers.iter().position(|h| h.is_none()).ok_or(())?;
+        handlers[slot] = Some(handler);
+        HandlerId { irq, slot }
+    };
+
+    unmask(id.irq);
+    Ok(id)
+}
+
+/// Detaches a handler, masking the line if no handlers remain
+pub fn unregister(id: HandlerId) {
+    let unused = {
+        let handlers = &mut HANDLERS.lock()[id.irq as usize];
+        handlers[id.slot] = None;
+        handlers.iter().all(|h| h.is_none())
+    };
+
+    if unused && id.irq != CASCADE_IRQ {
+        mask(id.irq);
     }
 }
+
+/// Runs the handlers attached to a line. Called from the IRQ stubs.
+pub fn dispatch(irq: u8) {
+    if handle_spurious(irq) {
+        return;
+    }
+
+    // Copied out so that handlers can register and unregister
+    let handlers = HANDLERS.lock()[irq as usize];
+    for handler in handlers.iter().flatten() {
+        handler();
+    }
+
+    crate::executor::notify_irq(irq);
+    eoi(irq);
+}
@@ -0,0 +1,52 @@
+use core::sync::atomic::{AtomicU16, Ordering};
+
+use super::io;
+
+const CHANNEL0_DATA: u16 = 0x40;
+const COMMAND: u16 = 0x43;
+
+/// Channel 0, low then high byte, mode 2 (rate generator), binary
+const CMD_CHANNEL0_RATE: u8 = 0x34;
+/// Channel 0 counter latch
+const CMD_CHANNEL0_LATCH: u8 = 0x00;
+
+/// Input clock of the 8254
+pub const BASE_HZ: u32 = 1_193_182;
+
+/// Line the PIT's channel 0 output is wired to
+pub const IRQ: u8 = 0;
+
+static RELOAD: AtomicU16 = AtomicU16::new(0);
+
+/// Programs channel 0 to interrupt `hz` times per second
+pub fn set_periodic(hz: u32) {
+    let reload = (BASE_HZ + hz / 2) / hz;
+    assert!(reload > 1 && reload <= 0xffff, "PIT frequency out of range");
+
+    RELOAD.store(reload as u16, Ordering::Release);
+    super::without_interrupts(|| unsafe {
+        io::write_u8(COMMAND, CMD_CHANNEL0_RATE);
+        io::write_u8(CHANNEL0_DATA, reload as u8);
+        io::write_u8(CHANNEL0_DATA, (reload >> 8) as u8);
+    });
+}
+
+/// Value channel 0 counts down from, as set by `set_periodic`
+pub fn reload() -> u16 {
+    RELOAD.load(Ordering::Acquire)
+}
+
+/// Actual interrupt rate after rounding the reload value
+pub fn frequency() -> u32 {
+    BASE_HZ / reload() as u32
+}
+
+/// Current channel 0 count, running from `reload()` down to 1
+pub fn read_count() -> u16 {
+    super::without_interrupts(|| unsafe {
+        io::write_u8(COMMAND, CMD_CHANNEL0_LATCH);
+        let lo = io::read_u8(CHANNEL0_DATA) as u16;
+        let hi = io::read_u8(CHANNEL0_DATA) as u16;
+        (hi << 8) | lo
+    })
+}
@@ -0,0 +1,147 @@
+use core::arch::asm;
+use core::ptr::{addr_of, addr_of_mut};
+
+use super::{exception, gdt, mmu, pagefault};
+
+pub const TSS_SELECTOR: u16 = (gdt::TSS_INDEX * 8) as u16;
+pub const DOUBLE_FAULT_TSS_SELECTOR: u16 = (gdt::DOUBLE_FAULT_TSS_INDEX * 8) as u16;
+
+const DOUBLE_FAULT_STACK_SIZE: usize = 8192;
+
+/// 32-bit Task State Segment. Segment selector slots are
+/// 16 bits wide, with the upper half reserved.
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct TaskStateSegment {
+    pub link: u32,
+    pub esp0: u32,
+    pub ss0: u32,
+    pub esp1: u32,
+    pub ss1: u32,
+    pub esp2: u32,
+    pub ss2: u32,
+    pub cr3: u32,
+    pub eip: u32,
+    pub eflags: u32,
+    pub eax: u32,
+    pub ecx: u32,
+    pub edx: u32,
+    pub ebx: u32,
+    pub esp: u32,
+    pub ebp: u32,
+    pub esi: u32,
+    pub edi: u32,
+    pub es: u32,
+    pub cs: u32,
+    pub ss: u32,
+    pub ds: u32,
+    pub fs: u32,
+    pub gs: u32,
+    pub ldtr: u32,
+    pub trap: u16,
+    pub iomap_base: u16,
+}
+
+impl TaskStateSegment {
+    pub const fn zero() -> Self {
+        Self {
+            link: 0,
+            esp0: 0,
+            ss0: 0,
+            esp1: 0,
+            ss1: 0,
+            esp2: 0,
+            ss2: 0,
+            cr3: 0,
+            eip: 0,
+            eflags: 0,
+            eax: 0,
+            ecx: 0,
+            edx: 0,
+            ebx: 0,
+            esp: 0,
+            ebp: 0,
+            esi: 0,
+            edi: 0,
+            es: 0,
+            cs: 0,
+            ss: 0,
+            ds: 0,
+            fs: 0,
+            gs: 0,
+            ldtr: 0,
+            trap: 0,
+            // No I/O permission bitmap
+            iomap_base: core::mem::size_of::<Self>() as u16,
+        }
+    }
+}
+
+#[repr(C, align(16))]
+struct Stack([u8; DOUBLE_FAULT_STACK_SIZE]);
+
+/// Kernel task. The CPU saves the interrupted state here when
+/// switching to the double fault task.
+static mut TSS: TaskStateSegment = TaskStateSegment::zero();
+
+static mut DOUBLE_FAULT_TSS: TaskStateSegment = TaskStateSegment::zero();
+static mut DOUBLE_FAULT_STACK: Stack = Stack([0; DOUBLE_FAULT_STACK_SIZE]);
+
+/// Fills in both TSS descriptors in the GDT.
+/// Must be called before the GDT is loaded.
+pub unsafe fn install(gdt: &mut [gdt::GDTSegment; gdt::GDT_ENTRIES]) {
+    TSS.ss0 = gdt::KERNEL_DS as u32;
+    TSS.esp0 = crate::kernel_stack_top();
+
+    let df_stack = addr_of_mut!(DOUBLE_FAULT_STACK);
+    let df = &mut *addr_of_mut!(DOUBLE_FAULT_TSS);
+    df.cr3 = mmu::CR3::current().0;
+    df.eip = double_fault_entry as *const () as u32;
+    df.esp = df_stack as u32 + DOUBLE_FAULT_STACK_SIZE as u32;
+    // Interrupts stay disabled, bit 1 is reserved as 1
+    df.eflags = 0x2;
+    df.cs = gdt::KERNEL_CS as u32;
+    df.ss = gdt::KERNEL_DS as u32;
+    df.ds = gdt::KERNEL_DS as u32;
+    df.es = gdt::KERNEL_DS as u32;
+    df.fs = gdt::KERNEL_DS as u32;
+    df.gs = gdt::KERNEL_DS as u32;
+
+    let limit = core::mem::size_of::<TaskStateSegment>() as u32 - 1;
+    gdt[gdt::TSS_INDEX] = gdt::GDTSegment::tss(addr_of!(TSS) as u32, limit);
+    gdt[gdt::DOUBLE_FAULT_TSS_INDEX] =
+        gdt::GDTSegment::tss(addr_of!(DOUBLE_FAULT_TSS) as u32, limit);
+}
+
+/// Loads the kernel task register. The GDT with the TSS
+/// descriptors from `install` must already be loaded.
+pub unsafe fn load() {
+    asm!("ltr ax", in("ax") TSS_SELECTOR);
+}
+
+/// Entry point of the double fault task, on a known-good stack.
+/// The CPU pushes the error code, so calling into the handler
+/// places it where the first cdecl argument is expected.
+#[naked]
+unsafe extern "C" fn double_fault_entry() {
+    asm!(
+        "call {handler}",
+        "ud2",
+        handler = sym double_fault,
+        options(noreturn),
+    );
+}
+
+extern "C" fn double_fault(error_code: u32) -> ! {
+    // The faulting context was saved to the kernel TSS by the task switch
+    let frame = unsafe {
+        exception::ExceptionFrame::from_tss(&*addr_of!(TSS), exception::DOUBLE_FAULT, error_code)
+    };
+
+    // Most commonly a fault while pushing an exception frame onto an
+    // overflowed stack, which leaves the guard page address in CR2
+    match pagefault::guard_for(frame.cr2) {
+        Some(desc) => exception::fatal_with_reason(&frame, desc),
+        None => exception::fatal(&frame),
+    }
+}
@@ -1,4 +1,4 @@
-use super::i2c;
+use super::{framebuffer, gfx, i2c, nv2a};
 use num::FromPrimitive;
 use num_derive::FromPrimitive;
 
@@ -18,6 +18,40 @@ pub enum AVMode {
     VGA = 0x7,
 }
 
+/// Share of video memory a mode's framebuffers may take
+const FRAMEBUFFER_BUDGET: u32 = nv2a::vram::SIZE / 16;
+
+/// Layout of 16 bit framebuffers, set at build time through
+/// WINDSOR_16BPP as either `rgb565` or `xrgb1555`
+const BUILD_16BPP: Option<&str> = option_env!("WINDSOR_16BPP");
+
+/// The 16 bit layout named by `spec`, R5G6B5 without one
+fn sixteen_bit_format(spec: Option<&str>) -> gfx::PixelFormat {
+    match spec {
+        None | Some("rgb565") => gfx::PixelFormat::Rgb565,
+        Some("xrgb1555") => gfx::PixelFormat::Xrgb1555,
+        Some(spec) => {
+            log::warn!("Ignoring WINDSOR_16BPP {:?}, using rgb565", spec);
+            gfx::PixelFormat::Rgb565
+        }
+    }
+}
+
+/// The deepest format whose framebuffers fit in FRAMEBUFFER_BUDGET
+/// at `width` by `height`, falling back to the 16 bit `fallback`
+fn pixel_format_for(width: u32, height: u32, fallback: gfx::PixelFormat) -> gfx::PixelFormat {
+    let size = |format: gfx::PixelFormat| {
+        width * height * format.bytes_per_pixel() as u32 * framebuffer::FRAMEBUFFER_COUNT as u32
+    };
+
+    if size(gfx::PixelFormat::Argb8888) <= FRAMEBUFFER_BUDGET {
+        gfx::PixelFormat::Argb8888
+    } else {
+        fallback
+    }
+}
+
+#[derive(Copy, Clone)]
 pub struct VideoModeInfo {
     pub width: u32,
     pub height: u32,
@@ -27,13 +61,27 @@ pub struct VideoModeInfo {
     pub nvvtotal: u32,
     pub nvhstart: u32,
     pub nvvstart: u32,
-    pub pixel_depth: u32,
+    pub pixel_format: gfx::PixelFormat,
+
+    /// Pixel clock in kHz, if the GPU should generate it through VPLL
+    pub pixel_clock: Option<u32>,
 
     pub crtc_hend: u32,
     pub crtc_vstart: u32,
     pub crtc_vtotal: u32,
 }
 
+impl VideoModeInfo {
+    pub fn bytes_per_pixel(&self) -> u32 {
+        self.pixel_format.bytes_per_pixel() as u32
+    }
+
+    /// Bytes from the start of one scanline to the next
+    pub fn pitch(&self) -> u32 {
+        self.width * self.bytes_per_pixel()
+    }
+}
+
 pub trait Encoder {}
 
 impl Model {
@@ -73,22 +121,25 @@ impl AVMode {
     fn get_vm_hdtv(&self, enc: &Model) -> Option<VideoModeInfo> {
         // FIXME: Support more than 480p
 
-        let (nvhtotal, nvvtotal) = if enc.is_xcalibur() {
-            (779, 524)
+        // Xcalibur timings are paced by the encoder's clock
+        let (nvhtotal, nvvtotal, pixel_clock) = if enc.is_xcalibur() {
+            (779, 524, None)
         } else {
-            (858, 525)
+            (858, 525, Some(27_000))
         };
 
+        let (width, height) = (720, 480);
         Some(VideoModeInfo {
-            width: 720,
-            height: 480,
+            width,
+            height,
             xmargin: 0,
             ymargin: 0,
             nvhtotal,
             nvvtotal,
             nvhstart: 738,
             nvvstart: 489,
-            pixel_depth: (32 + 1) / 8,
+            pixel_format: pixel_format_for(width, height, sixteen_bit_format(BUILD_16BPP)),
+            pixel_clock,
 
             crtc_hend: 720,
             crtc_vstart: 489,
@@ -103,3 +154,43 @@ impl AVMode {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sd_modes_keep_32_bits_per_pixel() {
+        let mode = AVMode::HDTV.get_video_mode(&Model::Conexant).unwrap();
+        assert_eq!(mode.pixel_format, gfx::PixelFormat::Argb8888);
+    }
+
+    #[test]
+    fn large_modes_fall_back_to_16_bits_per_pixel() {
+        // 720p and 1080i framebuffers at 32bpp don't fit in 64 MiB units' budget
+        let rgb565 = sixteen_bit_format(None);
+        assert_eq!(
+            pixel_format_for(1280, 720, rgb565),
+            gfx::PixelFormat::Rgb565
+        );
+        assert_eq!(
+            pixel_format_for(1920, 1080, rgb565),
+            gfx::PixelFormat::Rgb565
+        );
+
+        let xrgb1555 = sixteen_bit_format(Some("xrgb1555"));
+        assert_eq!(
+            pixel_format_for(1280, 720, xrgb1555),
+            gfx::PixelFormat::Xrgb1555
+        );
+        assert_eq!(
+            pixel_format_for(720, 480, xrgb1555),
+            gfx::PixelFormat::Argb8888
+        );
+    }
+
+    #[test]
+    fn unknown_16_bit_layouts_use_rgb565() {
+        assert_eq!(sixteen_bit_format(Some("rgb555")), gfx::PixelFormat::Rgb565);
+    }
+}
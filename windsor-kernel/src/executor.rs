@@ -0,0 +1,272 @@
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use alloc::vec::Vec;
+use core::future::{poll_fn, Future};
+use core::pin::{pin, Pin};
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+
+use crate::cpu::pic;
+use crate::sync::{FixedQueue, SpinLock};
+use crate::thread::WaitQueue;
+use crate::time;
+
+/// Tasks that can exist at once
+pub const MAX_TASKS: usize = 32;
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+struct TaskId(u32);
+
+struct Task {
+    id: TaskId,
+    future: Pin<Box<dyn Future<Output = ()> + Send>>,
+    /// Outlives the task until `run` holds the last reference, so that
+    /// interrupt handlers dropping a stale waker never free it
+    waker: Arc<TaskWaker>,
+}
+
+static NEXT_ID: AtomicU32 = AtomicU32::new(0);
+/// Tasks spawned and not yet completed
+static TASK_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Tasks spawned since the run loop last picked them up
+static SPAWNED: SpinLock<Vec<Task>> = SpinLock::new(Vec::new());
+/// Tasks woken since they were last polled. A task is only queued
+/// once until it is polled, so MAX_TASKS entries are always enough.
+static READY: SpinLock<FixedQueue<TaskId, MAX_TASKS>> = SpinLock::new(FixedQueue::new());
+/// Wakes the run loop when a task becomes ready
+static IDLE: WaitQueue = WaitQueue::new();
+
+struct TaskWaker {
+    id: TaskId,
+    /// Set while the task is on the READY queue
+    queued: AtomicBool,
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        if !self.queued.swap(true, Ordering::AcqRel) {
+            READY.lock().push_back(self.id);
+            IDLE.wake_all();
+        }
+    }
+}
+
+/// Queues a future to be run by the executor.
+/// Fails if MAX_TASKS tasks are already running.
+pub fn spawn(future: impl Future<Output = ()> + Send + 'static) -> Result<(), ()> {
+    let count = TASK_COUNT.fetch_add(1, Ordering::AcqRel);
+    if count >= MAX_TASKS {
+        TASK_COUNT.fetch_sub(1, Ordering::AcqRel);
+        return Err(());
+    }
+
+    let id = TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed));
+    let waker = Arc::new(TaskWaker {
+        id,
+        queued: AtomicBool::new(false),
+    });
+
+    SPAWNED.lock().push(Task {
+        id,
+        future: Box::pin(future),
+        waker: waker.clone(),
+    });
+
+    waker.wake();
+    Ok(())
+}
+
+/// Polls tasks as they are woken, sleeping until an interrupt
+/// or another thread wakes one when there is nothing to do
+pub fn run() -> ! {
+    let mut tasks = BTreeMap::new();
+    // Wakers of completed tasks, freed here once nothing else holds them
+    let mut retired: Vec<Arc<TaskWaker>> = Vec::new();
+
+    loop {
+        retired.retain(|waker| Arc::strong_count(waker) > 1);
+
+        let id = match READY.lock().pop_front() {
+            Some(id) => id,
+            None => {
+                IDLE.wait_until(|| !READY.lock().is_empty());
+                continue;
+            }
+        };
+
+        // Tasks are added to SPAWNED before they are first queued
+        for task in core::mem::take(&mut *SPAWNED.lock()) {
+            tasks.insert(task.id, task);
+        }
+
+        // Tasks may be woken again after they have completed
+        let task = match tasks.get_mut(&id) {
+            Some(task) => task,
+            None => continue,
+        };
+
+        // Wakes from here on queue the task to be polled again
+        task.waker.queued.store(false, Ordering::Release);
+
+        let waker = Waker::from(task.waker.clone());
+        let mut cx = Context::from_waker(&waker);
+        if task.future.as_mut().poll(&mut cx).is_ready() {
+            // Leftover wakers may still be woken, but won't queue it again
+            task.waker.queued.store(true, Ordering::Release);
+            if let Some(task) = tasks.remove(&id) {
+                retired.push(task.waker);
+            }
+            TASK_COUNT.fetch_sub(1, Ordering::AcqRel);
+        }
+    }
+}
+
+/// Wakers waiting on an event, for futures that are
+/// completed from interrupt handlers
+pub struct WakerList {
+    wakers: SpinLock<Vec<Waker>>,
+}
+
+impl WakerList {
+    pub const fn new() -> Self {
+        Self {
+            wakers: SpinLock::new(Vec::new()),
+        }
+    }
+
+    pub fn register(&self, waker: &Waker) {
+        let mut wakers = self.wakers.lock();
+        if !wakers.iter().any(|w| w.will_wake(waker)) {
+            wakers.push(waker.clone());
+        }
+    }
+
+    /// Wakes and removes every registered waker. The list keeps its
+    /// memory and `run` frees task wakers, so interrupt handlers
+    /// calling this never free memory.
+    pub fn wake_all(&self) {
+        loop {
+            let waker = match self.wakers.lock().pop() {
+                Some(waker) => waker,
+                None => break,
+            };
+            waker.wake();
+        }
+    }
+}
+
+static IRQ_WAKERS: [WakerList; pic::IRQ_COUNT as usize] =
+    [const { WakerList::new() }; pic::IRQ_COUNT as usize];
+
+/// Wakes futures waiting on an IRQ line. Called from `pic::dispatch`
+/// after the line's handlers have run.
+pub fn notify_irq(irq: u8) {
+    IRQ_WAKERS[irq as usize].wake_all();
+}
+
+/// Polls `f` until it returns a value, checking again after each
+/// interrupt on `irq`. The line must be unmasked, either directly
+/// or by registering a handler that acknowledges the device.
+pub fn irq_until<T>(irq: u8, mut f: impl FnMut() -> Option<T>) -> impl Future<Output = T> {
+    poll_fn(move |cx| {
+        if let Some(value) = f() {
+            return Poll::Ready(value);
+        }
+
+        IRQ_WAKERS[irq as usize].register(cx.waker());
+
+        // The interrupt may have arrived before the waker was registered
+        match f() {
+            Some(value) => Poll::Ready(value),
+            None => Poll::Pending,
+        }
+    })
+}
+
+static NEXT_SLEEP_ID: AtomicU32 = AtomicU32::new(0);
+
+/// Pending sleeps by id, woken by the timer tick once their deadline passes
+static SLEEPERS: SpinLock<Vec<(u32, u64, Waker)>> = SpinLock::new(Vec::new());
+
+/// Wakes sleeps that have expired. Called from the timer interrupt.
+pub(crate) fn tick(now: u64) {
+    loop {
+        let waker = {
+            let mut sleepers = SLEEPERS.lock();
+            let expired = sleepers.iter().position(|sleeper| sleeper.1 <= now);
+            match expired {
+                Some(idx) => sleepers.swap_remove(idx).2,
+                None => break,
+            }
+        };
+        waker.wake();
+    }
+}
+
+/// Future returned by `sleep`, which deregisters itself when dropped
+pub struct Sleep {
+    deadline: u64,
+    id: Option<u32>,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if time::ticks() >= self.deadline {
+            return Poll::Ready(());
+        }
+
+        // Task wakers don't change, so one registration is enough
+        if self.id.is_none() {
+            let id = NEXT_SLEEP_ID.fetch_add(1, Ordering::Relaxed);
+            let waker = cx.waker().clone();
+            SLEEPERS.lock().push((id, self.deadline, waker));
+            self.id = Some(id);
+        }
+
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            SLEEPERS.lock().retain(|&(other, _, _)| other != id);
+        }
+    }
+}
+
+/// Completes after at least `duration`
+pub fn sleep(duration: Duration) -> Sleep {
+    Sleep {
+        deadline: time::ticks() + time::to_ticks(duration),
+        id: None,
+    }
+}
+
+/// Runs `future`, giving up if it hasn't completed after `duration`
+pub async fn timeout<F: Future>(duration: Duration, future: F) -> Option<F::Output> {
+    let mut future = pin!(future);
+    let mut expired = pin!(sleep(duration));
+
+    poll_fn(|cx| {
+        if let Poll::Ready(value) = future.as_mut().poll(cx) {
+            return Poll::Ready(Some(value));
+        }
+
+        match expired.as_mut().poll(cx) {
+            Poll::Ready(()) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    })
+    .await
+}
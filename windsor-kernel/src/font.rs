@@ -6,9 +6,12 @@
 // 00-7F: Basic Latin
 // 80-FF: Latin-1 Supplement
 
+use alloc::vec::Vec;
+
+use crate::sync::OnceCell;
+
 pub const WIDTH: u32 = 8;
 pub const HEIGHT: u32 = 16;
-pub const VMIRROR: u32 = 0;
 
 pub const FONT: &'static [u8] = &[
     0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
@@ -524,3 +527,265 @@ pub const FONT: &'static [u8] = &[
     0x00, 0x00, 0x66, 0x66, 0x00, 0x00, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x3E, 0x06, 0x06,
     0x3C, // Symbol 0xFF
 ];
+
+const PSF2_MAGIC: [u8; 4] = [0x72, 0xb5, 0x4a, 0x86];
+const PSF2_HAS_UNICODE_TABLE: u32 = 0x01;
+/// Size of the PSF2 header fields, which later versions may extend
+const PSF2_HEADER_SIZE: usize = 32;
+/// Starts a multi code point sequence in a PSF2 Unicode table entry
+const PSF2_SEQUENCE_START: u8 = 0xfe;
+/// Ends the Unicode table entry of a glyph
+const PSF2_ENTRY_END: u8 = 0xff;
+
+/// PSF2 font embedded by build-tool, empty if none was given
+static EMBEDDED: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/console.psf"));
+
+/// The unscii font above, covering Latin-1
+pub static BUILTIN: Font<'static> = Font {
+    width: WIDTH,
+    height: HEIGHT,
+    bytes_per_row: ((WIDTH + 7) / 8) as usize,
+    glyph_count: FONT.len() / (((WIDTH + 7) / 8) * HEIGHT) as usize,
+    glyphs: FONT,
+    code_points: CodePoints::Direct,
+};
+
+static DEFAULT: OnceCell<Font<'static>> = OnceCell::new();
+
+enum CodePoints {
+    /// Glyphs are indexed by code point
+    Direct,
+    /// Code points sorted with their glyph index,
+    /// from the Unicode table of a PSF2 font
+    Table(Vec<(u32, u32)>),
+}
+
+/// Bitmap font with one bit per pixel, most significant bit leftmost
+pub struct Font<'a> {
+    width: u32,
+    height: u32,
+    bytes_per_row: usize,
+    glyph_count: usize,
+    glyphs: &'a [u8],
+    code_points: CodePoints,
+}
+
+/// Bitmap of a single character
+pub struct Glyph<'a> {
+    rows: &'a [u8],
+    bytes_per_row: usize,
+}
+
+impl Glyph<'_> {
+    pub fn is_set(&self, x: u32, y: u32) -> bool {
+        let byte = self.rows[y as usize * self.bytes_per_row + x as usize / 8];
+        byte & (0x80 >> (x % 8)) != 0
+    }
+}
+
+impl<'a> Font<'a> {
+    /// Parses a PSF2 font. Without a Unicode table,
+    /// glyphs are taken to be indexed by code point.
+    pub fn parse_psf2(data: &'a [u8]) -> Result<Self, ()> {
+        let field = |idx: usize| {
+            let bytes = data.get(idx * 4..idx * 4 + 4).ok_or(())?;
+            Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+        };
+
+        if data.get(..4) != Some(&PSF2_MAGIC[..]) {
+            return Err(());
+        }
+
+        let header_size = field(2)? as usize;
+        if header_size < PSF2_HEADER_SIZE {
+            return Err(());
+        }
+
+        let flags = field(3)?;
+        let glyph_count = field(4)? as usize;
+        let glyph_size = field(5)? as usize;
+        let height = field(6)?;
+        let width = field(7)?;
+
+        let bytes_per_row = ((width + 7) / 8) as usize;
+        if width == 0 || height == 0 || glyph_size != bytes_per_row * height as usize {
+            return Err(());
+        }
+
+        let glyphs_end = glyph_count
+            .checked_mul(glyph_size)
+            .and_then(|size| size.checked_add(header_size))
+            .ok_or(())?;
+        let glyphs = data.get(header_size..glyphs_end).ok_or(())?;
+
+        let code_points = if flags & PSF2_HAS_UNICODE_TABLE != 0 {
+            CodePoints::Table(parse_unicode_table(&data[glyphs_end..], glyph_count)?)
+        } else {
+            CodePoints::Direct
+        };
+
+        Ok(Self {
+            width,
+            height,
+            bytes_per_row,
+            glyph_count,
+            glyphs,
+            code_points,
+        })
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    fn index(&self, ch: char) -> Option<usize> {
+        let idx = match &self.code_points {
+            CodePoints::Direct => ch as usize,
+            CodePoints::Table(table) => {
+                let pos = table.binary_search_by_key(&(ch as u32), |&(cp, _)| cp);
+                table[pos.ok()?].1 as usize
+            }
+        };
+
+        (idx < self.glyph_count).then_some(idx)
+    }
+
+    /// Glyph for `ch`, or for a replacement character if the font lacks it
+    pub fn glyph(&self, ch: char) -> Glyph<'_> {
+        let idx = self
+            .index(ch)
+            .or_else(|| self.index(char::REPLACEMENT_CHARACTER))
+            .or_else(|| self.index('?'))
+            .unwrap_or(0);
+
+        let size = self.bytes_per_row * self.height as usize;
+        Glyph {
+            rows: &self.glyphs[idx * size..(idx + 1) * size],
+            bytes_per_row: self.bytes_per_row,
+        }
+    }
+
+    /// Largest integer scale that still fits 80 columns in `width`
+    /// pixels, giving 2x on 720p and 3x on 1080i
+    pub fn scale_for(&self, width: u32) -> u32 {
+        (width / (80 * self.width)).max(1)
+    }
+}
+
+/// Maps the single code points in a PSF2 Unicode table to glyph indices.
+/// Each glyph has an entry of UTF-8 characters, followed by sequences
+/// of combining characters that are skipped.
+fn parse_unicode_table(data: &[u8], glyph_count: usize) -> Result<Vec<(u32, u32)>, ()> {
+    let mut table = Vec::new();
+    let mut entries = data.split(|&b| b == PSF2_ENTRY_END);
+
+    for idx in 0..glyph_count {
+        let entry = entries.next().ok_or(())?;
+        let singles = entry.split(|&b| b == PSF2_SEQUENCE_START).next().unwrap();
+        let singles = core::str::from_utf8(singles).map_err(|_| ())?;
+        table.extend(singles.chars().map(|ch| (ch as u32, idx as u32)));
+    }
+
+    // The first glyph listed for a code point wins
+    table.sort_by_key(|&(cp, idx)| (cp, idx));
+    table.dedup_by_key(|&mut (cp, _)| cp);
+    Ok(table)
+}
+
+/// Loads the font build-tool embedded, if any. The heap must be set up first.
+pub fn init() {
+    if EMBEDDED.is_empty() {
+        return;
+    }
+
+    match Font::parse_psf2(EMBEDDED) {
+        Ok(font) => {
+            let _ = DEFAULT.set(font);
+        }
+        Err(()) => log::warn!("Embedded console font is not a valid PSF2 font"),
+    }
+}
+
+/// Font for text on screen, the embedded one once loaded
+pub fn default() -> &'static Font<'static> {
+    DEFAULT.get().unwrap_or(&BUILTIN)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Two 10x2 glyphs, a bar and a box, with a Unicode table
+    fn psf2(table: &[u8]) -> Vec<u8> {
+        let mut data = Vec::from(&PSF2_MAGIC[..]);
+        for field in [0, 32, PSF2_HAS_UNICODE_TABLE, 2, 4, 2, 10] {
+            data.extend_from_slice(&(field as u32).to_le_bytes());
+        }
+        data.extend_from_slice(&[0xff, 0xc0, 0x00, 0x00]);
+        data.extend_from_slice(&[0xff, 0xc0, 0x80, 0x40]);
+        data.extend_from_slice(table);
+        data
+    }
+
+    #[test]
+    fn parses_psf2_glyphs() {
+        let data = psf2(&[b'-', 0xff, b'#', 0xff]);
+        let font = Font::parse_psf2(&data).unwrap();
+        assert_eq!((font.width(), font.height()), (10, 2));
+
+        let bar = font.glyph('-');
+        assert!((0..10).all(|x| bar.is_set(x, 0) && !bar.is_set(x, 1)));
+
+        let square = font.glyph('#');
+        assert!(square.is_set(0, 1) && square.is_set(9, 1) && !square.is_set(5, 1));
+    }
+
+    #[test]
+    fn unicode_table_maps_code_points() {
+        // The box also stands in for U+2588 and a combining sequence
+        let mut table = Vec::from(&b"-\xff#"[..]);
+        table.extend_from_slice("\u{2588}".as_bytes());
+        table.push(PSF2_SEQUENCE_START);
+        table.extend_from_slice("a\u{301}".as_bytes());
+        table.push(0xff);
+
+        let data = psf2(&table);
+        let font = Font::parse_psf2(&data).unwrap();
+        assert_eq!(font.index('\u{2588}'), Some(1));
+        assert_eq!(font.index('a'), None);
+        assert_eq!(font.index('?'), None);
+    }
+
+    #[test]
+    fn missing_glyphs_fall_back() {
+        assert_eq!(BUILTIN.index('\u{2603}'), None);
+        let snowman = BUILTIN.glyph('\u{2603}');
+        let question = BUILTIN.glyph('?');
+        assert_eq!(snowman.rows, question.rows);
+        assert_eq!(BUILTIN.index('é'), Some(0xe9));
+    }
+
+    #[test]
+    fn rejects_malformed_fonts() {
+        let data = psf2(&[b'-', 0xff, b'#', 0xff]);
+        assert!(Font::parse_psf2(&data[..40]).is_err());
+        assert!(Font::parse_psf2(&data[1..]).is_err());
+        assert!(Font::parse_psf2(&psf2(&[0xc3, 0xff, b'#', 0xff])).is_err());
+
+        // Glyphs can't start inside the header
+        let mut overlapping = data.clone();
+        overlapping[8] = 16;
+        assert!(Font::parse_psf2(&overlapping).is_err());
+    }
+
+    #[test]
+    fn scale_keeps_80_columns() {
+        assert_eq!(BUILTIN.scale_for(640), 1);
+        assert_eq!(BUILTIN.scale_for(1280), 2);
+        assert_eq!(BUILTIN.scale_for(1920), 3);
+    }
+}
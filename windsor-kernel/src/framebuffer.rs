@@ -0,0 +1,294 @@
+use crate::cpu::mmu::{self, MapError};
+use crate::encoder;
+use crate::gfx;
+use crate::nv2a::{self, accel, vblank, vram::GpuMemory};
+use crate::print::RGBA;
+
+pub const MAX_BUFFERS: usize = 3;
+
+/// Buffers the framebuffer is allocated with, front and back
+pub const FRAMEBUFFER_COUNT: usize = 2;
+
+/// PCRTC start addresses need no more than page alignment
+const SCANOUT_ALIGN: u32 = 0x1000;
+
+/// Bytes each buffer takes for `vm`, in whole pages of GPU memory
+fn buffer_size_for(vm: &encoder::VideoModeInfo) -> u32 {
+    (vm.pitch() * vm.height).next_multiple_of(mmu::PAGE_SIZE)
+}
+
+/// Manages a set of scanout buffers allocated from GPU memory.
+/// Drawing happens in the back buffer, which is queued for scanout
+/// with `flip` and latched into PCRTC by the next vblank interrupt.
+pub struct Framebuffer {
+    buffers: [Option<GpuMemory>; MAX_BUFFERS],
+    count: usize,
+    buffer_size: u32,
+    width: u32,
+    height: u32,
+    format: gfx::PixelFormat,
+
+    front: usize,
+    back: usize,
+    pending: Option<usize>,
+    /// Color the GPU may still be filling the back buffer with
+    gpu_clear: Option<RGBA>,
+}
+
+impl Framebuffer {
+    /// Allocates up to `buffers` buffers sized for the video mode,
+    /// scanning out the first one.
+    /// Safety: the kernel mapping must be set up
+    pub unsafe fn new(
+        gpu: &mut nv2a::NV2A,
+        vm: &encoder::VideoModeInfo,
+        buffers: usize,
+    ) -> Result<Self, MapError> {
+        let mut fb = Self {
+            buffers: [None, None, None],
+            count: 0,
+            buffer_size: 0,
+            width: 0,
+            height: 0,
+            format: gfx::PixelFormat::Argb8888,
+            front: 0,
+            back: 0,
+            pending: None,
+            gpu_clear: None,
+        };
+
+        fb.allocate(vm, buffers.min(MAX_BUFFERS))?;
+        gpu.set_fb(fb.offset(0));
+        Ok(fb)
+    }
+
+    unsafe fn allocate(
+        &mut self,
+        vm: &encoder::VideoModeInfo,
+        count: usize,
+    ) -> Result<(), MapError> {
+        assert!(count > 0, "Framebuffer needs at least one buffer");
+
+        self.width = vm.width;
+        self.height = vm.height;
+        self.format = vm.pixel_format;
+        self.buffer_size = buffer_size_for(vm);
+
+        for idx in 0..count {
+            match GpuMemory::alloc(self.buffer_size, SCANOUT_ALIGN) {
+                Ok(mem) => self.buffers[idx] = Some(mem),
+                Err(e) => {
+                    self.release();
+                    return Err(e);
+                }
+            }
+        }
+
+        self.count = count;
+        self.front = 0;
+        self.back = if count > 1 { 1 } else { 0 };
+        self.pending = None;
+        self.gpu_clear = None;
+        Ok(())
+    }
+
+    unsafe fn release(&mut self) {
+        for buffer in self.buffers.iter_mut() {
+            if let Some(mem) = buffer.take() {
+                mem.free();
+            }
+        }
+
+        self.count = 0;
+    }
+
+    /// Frees the buffers and allocates new ones for a new video mode,
+    /// keeping the buffer count. The first new buffer is scanned out.
+    /// Safety: nothing else may hold on to a buffer, like the console
+    /// or the panic screen
+    pub unsafe fn resize(&mut self, vm: &encoder::VideoModeInfo) -> Result<(), MapError> {
+        let count = self.count;
+        self.wait_flip();
+        // The GPU may still be filling a buffer that's about to be freed
+        self.sync_gpu();
+
+        let mut gpu = nv2a::lock();
+        // Stop scanning out of memory that's about to be released
+        gpu.set_fb(0);
+        self.release();
+
+        self.allocate(vm, count)?;
+        gpu.set_fb(self.offset(0));
+        Ok(())
+    }
+
+    fn buffer(&self, idx: usize) -> &GpuMemory {
+        self.buffers[idx]
+            .as_ref()
+            .expect("Framebuffer buffer should be allocated")
+    }
+
+    /// PCRTC takes an offset into video memory, which is physical RAM
+    fn offset(&self, idx: usize) -> u32 {
+        self.buffer(idx).offset()
+    }
+
+    pub fn buffer_count(&self) -> usize {
+        self.count
+    }
+
+    /// Bytes of GPU memory each buffer takes
+    pub fn buffer_size(&self) -> u32 {
+        self.buffer_size
+    }
+
+    /// Address of the buffer that should be drawn into
+    pub fn back_buffer_addr(&self) -> *mut u8 {
+        self.buffer(self.back).vaddr() as *mut u8
+    }
+
+    pub fn format(&self) -> gfx::PixelFormat {
+        self.format
+    }
+
+    /// Bytes from one row to the next
+    fn pitch(&self) -> u32 {
+        self.width * self.format.bytes_per_pixel() as u32
+    }
+
+    /// Surface for drawing into the back buffer, once the GPU is done with it
+    pub fn surface(&mut self) -> gfx::Surface<'_> {
+        self.sync_gpu();
+        self.cpu_surface()
+    }
+
+    fn cpu_surface(&mut self) -> gfx::Surface<'_> {
+        unsafe {
+            gfx::Surface::from_raw(
+                self.back_buffer_addr(),
+                self.width,
+                self.height,
+                self.pitch() as usize,
+                self.format,
+            )
+        }
+    }
+
+    fn accel_surface(&self, idx: usize) -> Option<accel::Surface> {
+        accel::Surface::new(
+            self.offset(idx),
+            self.width,
+            self.height,
+            self.pitch(),
+            self.format,
+        )
+        .ok()
+    }
+
+    /// The back buffer for the 2D engine, if it can draw into it
+    pub fn back_buffer_accel(&self) -> Option<accel::Surface> {
+        self.accel_surface(self.back)
+    }
+
+    /// The front buffer for the 2D engine, if it can draw into it
+    pub fn front_buffer_accel(&self) -> Option<accel::Surface> {
+        self.accel_surface(self.front)
+    }
+
+    /// Fills the back buffer with `color`, on the GPU if it's available.
+    /// The GPU isn't waited for until the buffer is drawn into or flipped.
+    pub fn clear(&mut self, color: RGBA) {
+        if let (Some(surface), Some(mut accel)) = (self.back_buffer_accel(), accel::lock()) {
+            if accel.fill_rect(&surface, surface.bounds(), color).is_ok() {
+                self.gpu_clear = Some(color);
+                return;
+            }
+        }
+
+        self.surface().clear(color);
+    }
+
+    /// Waits for the GPU to fill the back buffer, filling
+    /// it with the CPU instead if the GPU stopped responding
+    fn sync_gpu(&mut self) {
+        let Some(color) = self.gpu_clear.take() else {
+            return;
+        };
+
+        let finished = accel::lock().ok_or(()).and_then(|mut accel| accel.finish());
+        if finished.is_err() {
+            self.cpu_surface().clear(color);
+        }
+    }
+
+    /// Address of the buffer currently being scanned out
+    pub fn front_buffer_addr(&self) -> *mut u8 {
+        self.buffer(self.front).vaddr() as *mut u8
+    }
+
+    /// Video memory offset of the buffer currently being scanned out
+    pub fn front_buffer_offset(&self) -> u32 {
+        self.offset(self.front)
+    }
+
+    pub fn flip_pending(&mut self) -> bool {
+        if let Some(next) = self.pending {
+            if vblank::fb_pending() {
+                return true;
+            }
+
+            self.front = next;
+            self.pending = None;
+        }
+
+        false
+    }
+
+    /// Redraws the console into the back buffer, where it keeps drawing
+    /// from then on, and queues the buffer for scanout on the next vblank.
+    /// With double buffering, the new back buffer is still on screen until
+    /// the flip completes, so callers must `wait_flip` before drawing again.
+    pub fn flip(&mut self) {
+        self.sync_gpu();
+        if let Some(console) = crate::CONSOLE.lock().as_mut() {
+            console.retarget(self.back_buffer_addr(), self.back_buffer_accel());
+        }
+
+        if self.count == 1 {
+            return;
+        }
+
+        self.pending = Some(self.back);
+        vblank::queue_fb(self.offset(self.back));
+
+        self.back = (self.back + 1) % self.count;
+        if self.back == self.front && self.count > 2 {
+            self.back = (self.back + 1) % self.count;
+        }
+    }
+
+    /// Sleeps until the queued flip has been latched
+    pub fn wait_flip(&mut self) {
+        while self.flip_pending() {
+            vblank::wait_flip();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buffers_are_whole_pages() {
+        let mut vm = encoder::AVMode::HDTV
+            .get_video_mode(&encoder::Model::Conexant)
+            .unwrap();
+
+        // 720x480 is 337.5 pages at 32bpp and 168.75 at 16bpp
+        assert_eq!(buffer_size_for(&vm), 338 * mmu::PAGE_SIZE);
+
+        vm.pixel_format = gfx::PixelFormat::Rgb565;
+        assert_eq!(buffer_size_for(&vm), 169 * mmu::PAGE_SIZE);
+    }
+}
@@ -0,0 +1,611 @@
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+
+use crate::font::Font;
+use crate::print::RGBA;
+
+/// Layout of a pixel in a framebuffer
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum PixelFormat {
+    /// 32 bits, blue in the low byte, then green, red and alpha
+    Argb8888,
+    /// 16 bits, 5 bits of red in the high bits, 6 of green and 5 of blue
+    Rgb565,
+    /// 16 bits, an unused top bit followed by 5 bits each of red, green and blue
+    Xrgb1555,
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            Self::Argb8888 => 4,
+            Self::Rgb565 | Self::Xrgb1555 => 2,
+        }
+    }
+
+    /// Writes an encoded pixel.
+    /// Safety: `ptr` must be valid for a pixel of this format
+    pub unsafe fn write(self, ptr: *mut u8, pixel: u32) {
+        match self.bytes_per_pixel() {
+            4 => (ptr as *mut u32).write_volatile(pixel),
+            _ => (ptr as *mut u16).write_volatile(pixel as u16),
+        }
+    }
+
+    /// Reads an encoded pixel.
+    /// Safety: `ptr` must be valid for a pixel of this format
+    pub unsafe fn read(self, ptr: *const u8) -> u32 {
+        match self.bytes_per_pixel() {
+            4 => (ptr as *const u32).read_volatile(),
+            _ => (ptr as *const u16).read_volatile() as u32,
+        }
+    }
+
+    pub fn encode(self, color: RGBA) -> u32 {
+        match self {
+            Self::Argb8888 => color.to_pixel(),
+            Self::Rgb565 => {
+                let (r, g, b) = (color.r() as u32, color.g() as u32, color.b() as u32);
+                (r >> 3) << 11 | (g >> 2) << 5 | b >> 3
+            }
+            Self::Xrgb1555 => {
+                let (r, g, b) = (color.r() as u32, color.g() as u32, color.b() as u32);
+                (r >> 3) << 10 | (g >> 3) << 5 | b >> 3
+            }
+        }
+    }
+
+    pub fn decode(self, pixel: u32) -> RGBA {
+        match self {
+            Self::Argb8888 => RGBA::from_pixel(pixel),
+            Self::Rgb565 => RGBA::rgb(
+                expand((pixel >> 11) & 0x1f, 5),
+                expand((pixel >> 5) & 0x3f, 6),
+                expand(pixel & 0x1f, 5),
+            ),
+            Self::Xrgb1555 => RGBA::rgb(
+                expand((pixel >> 10) & 0x1f, 5),
+                expand((pixel >> 5) & 0x1f, 5),
+                expand(pixel & 0x1f, 5),
+            ),
+        }
+    }
+}
+
+/// Scales a channel of `bits` bits to 8 bits, so that full intensity stays full
+fn expand(value: u32, bits: u32) -> u8 {
+    let value = value << (8 - bits);
+    (value | value >> bits) as u8
+}
+
+/// Rectangle in pixels. The right and bottom edges are exclusive.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Rect {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Rect {
+    pub const fn new(x: i32, y: i32, width: u32, height: u32) -> Self {
+        Self {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    pub fn right(&self) -> i32 {
+        self.x + self.width as i32
+    }
+
+    pub fn bottom(&self) -> i32 {
+        self.y + self.height as i32
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.width == 0 || self.height == 0
+    }
+
+    pub fn contains(&self, x: i32, y: i32) -> bool {
+        x >= self.x && x < self.right() && y >= self.y && y < self.bottom()
+    }
+
+    /// Area covered by both rectangles, empty if they don't overlap
+    pub fn intersect(&self, other: &Rect) -> Rect {
+        let x = self.x.max(other.x);
+        let y = self.y.max(other.y);
+        let right = self.right().min(other.right());
+        let bottom = self.bottom().min(other.bottom());
+
+        Rect::new(x, y, (right - x).max(0) as u32, (bottom - y).max(0) as u32)
+    }
+}
+
+/// Image in memory to blit from, row by row without padding
+pub struct Image<'a> {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: &'a [RGBA],
+}
+
+/// Image held in a pixel format, ready to be copied into a
+/// surface of the same format without converting each pixel
+pub struct Pixmap {
+    width: u32,
+    height: u32,
+    format: PixelFormat,
+    /// Held as words to keep pixels aligned
+    data: Vec<u32>,
+}
+
+impl Pixmap {
+    pub fn new(width: u32, height: u32, format: PixelFormat) -> Self {
+        let size = (width * height) as usize * format.bytes_per_pixel();
+        Self {
+            width,
+            height,
+            format,
+            data: alloc::vec![0; size.div_ceil(4)],
+        }
+    }
+
+    /// Surface for drawing into the pixmap
+    pub fn surface(&mut self) -> Surface<'_> {
+        let bytes = unsafe {
+            core::slice::from_raw_parts_mut(self.data.as_mut_ptr().cast(), self.data.len() * 4)
+        };
+        Surface::new(bytes, self.width, self.height, self.format)
+    }
+
+    fn row(&self, y: usize) -> &[u8] {
+        let bytes =
+            unsafe { core::slice::from_raw_parts(self.data.as_ptr().cast(), self.data.len() * 4) };
+        let pitch = self.width as usize * self.format.bytes_per_pixel();
+        &bytes[y * pitch..][..pitch]
+    }
+}
+
+/// Scales each channel of `color` by `level`, from 0 for black to 0xff
+fn dim(color: RGBA, level: u8) -> RGBA {
+    let scale = |c: u8| ((c as u32 * level as u32 + 127) / 255) as u8;
+    RGBA::rgba(
+        scale(color.r()),
+        scale(color.g()),
+        scale(color.b()),
+        color.a(),
+    )
+}
+
+/// Mixes `src` over `dst` by the source alpha
+fn blend(src: RGBA, dst: RGBA) -> RGBA {
+    let alpha = src.a() as u32;
+    let mix = |s: u8, d: u8| ((s as u32 * alpha + d as u32 * (255 - alpha) + 127) / 255) as u8;
+    RGBA::rgb(
+        mix(src.r(), dst.r()),
+        mix(src.g(), dst.g()),
+        mix(src.b(), dst.b()),
+    )
+}
+
+/// Size in pixels of `text` drawn by `Surface::draw_text`
+pub fn text_size(text: &str, font: &Font, scale: u32) -> (u32, u32) {
+    let columns = text.split('\n').map(|line| line.chars().count()).max();
+    let lines = text.split('\n').count() as u32;
+    (
+        columns.unwrap_or(0) as u32 * font.width() * scale,
+        lines * font.height() * scale,
+    )
+}
+
+/// Pixel buffer for the drawing primitives. Drawing only touches
+/// pixels inside the clipping rectangle, which starts out as the
+/// whole surface.
+pub struct Surface<'a> {
+    base: *mut u8,
+    width: u32,
+    height: u32,
+    /// Bytes from the start of one row to the next
+    pitch: usize,
+    format: PixelFormat,
+    clip: Rect,
+    _buffer: PhantomData<&'a mut [u8]>,
+}
+
+impl<'a> Surface<'a> {
+    /// Creates a surface over a buffer with rows of `width` pixels
+    pub fn new(buffer: &'a mut [u8], width: u32, height: u32, format: PixelFormat) -> Self {
+        let pitch = width as usize * format.bytes_per_pixel();
+        assert!(
+            buffer.len() >= pitch * height as usize,
+            "Surface buffer should hold every pixel"
+        );
+        assert!(
+            buffer.as_ptr() as usize % format.bytes_per_pixel() == 0,
+            "Surface buffer should be aligned for its pixels"
+        );
+
+        unsafe { Self::from_raw(buffer.as_mut_ptr(), width, height, pitch, format) }
+    }
+
+    /// Creates a surface over memory such as a framebuffer.
+    /// Safety: `base` must point to `height` rows of `pitch` bytes,
+    /// that nothing else accesses for the lifetime of the surface
+    pub unsafe fn from_raw(
+        base: *mut u8,
+        width: u32,
+        height: u32,
+        pitch: usize,
+        format: PixelFormat,
+    ) -> Self {
+        Self {
+            base,
+            width,
+            height,
+            pitch,
+            format,
+            clip: Rect::new(0, 0, width, height),
+            _buffer: PhantomData,
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn bounds(&self) -> Rect {
+        Rect::new(0, 0, self.width, self.height)
+    }
+
+    /// Limits drawing to the part of `rect` inside the surface
+    pub fn set_clip(&mut self, rect: Rect) {
+        self.clip = rect.intersect(&self.bounds());
+    }
+
+    pub fn reset_clip(&mut self) {
+        self.clip = self.bounds();
+    }
+
+    fn pixel_ptr(&self, x: i32, y: i32) -> *mut u8 {
+        let offset = y as usize * self.pitch + x as usize * self.format.bytes_per_pixel();
+        unsafe { self.base.add(offset) }
+    }
+
+    /// Safety: the pixel must be inside the surface
+    unsafe fn write(&mut self, x: i32, y: i32, pixel: u32) {
+        self.format.write(self.pixel_ptr(x, y), pixel);
+    }
+
+    /// Safety: the pixel must be inside the surface
+    unsafe fn read(&self, x: i32, y: i32) -> u32 {
+        self.format.read(self.pixel_ptr(x, y))
+    }
+
+    /// Fills the clipping rectangle
+    pub fn clear(&mut self, color: RGBA) {
+        self.fill_rect(self.clip, color);
+    }
+
+    pub fn fill_rect(&mut self, rect: Rect, color: RGBA) {
+        let rect = rect.intersect(&self.clip);
+        let pixel = self.format.encode(color);
+
+        for y in rect.y..rect.bottom() {
+            for x in rect.x..rect.right() {
+                unsafe { self.write(x, y, pixel) };
+            }
+        }
+    }
+
+    /// Draws the one pixel wide outline of `rect`
+    pub fn draw_rect(&mut self, rect: Rect, color: RGBA) {
+        if rect.is_empty() {
+            return;
+        }
+
+        let (right, bottom) = (rect.right() - 1, rect.bottom() - 1);
+        self.fill_rect(Rect::new(rect.x, rect.y, rect.width, 1), color);
+        self.fill_rect(Rect::new(rect.x, bottom, rect.width, 1), color);
+        self.fill_rect(Rect::new(rect.x, rect.y, 1, rect.height), color);
+        self.fill_rect(Rect::new(right, rect.y, 1, rect.height), color);
+    }
+
+    /// Draws a line including both end points
+    pub fn draw_line(&mut self, from: (i32, i32), to: (i32, i32), color: RGBA) {
+        let pixel = self.format.encode(color);
+        let (mut x, mut y) = from;
+        let dx = (to.0 - x).abs();
+        let dy = -(to.1 - y).abs();
+        let step_x = if x < to.0 { 1 } else { -1 };
+        let step_y = if y < to.1 { 1 } else { -1 };
+        let mut err = dx + dy;
+
+        // Bresenham's algorithm, clipping each pixel
+        loop {
+            if self.clip.contains(x, y) {
+                unsafe { self.write(x, y, pixel) };
+            }
+
+            if (x, y) == to {
+                break;
+            }
+
+            let err2 = 2 * err;
+            if err2 >= dy {
+                err += dy;
+                x += step_x;
+            }
+            if err2 <= dx {
+                err += dx;
+                y += step_y;
+            }
+        }
+    }
+
+    /// Copies `pixmap` with its top left corner at (`x`, `y`), with its
+    /// brightness scaled by `level`. At full brightness rows are copied as is.
+    pub fn draw_pixmap(&mut self, x: i32, y: i32, pixmap: &Pixmap, level: u8) {
+        assert_eq!(
+            pixmap.format, self.format,
+            "Pixmap should be in the surface's format"
+        );
+
+        let dest = Rect::new(x, y, pixmap.width, pixmap.height).intersect(&self.clip);
+        let bytes_pp = self.format.bytes_per_pixel();
+
+        for dy in dest.y..dest.bottom() {
+            let start = (dest.x - x) as usize * bytes_pp;
+            let src = &pixmap.row((dy - y) as usize)[start..][..dest.width as usize * bytes_pp];
+
+            if level == 0xff {
+                let dst = self.pixel_ptr(dest.x, dy);
+                unsafe { core::ptr::copy_nonoverlapping(src.as_ptr(), dst, src.len()) };
+                continue;
+            }
+
+            for (dx, px) in (dest.x..).zip(src.chunks_exact(bytes_pp)) {
+                let pixel = unsafe { self.format.read(px.as_ptr()) };
+                let color = dim(self.format.decode(pixel), level);
+                unsafe { self.write(dx, dy, self.format.encode(color)) };
+            }
+        }
+    }
+
+    /// Draws `image` with its top left corner at (`x`, `y`),
+    /// blending partially transparent pixels over the surface
+    pub fn blit(&mut self, x: i32, y: i32, image: &Image) {
+        let dest = Rect::new(x, y, image.width, image.height).intersect(&self.clip);
+
+        for dy in dest.y..dest.bottom() {
+            let row = (dy - y) as usize * image.width as usize;
+            for dx in dest.x..dest.right() {
+                let src = image.pixels[row + (dx - x) as usize];
+                let color = match src.a() {
+                    0 => continue,
+                    0xff => src,
+                    _ => blend(src, self.format.decode(unsafe { self.read(dx, dy) })),
+                };
+
+                unsafe { self.write(dx, dy, self.format.encode(color)) };
+            }
+        }
+    }
+
+    /// Draws `text` with its top left corner at (`x`, `y`), starting
+    /// each line below the previous one. Only the glyphs are drawn.
+    /// Returns the area the text covers.
+    pub fn draw_text(
+        &mut self,
+        x: i32,
+        y: i32,
+        text: &str,
+        font: &Font,
+        scale: u32,
+        color: RGBA,
+    ) -> Rect {
+        let pixel = self.format.encode(color);
+        let (glyph_width, glyph_height) = (font.width() * scale, font.height() * scale);
+
+        for (line_idx, line) in text.split('\n').enumerate() {
+            let top = y + (line_idx as u32 * glyph_height) as i32;
+            for (col, ch) in line.chars().enumerate() {
+                let left = x + (col as u32 * glyph_width) as i32;
+                let cell = Rect::new(left, top, glyph_width, glyph_height).intersect(&self.clip);
+                let glyph = font.glyph(ch);
+
+                for py in cell.y..cell.bottom() {
+                    for px in cell.x..cell.right() {
+                        let (gx, gy) = ((px - left) as u32 / scale, (py - top) as u32 / scale);
+                        if glyph.is_set(gx, gy) {
+                            unsafe { self.write(px, py, pixel) };
+                        }
+                    }
+                }
+            }
+        }
+
+        let (width, height) = text_size(text, font, scale);
+        Rect::new(x, y, width, height)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::font;
+    use crate::print::{COLOR_BLACK, COLOR_WHITE};
+    use alloc::vec::Vec;
+
+    const RED: RGBA = RGBA::rgb(0xff, 0, 0);
+
+    /// Color of a pixel, or None outside the surface
+    fn get_pixel(surface: &Surface, x: i32, y: i32) -> Option<RGBA> {
+        if !surface.bounds().contains(x, y) {
+            return None;
+        }
+
+        Some(surface.format.decode(unsafe { surface.read(x, y) }))
+    }
+
+    fn buffer(format: PixelFormat) -> Vec<u8> {
+        alloc::vec![0; 16 * 8 * format.bytes_per_pixel()]
+    }
+
+    /// Rows of the surface with set pixels as '#'
+    fn render(surface: &Surface) -> Vec<alloc::string::String> {
+        (0..surface.height() as i32)
+            .map(|y| {
+                (0..surface.width() as i32)
+                    .map(|x| match get_pixel(surface, x, y).unwrap() {
+                        c if c == COLOR_BLACK || c == RGBA::from_pixel(0) => '.',
+                        _ => '#',
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rgb565_round_trips_colors() {
+        let format = PixelFormat::Rgb565;
+        assert_eq!(format.encode(RED), 0xf800);
+        assert_eq!(format.encode(COLOR_WHITE), 0xffff);
+        assert_eq!(format.decode(0xffff), COLOR_WHITE);
+        assert_eq!(format.decode(0x07e0), RGBA::rgb(0, 0xff, 0));
+    }
+
+    #[test]
+    fn xrgb1555_round_trips_colors() {
+        let format = PixelFormat::Xrgb1555;
+        assert_eq!(format.encode(RED), 0x7c00);
+        assert_eq!(format.encode(COLOR_WHITE), 0x7fff);
+        assert_eq!(format.decode(0x7fff), COLOR_WHITE);
+        assert_eq!(format.decode(0x03e0), RGBA::rgb(0, 0xff, 0));
+        assert_eq!(
+            format.decode(format.encode(RGBA::rgb(0x84, 0x42, 0x21))),
+            RGBA::rgb(0x84, 0x42, 0x21)
+        );
+    }
+
+    #[test]
+    fn rectangles_are_clipped() {
+        for format in [
+            PixelFormat::Argb8888,
+            PixelFormat::Rgb565,
+            PixelFormat::Xrgb1555,
+        ] {
+            let mut buf = buffer(format);
+            let mut surface = Surface::new(&mut buf, 16, 8, format);
+
+            surface.set_clip(Rect::new(2, 1, 20, 3));
+            surface.fill_rect(Rect::new(-4, -4, 8, 8), RED);
+            surface.reset_clip();
+            surface.draw_rect(Rect::new(10, 2, 4, 4), RED);
+
+            let rows = render(&surface);
+            assert_eq!(rows[0], "................");
+            assert_eq!(rows[1], "..##............");
+            assert_eq!(rows[2], "..##......####..");
+            assert_eq!(rows[3], "..##......#..#..");
+            assert_eq!(rows[5], "..........####..");
+            assert_eq!(
+                get_pixel(&surface, 2, 1),
+                Some(format.decode(format.encode(RED)))
+            );
+        }
+    }
+
+    #[test]
+    fn lines_cover_both_end_points() {
+        let mut buf = buffer(PixelFormat::Argb8888);
+        let mut surface = Surface::new(&mut buf, 16, 8, PixelFormat::Argb8888);
+        surface.draw_line((0, 0), (3, 3), RED);
+        surface.draw_line((15, 7), (8, 7), RED);
+        surface.draw_line((20, -5), (30, -5), RED);
+
+        let rows = render(&surface);
+        assert_eq!(rows[0], "#...............");
+        assert_eq!(rows[3], "...#............");
+        assert_eq!(rows[7], "........########");
+    }
+
+    #[test]
+    fn blits_blend_by_alpha() {
+        let mut buf = buffer(PixelFormat::Argb8888);
+        let mut surface = Surface::new(&mut buf, 16, 8, PixelFormat::Argb8888);
+        surface.clear(COLOR_WHITE);
+
+        let pixels = [
+            RED,
+            RGBA::rgba(0, 0, 0, 0),
+            RGBA::rgba(0, 0, 0xff, 0x80),
+            RGBA::rgba(0, 0, 0, 0xff),
+        ];
+        let image = Image {
+            width: 2,
+            height: 2,
+            pixels: &pixels,
+        };
+        surface.blit(15, 7, &image);
+        surface.blit(0, 0, &image);
+
+        assert_eq!(get_pixel(&surface, 0, 0), Some(RED));
+        assert_eq!(get_pixel(&surface, 1, 0), Some(COLOR_WHITE));
+        assert_eq!(get_pixel(&surface, 0, 1), Some(RGBA::rgb(0x7f, 0x7f, 0xff)));
+        assert_eq!(get_pixel(&surface, 1, 1), Some(COLOR_BLACK));
+        assert_eq!(get_pixel(&surface, 15, 7), Some(RED));
+    }
+
+    #[test]
+    fn pixmaps_are_copied_and_dimmed() {
+        for format in [
+            PixelFormat::Argb8888,
+            PixelFormat::Rgb565,
+            PixelFormat::Xrgb1555,
+        ] {
+            let mut pixmap = Pixmap::new(3, 2, format);
+            pixmap.surface().clear(COLOR_WHITE);
+            pixmap.surface().fill_rect(Rect::new(0, 0, 1, 1), RED);
+
+            let mut buf = buffer(format);
+            let mut surface = Surface::new(&mut buf, 16, 8, format);
+            surface.draw_pixmap(14, 7, &pixmap, 0xff);
+            surface.draw_pixmap(1, 1, &pixmap, 0x80);
+
+            assert_eq!(
+                get_pixel(&surface, 14, 7),
+                Some(format.decode(format.encode(RED)))
+            );
+            assert_eq!(get_pixel(&surface, 15, 7), Some(COLOR_WHITE));
+            assert_eq!(get_pixel(&surface, 0, 1), Some(format.decode(0)));
+
+            let half = get_pixel(&surface, 3, 2).unwrap();
+            assert!((0x78..=0x88).contains(&half.g()), "{:?}", half);
+            assert_eq!(get_pixel(&surface, 1, 1).unwrap().g(), 0);
+        }
+    }
+
+    #[test]
+    fn text_is_drawn_at_any_position() {
+        let mut buf = alloc::vec![0; 40 * 80 * 2];
+        let mut surface = Surface::new(&mut buf, 40, 80, PixelFormat::Rgb565);
+        let font = &font::BUILTIN;
+
+        let area = surface.draw_text(-3, 5, "ab\nc", font, 2, COLOR_WHITE);
+        assert_eq!(area, Rect::new(-3, 5, 32, 64));
+        assert_eq!(text_size("ab\nc", font, 2), (32, 64));
+
+        // Nothing lands outside the text, something on each line
+        let rows = render(&surface);
+        assert!(rows[..5].iter().all(|row| !row.contains('#')));
+        assert!(rows[5..37].iter().any(|row| row.contains('#')));
+        assert!(rows[37..69].iter().any(|row| row.contains('#')));
+        assert!(rows[69..].iter().all(|row| !row.contains('#')));
+    }
+}
@@ -0,0 +1,404 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::fmt;
+use core::mem::{align_of, size_of};
+use core::ptr;
+
+use crate::cpu::mmu;
+use crate::cpu::pagefault;
+use crate::sync::SpinLock;
+
+/// Reserved virtual range the heap grows into, above `map_anywhere`'s range.
+/// It's a demand-zero region, so pages are only backed once touched.
+pub const HEAP_START: u32 = 0xE000_1000;
+pub const HEAP_MAX: u32 = 0x0800_0000 - 0x1000;
+
+/// Unmapped page below the heap, catching underflows
+const HEAP_GUARD: u32 = HEAP_START - 0x1000;
+
+const INITIAL_SIZE: u32 = 0x4_0000;
+const GROW_SIZE: u32 = 0x1_0000;
+
+/// Free memory is kept as an address-ordered list of blocks, with the
+/// list node stored in the free memory itself. Every allocation is rounded
+/// up to whole nodes so that a freed block can always hold one.
+struct FreeBlock {
+    size: usize,
+    next: *mut FreeBlock,
+}
+
+const UNIT: usize = size_of::<FreeBlock>();
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Stats {
+    /// Bytes of the reserved range handed to the free list
+    pub size: usize,
+    /// Bytes handed out, including rounding
+    pub used: usize,
+    pub peak: usize,
+    pub allocations: usize,
+    pub failures: usize,
+}
+
+struct Heap {
+    head: *mut FreeBlock,
+    top: usize,
+    limit: usize,
+    stats: Stats,
+    last_failure: Option<Layout>,
+}
+
+// Free list nodes are only reached through the lock
+unsafe impl Send for Heap {}
+
+static HEAP: SpinLock<Heap> = SpinLock::new(Heap::empty());
+
+impl Heap {
+    const fn empty() -> Self {
+        Self {
+            head: ptr::null_mut(),
+            top: 0,
+            limit: 0,
+            stats: Stats {
+                size: 0,
+                used: 0,
+                peak: 0,
+                allocations: 0,
+                failures: 0,
+            },
+            last_failure: None,
+        }
+    }
+
+    /// Creates a heap managing the virtual range `start..limit`,
+    /// none of which is backed yet
+    const fn new(start: usize, limit: usize) -> Self {
+        let mut heap = Self::empty();
+        heap.top = start;
+        heap.limit = limit;
+        heap
+    }
+
+    fn stats(&self) -> Stats {
+        self.stats
+    }
+
+    fn normalize(layout: Layout) -> (usize, usize) {
+        let size = layout.size().max(UNIT).next_multiple_of(UNIT);
+        let align = layout.align().max(align_of::<FreeBlock>()).max(UNIT);
+        (size, align)
+    }
+
+    /// Adds the memory at `addr` to the free list, merging it with
+    /// neighbouring blocks
+    unsafe fn insert_free(&mut self, addr: usize, size: usize) {
+        let mut prev: *mut FreeBlock = ptr::null_mut();
+        let mut next = self.head;
+        while !next.is_null() && (next as usize) < addr {
+            prev = next;
+            next = (*next).next;
+        }
+
+        let block = addr as *mut FreeBlock;
+        block.write(FreeBlock { size, next });
+
+        if !next.is_null() && addr + size == next as usize {
+            (*block).size += (*next).size;
+            (*block).next = (*next).next;
+        }
+
+        if prev.is_null() {
+            self.head = block;
+        } else if prev as usize + (*prev).size == addr {
+            (*prev).size += (*block).size;
+            (*prev).next = (*block).next;
+        } else {
+            (*prev).next = block;
+        }
+    }
+
+    /// First-fit search of the free list
+    unsafe fn take_free(&mut self, size: usize, align: usize) -> Option<usize> {
+        let mut prev: *mut FreeBlock = ptr::null_mut();
+        let mut cur = self.head;
+
+        while !cur.is_null() {
+            let start = cur as usize;
+            let end = start + (*cur).size;
+            let alloc_start = start.next_multiple_of(align);
+
+            if alloc_start + size <= end {
+                let next = (*cur).next;
+                if prev.is_null() {
+                    self.head = next;
+                } else {
+                    (*prev).next = next;
+                }
+
+                // Padding and remainders are whole units, so they
+                // can always go back on the list
+                if alloc_start > start {
+                    self.insert_free(start, alloc_start - start);
+                }
+                if alloc_start + size < end {
+                    self.insert_free(alloc_start + size, end - alloc_start - size);
+                }
+
+                return Some(alloc_start);
+            }
+
+            prev = cur;
+            cur = (*cur).next;
+        }
+
+        None
+    }
+
+    /// Hands the memory at `top` to the free list and moves `top` up.
+    /// `top..top + size` must be accessible, or fault in on first touch.
+    unsafe fn extend(&mut self, size: usize) {
+        let top = self.top;
+        self.top += size;
+        self.stats.size += size;
+        self.insert_free(top, size);
+    }
+
+    /// Takes more of the reserved range. Frames are only allocated when
+    /// the new pages fault in, so running out of RAM is fatal there.
+    unsafe fn grow(&mut self, min_size: usize) -> bool {
+        let size = (min_size as u32)
+            .max(GROW_SIZE)
+            .next_multiple_of(mmu::PAGE_SIZE);
+        if self.top + size as usize > self.limit {
+            return false;
+        }
+
+        self.extend(size as usize);
+        true
+    }
+
+    unsafe fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        let (size, align) = Self::normalize(layout);
+
+        let addr = match self.take_free(size, align) {
+            Some(addr) => Some(addr),
+            // Worst case alignment padding is taken from the new memory
+            None if self.grow(size + align) => self.take_free(size, align),
+            None => None,
+        };
+
+        match addr {
+            Some(addr) => {
+                self.stats.used += size;
+                self.stats.peak = self.stats.peak.max(self.stats.used);
+                self.stats.allocations += 1;
+                addr as *mut u8
+            }
+            None => {
+                self.stats.failures += 1;
+                self.last_failure = Some(layout);
+                ptr::null_mut()
+            }
+        }
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        let (size, _) = Self::normalize(layout);
+
+        self.insert_free(ptr as usize, size);
+        self.stats.used -= size;
+        self.stats.allocations -= 1;
+    }
+}
+
+/// Reserves the heap as a demand-zero region with a guard page below it.
+/// Safety: the kernel mapping must be set up
+pub unsafe fn init() {
+    pagefault::register(
+        HEAP_GUARD,
+        0x1000,
+        pagefault::RegionKind::Guard("Kernel heap underflow"),
+    )
+    .expect("Heap guard region should register");
+    pagefault::register(HEAP_START, HEAP_MAX, pagefault::RegionKind::DemandZero)
+        .expect("Heap region should register");
+
+    let mut heap = HEAP.lock();
+    *heap = Heap::new(HEAP_START as usize, (HEAP_START + HEAP_MAX) as usize);
+    heap.grow(INITIAL_SIZE as usize);
+}
+
+pub fn stats() -> Stats {
+    HEAP.lock().stats()
+}
+
+/// Prints the heap statistics if an allocation has failed.
+/// Safety: reads the heap without locking, for the panic screen only
+pub unsafe fn dump_failure(out: &mut impl fmt::Write) -> fmt::Result {
+    let heap = &*HEAP.steal();
+    let layout = match heap.last_failure {
+        Some(layout) => layout,
+        None => return Ok(()),
+    };
+
+    writeln!(
+        out,
+        "\nHeap allocation failed, size {:x} align {:x}",
+        layout.size(),
+        layout.align()
+    )?;
+
+    let stats = heap.stats();
+    let rows = [
+        ("Heap size", stats.size),
+        ("Heap used", stats.used),
+        ("Heap peak", stats.peak),
+        ("Live allocs", stats.allocations),
+        ("Failures", stats.failures),
+    ];
+    for (name, val) in rows {
+        writeln!(out, "{:<11} {:x}", name, val)?;
+    }
+
+    Ok(())
+}
+
+pub struct KernelHeap;
+
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        HEAP.lock().alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        HEAP.lock().dealloc(ptr, layout)
+    }
+}
+
+#[cfg(not(test))]
+#[global_allocator]
+static GLOBAL: KernelHeap = KernelHeap;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAGE: usize = mmu::PAGE_SIZE as usize;
+
+    /// A heap over `size` bytes of host memory, page aligned so that
+    /// alignment padding is predictable. Returns the backing memory,
+    /// which must outlive the heap, and the heap's start.
+    fn new_heap(size: usize) -> (Vec<u8>, Heap, usize) {
+        let mem = alloc::vec![0; size + PAGE];
+        let start = (mem.as_ptr() as usize).next_multiple_of(PAGE);
+        (mem, Heap::new(start, start + size), start)
+    }
+
+    fn layout(size: usize, align: usize) -> Layout {
+        Layout::from_size_align(size, align).unwrap()
+    }
+
+    /// Offsets and sizes of the free blocks, in list order
+    fn free_blocks(heap: &Heap, start: usize) -> Vec<(usize, usize)> {
+        let mut blocks = Vec::new();
+        let mut cur = heap.head;
+        while !cur.is_null() {
+            unsafe {
+                blocks.push((cur as usize - start, (*cur).size));
+                cur = (*cur).next;
+            }
+        }
+        blocks
+    }
+
+    #[test]
+    fn allocations_split_free_blocks() {
+        let (_mem, mut heap, start) = new_heap(PAGE);
+        unsafe {
+            heap.extend(PAGE);
+
+            // Rounded up to whole units
+            let a = heap.alloc(layout(20, 4));
+            assert_eq!(a as usize, start);
+            assert_eq!(free_blocks(&heap, start), [(24, PAGE - 24)]);
+            assert_eq!(heap.stats().used, 24);
+
+            let b = heap.alloc(layout(8, 8));
+            assert_eq!(b as usize, start + 24);
+            assert_eq!(free_blocks(&heap, start), [(32, PAGE - 32)]);
+        }
+    }
+
+    #[test]
+    fn first_fit_takes_the_lowest_hole() {
+        let (_mem, mut heap, start) = new_heap(PAGE);
+        unsafe {
+            heap.extend(PAGE);
+            let blocks: Vec<_> = (0..4).map(|_| heap.alloc(layout(64, 8))).collect();
+            heap.dealloc(blocks[0], layout(64, 8));
+            heap.dealloc(blocks[2], layout(64, 8));
+
+            // Both holes fit, the first one is split
+            let small = heap.alloc(layout(32, 8));
+            assert_eq!(small, blocks[0]);
+            assert_eq!(
+                free_blocks(&heap, start),
+                [(32, 32), (128, 64), (256, PAGE - 256)]
+            );
+
+            // Too big for what's left of the first hole
+            let large = heap.alloc(layout(64, 8));
+            assert_eq!(large, blocks[2]);
+        }
+    }
+
+    #[test]
+    fn adjacent_frees_merge() {
+        let (_mem, mut heap, start) = new_heap(PAGE);
+        unsafe {
+            heap.extend(PAGE);
+            let blocks: Vec<_> = (0..3).map(|_| heap.alloc(layout(64, 8))).collect();
+
+            heap.dealloc(blocks[0], layout(64, 8));
+            heap.dealloc(blocks[2], layout(64, 8));
+            assert_eq!(free_blocks(&heap, start), [(0, 64), (128, PAGE - 128)]);
+
+            // Merges with the blocks on both sides
+            heap.dealloc(blocks[1], layout(64, 8));
+            assert_eq!(free_blocks(&heap, start), [(0, PAGE)]);
+            assert_eq!(heap.stats().used, 0);
+
+            assert_eq!(heap.alloc(layout(PAGE, 8)) as usize, start);
+        }
+    }
+
+    #[test]
+    fn alignment_padding_stays_free() {
+        let (_mem, mut heap, start) = new_heap(PAGE);
+        unsafe {
+            heap.extend(PAGE);
+            heap.alloc(layout(8, 8));
+
+            let aligned = heap.alloc(layout(16, 256));
+            assert_eq!(aligned as usize, start + 256);
+            assert_eq!(free_blocks(&heap, start), [(8, 248), (272, PAGE - 272)]);
+
+            // The padding is handed out again
+            assert_eq!(heap.alloc(layout(8, 8)) as usize, start + 8);
+        }
+    }
+
+    #[test]
+    fn grows_within_the_reserved_range() {
+        let size = GROW_SIZE as usize;
+        let (_mem, mut heap, start) = new_heap(size);
+        unsafe {
+            assert_eq!(heap.alloc(layout(PAGE, 8)) as usize, start);
+            assert_eq!(heap.stats().size, size);
+
+            // Past the end of the reserved range
+            assert!(heap.alloc(layout(size, 8)).is_null());
+            assert_eq!(heap.stats().failures, 1);
+            assert_eq!(heap.last_failure, Some(layout(size, 8)));
+        }
+    }
+}
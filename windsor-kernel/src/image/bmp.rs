@@ -0,0 +1,182 @@
+use alloc::vec::Vec;
+
+use super::{check_size, Bitmap};
+use crate::print::RGBA;
+
+pub const MAGIC: &[u8] = b"BM";
+
+/// File header followed by the BITMAPINFOHEADER fields all later headers share
+const HEADER_SIZE: usize = 54;
+/// BITMAPV4HEADER and later have an alpha mask after the color masks
+const V4_HEADER_SIZE: u32 = 108;
+
+const BI_RGB: u32 = 0;
+const BI_BITFIELDS: u32 = 3;
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+/// Extracts the channel selected by `mask` from a pixel, scaled to 8 bits
+fn channel(pixel: u32, mask: u32) -> u8 {
+    if mask == 0 {
+        return 0;
+    }
+
+    let bits = mask.count_ones();
+    let value = (pixel & mask) >> mask.trailing_zeros();
+    if bits >= 8 {
+        (value >> (bits - 8)) as u8
+    } else {
+        (value * 0xff / ((1 << bits) - 1)) as u8
+    }
+}
+
+/// Decodes an uncompressed 24 or 32 bits per pixel BMP. 32 bit images
+/// are only given an alpha channel if the header has a mask for it.
+pub fn decode(data: &[u8]) -> Result<Bitmap, ()> {
+    if !data.starts_with(MAGIC) || data.len() < HEADER_SIZE {
+        return Err(());
+    }
+
+    let pixels_offset = read_u32(data, 10) as usize;
+    let header_size = read_u32(data, 14);
+    let width = read_u32(data, 18) as i32;
+    let height = read_u32(data, 22) as i32;
+    let bpp = read_u16(data, 28);
+    let compression = read_u32(data, 30);
+
+    // Bottom-up unless the height is negative
+    let top_down = height < 0;
+    let (width, height) = (width.unsigned_abs(), height.unsigned_abs());
+    check_size(width, height)?;
+
+    let masks = match (bpp, compression) {
+        (24, BI_RGB) | (32, BI_RGB) => [0xff_0000, 0xff00, 0xff, 0],
+        (32, BI_BITFIELDS) => {
+            // The masks follow a BITMAPINFOHEADER, or are part of later headers
+            let mask = |idx: usize| {
+                let offset = HEADER_SIZE + idx * 4;
+                data.get(offset..offset + 4).map(|d| read_u32(d, 0))
+            };
+            let alpha = if header_size >= V4_HEADER_SIZE {
+                mask(3).ok_or(())?
+            } else {
+                0
+            };
+            [
+                mask(0).ok_or(())?,
+                mask(1).ok_or(())?,
+                mask(2).ok_or(())?,
+                alpha,
+            ]
+        }
+        _ => return Err(()),
+    };
+
+    let bytes_pp = bpp as usize / 8;
+    let stride = (width as usize * bytes_pp).next_multiple_of(4);
+    let end = pixels_offset
+        .checked_add(stride * height as usize)
+        .ok_or(())?;
+    let rows = data.get(pixels_offset..end).ok_or(())?;
+
+    let mut pixels = Vec::with_capacity((width * height) as usize);
+    for y in 0..height as usize {
+        let row = if top_down { y } else { height as usize - 1 - y };
+        let row = &rows[row * stride..][..width as usize * bytes_pp];
+
+        pixels.extend(row.chunks_exact(bytes_pp).map(|px| {
+            let pixel = match px {
+                [b, g, r] => u32::from_le_bytes([*b, *g, *r, 0]),
+                _ => read_u32(px, 0),
+            };
+
+            let alpha = if masks[3] == 0 {
+                0xff
+            } else {
+                channel(pixel, masks[3])
+            };
+            RGBA::rgba(
+                channel(pixel, masks[0]),
+                channel(pixel, masks[1]),
+                channel(pixel, masks[2]),
+                alpha,
+            )
+        }));
+    }
+
+    Ok(Bitmap {
+        width,
+        height,
+        pixels,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bmp(width: i32, height: i32, bpp: u16, compression: u32, extra: &[u8]) -> Vec<u8> {
+        let mut data = Vec::from(MAGIC);
+        data.extend_from_slice(&[0; 8]);
+        data.extend_from_slice(&(HEADER_SIZE as u32 + extra.len() as u32).to_le_bytes());
+        data.extend_from_slice(&40u32.to_le_bytes());
+        data.extend_from_slice(&width.to_le_bytes());
+        data.extend_from_slice(&height.to_le_bytes());
+        data.extend_from_slice(&1u16.to_le_bytes());
+        data.extend_from_slice(&bpp.to_le_bytes());
+        data.extend_from_slice(&compression.to_le_bytes());
+        data.extend_from_slice(&[0; 20]);
+        data.extend_from_slice(extra);
+        data
+    }
+
+    #[test]
+    fn decodes_padded_bottom_up_rows() {
+        let mut data = bmp(2, 2, 24, BI_RGB, &[]);
+        // Bottom row first, each padded to 8 bytes
+        data.extend_from_slice(&[3, 2, 1, 6, 5, 4, 0, 0]);
+        data.extend_from_slice(&[0, 0, 0xff, 0xff, 0, 0, 0, 0]);
+
+        let bitmap = decode(&data).unwrap();
+        assert_eq!(
+            bitmap.pixels,
+            [
+                RGBA::rgb(0xff, 0, 0),
+                RGBA::rgb(0, 0, 0xff),
+                RGBA::rgb(1, 2, 3),
+                RGBA::rgb(4, 5, 6),
+            ]
+        );
+    }
+
+    #[test]
+    fn decodes_top_down_bitfields() {
+        // A BITMAPINFOHEADER with RGB565-style masks in 32 bit pixels
+        let mut masks = Vec::new();
+        for mask in [0xf800u32, 0x07e0, 0x001f] {
+            masks.extend_from_slice(&mask.to_le_bytes());
+        }
+        let mut data = bmp(1, -2, 32, BI_BITFIELDS, &masks);
+        data.extend_from_slice(&0xf800u32.to_le_bytes());
+        data.extend_from_slice(&0x07ffu32.to_le_bytes());
+
+        let bitmap = decode(&data).unwrap();
+        assert_eq!(
+            bitmap.pixels,
+            [RGBA::rgb(0xff, 0, 0), RGBA::rgb(0, 0xff, 0xff)]
+        );
+    }
+
+    #[test]
+    fn rejects_other_formats_and_truncated_data() {
+        assert!(decode(&bmp(2, 2, 8, BI_RGB, &[])).is_err());
+        assert!(decode(&bmp(2, 2, 24, 1, &[])).is_err());
+        assert!(decode(&bmp(2, 2, 24, BI_RGB, &[0; 12])).is_err());
+    }
+}
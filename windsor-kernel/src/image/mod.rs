@@ -0,0 +1,103 @@
+use alloc::vec::Vec;
+
+use crate::gfx;
+use crate::print::RGBA;
+
+pub mod bmp;
+pub mod png;
+pub mod qoi;
+
+/// Largest width or height accepted from an image file, which
+/// keeps a corrupt header from asking for an enormous allocation
+pub const MAX_DIMENSION: u32 = 2048;
+
+/// Decoded image, row by row without padding
+pub struct Bitmap {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<RGBA>,
+}
+
+impl Bitmap {
+    pub fn image(&self) -> gfx::Image<'_> {
+        gfx::Image {
+            width: self.width,
+            height: self.height,
+            pixels: &self.pixels,
+        }
+    }
+
+    /// Resizes the image with nearest neighbour sampling
+    pub fn scaled(&self, width: u32, height: u32) -> Bitmap {
+        let mut pixels = Vec::with_capacity((width * height) as usize);
+        for y in 0..height {
+            let src_y = (y as u64 * self.height as u64 / height as u64) as usize;
+            let row = &self.pixels[src_y * self.width as usize..][..self.width as usize];
+            for x in 0..width {
+                pixels.push(row[(x as u64 * self.width as u64 / width as u64) as usize]);
+            }
+        }
+
+        Bitmap {
+            width,
+            height,
+            pixels,
+        }
+    }
+}
+
+/// Checks the dimensions from an image header
+fn check_size(width: u32, height: u32) -> Result<(), ()> {
+    if width == 0 || height == 0 || width > MAX_DIMENSION || height > MAX_DIMENSION {
+        return Err(());
+    }
+
+    Ok(())
+}
+
+fn read_u32_be(data: &[u8], offset: usize) -> Result<u32, ()> {
+    let bytes = data.get(offset..offset + 4).ok_or(())?;
+    Ok(u32::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+/// Decodes a QOI, PNG or BMP file, telling them apart by their magic
+pub fn decode(data: &[u8]) -> Result<Bitmap, ()> {
+    if data.starts_with(qoi::MAGIC) {
+        qoi::decode(data)
+    } else if data.starts_with(png::SIGNATURE) {
+        png::decode(data)
+    } else if data.starts_with(bmp::MAGIC) {
+        bmp::decode(data)
+    } else {
+        Err(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scaling_samples_nearest_pixel() {
+        let [a, b, c, d] = [1, 2, 3, 4].map(|v| RGBA::rgb(v, v, v));
+        let bitmap = Bitmap {
+            width: 2,
+            height: 2,
+            pixels: alloc::vec![a, b, c, d],
+        };
+
+        let up = bitmap.scaled(4, 3);
+        assert_eq!(up.pixels[..4], [a, a, b, b]);
+        assert_eq!(up.pixels[4..8], [a, a, b, b]);
+        assert_eq!(up.pixels[8..], [c, c, d, d]);
+
+        let down = bitmap.scaled(1, 1);
+        assert_eq!(down.pixels, [a]);
+    }
+
+    #[test]
+    fn decode_rejects_unknown_formats() {
+        assert!(decode(b"GIF89a").is_err());
+        assert!(decode(&[]).is_err());
+    }
+}
@@ -0,0 +1,340 @@
+use alloc::vec::Vec;
+
+use super::{check_size, read_u32_be, Bitmap};
+use crate::print::RGBA;
+
+pub const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+const COLOR_GRAY: u8 = 0;
+const COLOR_RGB: u8 = 2;
+const COLOR_PALETTE: u8 = 3;
+const COLOR_GRAY_ALPHA: u8 = 4;
+const COLOR_RGBA: u8 = 6;
+
+const FILTER_NONE: u8 = 0;
+const FILTER_SUB: u8 = 1;
+const FILTER_UP: u8 = 2;
+const FILTER_AVERAGE: u8 = 3;
+const FILTER_PAETH: u8 = 4;
+
+struct Header {
+    width: u32,
+    height: u32,
+    bit_depth: u8,
+    color_type: u8,
+}
+
+impl Header {
+    fn channels(&self) -> usize {
+        match self.color_type {
+            COLOR_RGB => 3,
+            COLOR_GRAY_ALPHA => 2,
+            COLOR_RGBA => 4,
+            _ => 1,
+        }
+    }
+
+    fn bits_per_pixel(&self) -> usize {
+        self.channels() * self.bit_depth as usize
+    }
+
+    fn stride(&self) -> usize {
+        (self.width as usize * self.bits_per_pixel()).div_ceil(8)
+    }
+}
+
+/// Iterates over the type and data of each chunk up to IEND. The CRCs
+/// aren't checked, the zlib stream has its own checksum.
+fn chunks(data: &[u8]) -> impl Iterator<Item = Result<(&[u8], &[u8]), ()>> {
+    let mut offset = SIGNATURE.len();
+    core::iter::from_fn(move || {
+        let len = match read_u32_be(data, offset) {
+            Ok(len) => len as usize,
+            Err(()) => return Some(Err(())),
+        };
+
+        let kind = data.get(offset + 4..offset + 8)?;
+        let start = offset + 8;
+        let Some(body) = start.checked_add(len).and_then(|end| data.get(start..end)) else {
+            return Some(Err(()));
+        };
+        offset = start + len + 4;
+
+        match kind {
+            b"IEND" => None,
+            _ => Some(Ok((kind, body))),
+        }
+    })
+}
+
+fn parse_header(body: &[u8]) -> Result<Header, ()> {
+    if body.len() != 13 {
+        return Err(());
+    }
+
+    let header = Header {
+        width: read_u32_be(body, 0)?,
+        height: read_u32_be(body, 4)?,
+        bit_depth: body[8],
+        color_type: body[9],
+    };
+    check_size(header.width, header.height)?;
+
+    let depth_ok = match header.color_type {
+        COLOR_GRAY => matches!(header.bit_depth, 1 | 2 | 4 | 8 | 16),
+        COLOR_PALETTE => matches!(header.bit_depth, 1 | 2 | 4 | 8),
+        COLOR_RGB | COLOR_GRAY_ALPHA | COLOR_RGBA => matches!(header.bit_depth, 8 | 16),
+        _ => false,
+    };
+
+    // Compression and filter method 0 are the only ones defined
+    let (compression, filter, interlace) = (body[10], body[11], body[12]);
+    if !depth_ok || compression != 0 || filter != 0 || interlace != 0 {
+        return Err(());
+    }
+
+    Ok(header)
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let (pa, pb, pc) = (
+        (p - a as i16).abs(),
+        (p - b as i16).abs(),
+        (p - c as i16).abs(),
+    );
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+/// Reverses the filter on each scanline in place, leaving the
+/// filter type bytes in front of the rows as they were
+fn unfilter(data: &mut [u8], header: &Header) -> Result<(), ()> {
+    let stride = header.stride();
+    // Filters work on bytes, a whole pixel back or one byte for sub-byte depths
+    let bpp = (header.bits_per_pixel() / 8).max(1);
+
+    for y in 0..header.height as usize {
+        let (prev, rest) = data.split_at_mut(y * (stride + 1));
+        let prev = prev
+            .get(prev.len().saturating_sub(stride)..)
+            .filter(|_| y > 0);
+        let (filter, row) = rest[..stride + 1].split_first_mut().unwrap();
+
+        for x in 0..stride {
+            let a = if x >= bpp { row[x - bpp] } else { 0 };
+            let b = prev.map_or(0, |prev| prev[x]);
+            let c = if x >= bpp {
+                prev.map_or(0, |prev| prev[x - bpp])
+            } else {
+                0
+            };
+
+            row[x] = row[x].wrapping_add(match *filter {
+                FILTER_NONE => 0,
+                FILTER_SUB => a,
+                FILTER_UP => b,
+                FILTER_AVERAGE => ((a as u16 + b as u16) / 2) as u8,
+                FILTER_PAETH => paeth(a, b, c),
+                _ => return Err(()),
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Sample `idx` of a scanline, scaled to 8 bits unless it's a palette index
+fn sample(row: &[u8], idx: usize, header: &Header) -> u8 {
+    match header.bit_depth {
+        8 => row[idx],
+        // Only the high byte matters for an 8 bit framebuffer
+        16 => row[idx * 2],
+        depth => {
+            let bit = idx * depth as usize;
+            let max = (1u8 << depth) - 1;
+            let value = (row[bit / 8] >> (8 - depth as usize - bit % 8)) & max;
+            if header.color_type == COLOR_PALETTE {
+                value
+            } else {
+                (value as u16 * 0xff / max as u16) as u8
+            }
+        }
+    }
+}
+
+/// Decodes a non-interlaced PNG of any color type. Palette images use
+/// their tRNS chunk for alpha; other transparency chunks are ignored.
+pub fn decode(data: &[u8]) -> Result<Bitmap, ()> {
+    if !data.starts_with(SIGNATURE) {
+        return Err(());
+    }
+
+    let mut header = None;
+    let mut palette = Vec::new();
+    let mut zlib = Vec::new();
+
+    for chunk in chunks(data) {
+        match chunk? {
+            (b"IHDR", body) => header = Some(parse_header(body)?),
+            (b"PLTE", body) => {
+                palette = body
+                    .chunks_exact(3)
+                    .map(|rgb| RGBA::rgb(rgb[0], rgb[1], rgb[2]))
+                    .collect();
+            }
+            (b"tRNS", body) => {
+                for (color, alpha) in palette.iter_mut().zip(body) {
+                    *color = RGBA::rgba(color.r(), color.g(), color.b(), *alpha);
+                }
+            }
+            (b"IDAT", body) => zlib.extend_from_slice(body),
+            _ => {}
+        }
+    }
+
+    let header = header.ok_or(())?;
+    let stride = header.stride();
+    let size = (stride + 1) * header.height as usize;
+    let mut scanlines =
+        miniz_oxide::inflate::decompress_to_vec_zlib_with_limit(&zlib, size).map_err(|_| ())?;
+    if scanlines.len() != size {
+        return Err(());
+    }
+    drop(zlib);
+
+    unfilter(&mut scanlines, &header)?;
+
+    let (width, height) = (header.width as usize, header.height as usize);
+    let channels = header.channels();
+    let mut pixels = Vec::with_capacity(width * height);
+
+    for row in scanlines.chunks_exact(stride + 1) {
+        let row = &row[1..];
+        for x in 0..width {
+            let s = |channel| sample(row, x * channels + channel, &header);
+            pixels.push(match header.color_type {
+                COLOR_GRAY => RGBA::rgb(s(0), s(0), s(0)),
+                COLOR_GRAY_ALPHA => RGBA::rgba(s(0), s(0), s(0), s(1)),
+                COLOR_RGB => RGBA::rgb(s(0), s(1), s(2)),
+                COLOR_RGBA => RGBA::rgba(s(0), s(1), s(2), s(3)),
+                _ => *palette.get(s(0) as usize).ok_or(())?,
+            });
+        }
+    }
+
+    Ok(Bitmap {
+        width: header.width,
+        height: header.height,
+        pixels,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(png: &mut Vec<u8>, kind: &[u8], body: &[u8]) {
+        png.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        png.extend_from_slice(kind);
+        png.extend_from_slice(body);
+        png.extend_from_slice(&[0; 4]);
+    }
+
+    fn png(
+        width: u32,
+        height: u32,
+        depth: u8,
+        color: u8,
+        extra: &[(&[u8], &[u8])],
+        scanlines: &[u8],
+    ) -> Vec<u8> {
+        let mut ihdr = Vec::new();
+        ihdr.extend_from_slice(&width.to_be_bytes());
+        ihdr.extend_from_slice(&height.to_be_bytes());
+        ihdr.extend_from_slice(&[depth, color, 0, 0, 0]);
+
+        let mut data = Vec::from(SIGNATURE);
+        chunk(&mut data, b"IHDR", &ihdr);
+        for (kind, body) in extra {
+            chunk(&mut data, kind, body);
+        }
+
+        // Split the stream to check IDAT chunks are joined
+        let zlib = miniz_oxide::deflate::compress_to_vec_zlib(scanlines, 6);
+        let (first, second) = zlib.split_at(zlib.len() / 2);
+        chunk(&mut data, b"IDAT", first);
+        chunk(&mut data, b"IDAT", second);
+        chunk(&mut data, b"IEND", &[]);
+        data
+    }
+
+    #[test]
+    fn decodes_filtered_rgba() {
+        #[rustfmt::skip]
+        let scanlines = [
+            FILTER_NONE, 10, 20, 30, 255, 40, 50, 60, 128,
+            FILTER_SUB, 1, 2, 3, 255, 1, 1, 1, 0,
+            FILTER_UP, 5, 5, 5, 0, 0, 0, 0, 0,
+            FILTER_AVERAGE, 2, 2, 2, 128, 10, 10, 10, 0,
+            FILTER_PAETH, 0, 0, 0, 0, 1, 1, 1, 1,
+        ];
+        let data = png(2, 5, 8, COLOR_RGBA, &[], &scanlines);
+        let bitmap = decode(&data).unwrap();
+
+        assert_eq!(bitmap.pixels[0], RGBA::rgba(10, 20, 30, 255));
+        assert_eq!(bitmap.pixels[1], RGBA::rgba(40, 50, 60, 128));
+        assert_eq!(bitmap.pixels[2], RGBA::rgba(1, 2, 3, 255));
+        assert_eq!(bitmap.pixels[3], RGBA::rgba(2, 3, 4, 255));
+        assert_eq!(bitmap.pixels[4], RGBA::rgba(6, 7, 8, 255));
+        assert_eq!(bitmap.pixels[5], RGBA::rgba(2, 3, 4, 255));
+        // Average of the pixel to the left and the one above
+        assert_eq!(bitmap.pixels[6], RGBA::rgba(5, 5, 6, 255));
+        assert_eq!(bitmap.pixels[7], RGBA::rgba(13, 14, 15, 255));
+        // Paeth predicts from the pixel above in both cases here
+        assert_eq!(bitmap.pixels[8], RGBA::rgba(5, 5, 6, 255));
+        assert_eq!(bitmap.pixels[9], RGBA::rgba(14, 15, 16, 0));
+    }
+
+    #[test]
+    fn decodes_packed_palette_with_transparency() {
+        let palette = [0, 0, 0, 255, 0, 0, 0, 255, 0];
+        let trns = [0, 0x80];
+        let scanlines = [FILTER_NONE, 0b00_01_10_01, FILTER_NONE, 0b10_00_00_00];
+        let extra: [(&[u8], &[u8]); 2] = [(b"PLTE", &palette), (b"tRNS", &trns)];
+        let data = png(4, 2, 2, COLOR_PALETTE, &extra, &scanlines);
+        let bitmap = decode(&data).unwrap();
+
+        let red = RGBA::rgba(255, 0, 0, 0x80);
+        assert_eq!(
+            bitmap.pixels[..5],
+            [
+                RGBA::rgba(0, 0, 0, 0),
+                red,
+                RGBA::rgb(0, 255, 0),
+                red,
+                RGBA::rgb(0, 255, 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn decodes_gray_and_rejects_bad_data() {
+        let data = png(2, 1, 4, COLOR_GRAY, &[], &[FILTER_NONE, 0xf5]);
+        let bitmap = decode(&data).unwrap();
+        assert_eq!(
+            bitmap.pixels,
+            [RGBA::rgb(0xff, 0xff, 0xff), RGBA::rgb(0x55, 0x55, 0x55)]
+        );
+
+        // Too little image data, an unknown filter and a missing palette
+        assert!(decode(&png(2, 2, 4, COLOR_GRAY, &[], &[FILTER_NONE, 0xf5])).is_err());
+        assert!(decode(&png(2, 1, 4, COLOR_GRAY, &[], &[7, 0xf5])).is_err());
+        assert!(decode(&png(2, 1, 8, COLOR_PALETTE, &[], &[FILTER_NONE, 0, 1])).is_err());
+    }
+}
@@ -0,0 +1,152 @@
+use alloc::vec::Vec;
+
+use super::{check_size, read_u32_be, Bitmap};
+use crate::print::RGBA;
+
+pub const MAGIC: &[u8] = b"qoif";
+
+/// Magic, width, height, channels and colorspace
+const HEADER_SIZE: usize = 14;
+
+const OP_RGB: u8 = 0xfe;
+const OP_RGBA: u8 = 0xff;
+const OP_INDEX: u8 = 0x00;
+const OP_DIFF: u8 = 0x40;
+const OP_LUMA: u8 = 0x80;
+const OP_RUN: u8 = 0xc0;
+const OP_MASK: u8 = 0xc0;
+
+fn hash(px: RGBA) -> usize {
+    let (r, g, b, a) = (
+        px.r() as usize,
+        px.g() as usize,
+        px.b() as usize,
+        px.a() as usize,
+    );
+    (r * 3 + g * 5 + b * 7 + a * 11) % 64
+}
+
+/// Decodes a QOI image. The colorspace is ignored,
+/// the framebuffer is assumed to be sRGB.
+pub fn decode(data: &[u8]) -> Result<Bitmap, ()> {
+    if !data.starts_with(MAGIC) || data.len() < HEADER_SIZE {
+        return Err(());
+    }
+
+    let width = read_u32_be(data, 4)?;
+    let height = read_u32_be(data, 8)?;
+    check_size(width, height)?;
+
+    let count = (width * height) as usize;
+    let mut pixels = Vec::with_capacity(count);
+    let mut index = [RGBA::rgba(0, 0, 0, 0); 64];
+    let mut px = RGBA::rgb(0, 0, 0);
+
+    let mut bytes = data[HEADER_SIZE..].iter().copied();
+    let mut next = || bytes.next().ok_or(());
+
+    while pixels.len() < count {
+        let op = next()?;
+        let mut run = 1;
+
+        match op {
+            OP_RGB => px = RGBA::rgba(next()?, next()?, next()?, px.a()),
+            OP_RGBA => px = RGBA::rgba(next()?, next()?, next()?, next()?),
+            _ => match op & OP_MASK {
+                OP_INDEX => px = index[op as usize],
+                OP_DIFF => {
+                    let diff = |shift: u8| ((op >> shift) & 0x3).wrapping_sub(2);
+                    px = RGBA::rgba(
+                        px.r().wrapping_add(diff(4)),
+                        px.g().wrapping_add(diff(2)),
+                        px.b().wrapping_add(diff(0)),
+                        px.a(),
+                    );
+                }
+                OP_LUMA => {
+                    let dg = (op & 0x3f).wrapping_sub(32);
+                    let drb = next()?;
+                    let dr = dg.wrapping_add(drb >> 4).wrapping_sub(8);
+                    let db = dg.wrapping_add(drb & 0xf).wrapping_sub(8);
+                    px = RGBA::rgba(
+                        px.r().wrapping_add(dr),
+                        px.g().wrapping_add(dg),
+                        px.b().wrapping_add(db),
+                        px.a(),
+                    );
+                }
+                OP_RUN => run = (op & 0x3f) as usize + 1,
+                _ => unreachable!(),
+            },
+        }
+
+        index[hash(px)] = px;
+        let run = run.min(count - pixels.len());
+        pixels.extend(core::iter::repeat(px).take(run));
+    }
+
+    Ok(Bitmap {
+        width,
+        height,
+        pixels,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(width: u32, height: u32) -> Vec<u8> {
+        let mut data = Vec::from(MAGIC);
+        data.extend_from_slice(&width.to_be_bytes());
+        data.extend_from_slice(&height.to_be_bytes());
+        data.extend_from_slice(&[4, 0]);
+        data
+    }
+
+    #[test]
+    fn decodes_every_op() {
+        let first = RGBA::rgba(10, 20, 30, 0x80);
+        let mut data = header(7, 1);
+        data.extend_from_slice(&[
+            OP_RGBA,
+            10,
+            20,
+            30,
+            0x80,                 // (10, 20, 30, 0x80)
+            OP_DIFF | 0b11_01_10, // r+1, g-1, b
+            OP_LUMA | 34,
+            0x97,       // g+2, r+2+1, b+2-1
+            OP_RUN | 1, // two more of the same
+            OP_RGB,
+            1,
+            2,
+            3, // alpha stays
+            OP_INDEX | hash(first) as u8,
+        ]);
+        data.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 1]);
+
+        let bitmap = decode(&data).unwrap();
+        let luma = RGBA::rgba(14, 21, 31, 0x80);
+        assert_eq!(
+            bitmap.pixels,
+            [
+                first,
+                RGBA::rgba(11, 19, 30, 0x80),
+                luma,
+                luma,
+                luma,
+                RGBA::rgba(1, 2, 3, 0x80),
+                first,
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_images() {
+        let mut data = header(2, 2);
+        data.extend_from_slice(&[OP_RGB, 1, 2, 3, OP_RUN | 1]);
+        assert!(decode(&data).is_err());
+        assert!(decode(&header(0, 2)).is_err());
+    }
+}
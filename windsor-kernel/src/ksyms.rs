@@ -0,0 +1,165 @@
+/// Identifies a table written by build-tool. Builds that skip
+/// build-tool leave the reserved space zeroed.
+const MAGIC: &[u8; 4] = b"KSYM";
+/// Magic followed by the symbol count
+const HEADER_SIZE: usize = 8;
+/// Address, size and name offset
+const ENTRY_SIZE: usize = 12;
+
+#[cfg(not(test))]
+extern "C" {
+    static __ksyms_start: u8;
+    static __ksyms_end: u8;
+}
+
+/// Function symbols sorted by address, in the format build-tool embeds:
+/// a header, fixed size entries and then the NUL terminated names
+pub struct SymbolTable<'a> {
+    entries: &'a [u8],
+    names: &'a [u8],
+}
+
+/// Function containing a looked up address
+pub struct Symbol<'a> {
+    pub name: &'a str,
+    pub addr: u32,
+}
+
+impl<'a> SymbolTable<'a> {
+    pub fn parse(data: &'a [u8]) -> Option<Self> {
+        if data.get(..4)? != MAGIC {
+            return None;
+        }
+
+        let count = read_u32(data, 4)? as usize;
+        let names_start = count.checked_mul(ENTRY_SIZE)?.checked_add(HEADER_SIZE)?;
+
+        Some(Self {
+            entries: data.get(HEADER_SIZE..names_start)?,
+            names: &data[names_start..],
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len() / ENTRY_SIZE
+    }
+
+    /// Address, size and name offset of the entry at `idx`
+    fn entry(&self, idx: usize) -> (u32, u32, u32) {
+        let base = idx * ENTRY_SIZE;
+        let field = |offset| read_u32(self.entries, base + offset).unwrap();
+        (field(0), field(4), field(8))
+    }
+
+    /// Finds the function whose code contains `addr`
+    pub fn lookup(&self, addr: u32) -> Option<Symbol<'a>> {
+        // Index of the first function starting past `addr`
+        let idx = partition_point(self.len(), |idx| self.entry(idx).0 <= addr);
+        let (start, size, name) = self.entry(idx.checked_sub(1)?);
+        if addr - start >= size {
+            return None;
+        }
+
+        let name = self.names.get(name as usize..)?;
+        let len = name.iter().position(|&b| b == 0)?;
+        Some(Symbol {
+            name: core::str::from_utf8(&name[..len]).ok()?,
+            addr: start,
+        })
+    }
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset + 4)?;
+    Some(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+/// Number of leading indices below `len` for which `pred` holds
+fn partition_point(len: usize, pred: impl Fn(usize) -> bool) -> usize {
+    let (mut lo, mut hi) = (0, len);
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        if pred(mid) {
+            lo = mid + 1;
+        } else {
+            hi = mid;
+        }
+    }
+
+    lo
+}
+
+/// The kernel's own symbols, if build-tool embedded them
+#[cfg(not(test))]
+pub fn kernel() -> Option<SymbolTable<'static>> {
+    let data = unsafe {
+        let start = &__ksyms_start as *const u8;
+        let end = &__ksyms_end as *const u8;
+        core::slice::from_raw_parts(start, end as usize - start as usize)
+    };
+
+    SymbolTable::parse(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    fn build(funcs: &[(u32, u32, &str)]) -> Vec<u8> {
+        let mut table = Vec::from(&MAGIC[..]);
+        table.extend_from_slice(&(funcs.len() as u32).to_le_bytes());
+
+        let mut names = Vec::new();
+        for (addr, size, name) in funcs {
+            table.extend_from_slice(&addr.to_le_bytes());
+            table.extend_from_slice(&size.to_le_bytes());
+            table.extend_from_slice(&(names.len() as u32).to_le_bytes());
+            names.extend_from_slice(name.as_bytes());
+            names.push(0);
+        }
+
+        table.extend_from_slice(&names);
+        table
+    }
+
+    #[test]
+    fn lookup_finds_containing_function() {
+        let data = build(&[
+            (0x8001_0000, 0x40, "kmain"),
+            (0x8001_0040, 0x10, "windsor_kernel::thread::exit"),
+            (0x8001_0080, 0x20, "core::panicking::panic"),
+        ]);
+        let table = SymbolTable::parse(&data).unwrap();
+        assert_eq!(table.len(), 3);
+
+        let name = |addr| table.lookup(addr).map(|sym| sym.name);
+        assert_eq!(name(0x8001_0000), Some("kmain"));
+        assert_eq!(name(0x8001_003f), Some("kmain"));
+        assert_eq!(name(0x8001_0044), Some("windsor_kernel::thread::exit"));
+        assert_eq!(name(0x8001_009f), Some("core::panicking::panic"));
+        assert_eq!(table.lookup(0x8001_0088).unwrap().addr, 0x8001_0080);
+    }
+
+    #[test]
+    fn lookup_misses_outside_functions() {
+        let data = build(&[(0x8001_0000, 0x40, "kmain"), (0x8001_0080, 0x20, "idle")]);
+        let table = SymbolTable::parse(&data).unwrap();
+
+        assert!(table.lookup(0x8000_ffff).is_none());
+        assert!(table.lookup(0x8001_0040).is_none());
+        assert!(table.lookup(0x8001_00a0).is_none());
+    }
+
+    #[test]
+    fn parse_rejects_missing_or_truncated_tables() {
+        assert!(SymbolTable::parse(&[0; 64]).is_none());
+
+        let data = build(&[(0x8001_0000, 0x40, "kmain")]);
+        assert!(SymbolTable::parse(&data[..HEADER_SIZE + ENTRY_SIZE - 1]).is_none());
+
+        let empty = build(&[]);
+        assert_eq!(SymbolTable::parse(&empty).unwrap().len(), 0);
+        assert!(SymbolTable::parse(&empty).unwrap().lookup(0).is_none());
+    }
+}
@@ -0,0 +1,342 @@
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+use crate::sync::SpinLock;
+use crate::{serial, time};
+
+/// Bytes of recent output kept for sinks attached later
+const RING_SIZE: usize = 16 * 1024;
+
+const DEFAULT_LEVEL: LevelFilter = LevelFilter::Info;
+
+/// Prints to the kernel log without a level or prefix
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => ($crate::logger::_print(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($($arg:tt)*) => ($crate::logger::_print(format_args!("{}\n", format_args!($($arg)*))));
+}
+
+/// Destination for log output. Sinks are called with the log locked,
+/// so they must not log themselves.
+pub trait Sink: Send {
+    fn write_str(&mut self, s: &str);
+
+    /// Writes the panic report. The panic may have interrupted
+    /// the sink itself, so sinks that lock should skip it.
+    fn write_panic(&mut self, s: &str) {
+        self.write_str(s);
+    }
+}
+
+struct Ring {
+    buf: [u8; RING_SIZE],
+    start: usize,
+    len: usize,
+}
+
+impl Ring {
+    fn push(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.buf[(self.start + self.len) % RING_SIZE] = byte;
+            if self.len == RING_SIZE {
+                self.start = (self.start + 1) % RING_SIZE;
+            } else {
+                self.len += 1;
+            }
+        }
+    }
+
+    fn contents(&self) -> String {
+        let mut bytes = Vec::with_capacity(self.len);
+        bytes.extend_from_slice(&self.buf[self.start..(self.start + self.len).min(RING_SIZE)]);
+        if self.start + self.len > RING_SIZE {
+            bytes.extend_from_slice(&self.buf[..self.start + self.len - RING_SIZE]);
+        }
+
+        // Once wrapped, the oldest line has lost its beginning
+        if self.len == RING_SIZE {
+            let first_line = bytes.iter().position(|&b| b == b'\n').map_or(0, |i| i + 1);
+            bytes.drain(..first_line);
+        }
+
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+}
+
+struct Filters {
+    default: LevelFilter,
+    /// Module path prefixes with their own level
+    modules: Vec<(&'static str, LevelFilter)>,
+}
+
+impl Filters {
+    fn level_for(&self, target: &str) -> LevelFilter {
+        self.modules
+            .iter()
+            .filter(|(module, _)| {
+                target == *module
+                    || (target.starts_with(module) && target[module.len()..].starts_with("::"))
+            })
+            .max_by_key(|(module, _)| module.len())
+            .map_or(self.default, |&(_, level)| level)
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .map(|&(_, level)| level)
+            .fold(self.default, Ord::max)
+    }
+}
+
+static RING: SpinLock<Ring> = SpinLock::new(Ring {
+    buf: [0; RING_SIZE],
+    start: 0,
+    len: 0,
+});
+
+static SINKS: SpinLock<Vec<Box<dyn Sink>>> = SpinLock::new(Vec::new());
+
+static FILTERS: SpinLock<Filters> = SpinLock::new(Filters {
+    default: DEFAULT_LEVEL,
+    modules: Vec::new(),
+});
+
+struct Logger;
+
+static LOGGER: Logger = Logger;
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= FILTERS.lock().level_for(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        // ANSI colors, understood by the console and serial terminals
+        let color = match record.level() {
+            Level::Error => "31",
+            Level::Warn => "33",
+            Level::Info => "32",
+            Level::Debug => "36",
+            Level::Trace => "35",
+        };
+
+        let uptime = time::uptime();
+        _print(format_args!(
+            "[{:5}.{:06}] \x1b[{}m{:5}\x1b[0m {}: {}\n",
+            uptime.as_secs(),
+            uptime.subsec_micros(),
+            color,
+            record.level(),
+            record.target(),
+            record.args()
+        ));
+    }
+
+    fn flush(&self) {}
+}
+
+/// Levels set at build time through WINDSOR_LOG, in the form
+/// `info,windsor_kernel::nv2a=debug`
+const BUILD_LEVELS: Option<&str> = option_env!("WINDSOR_LOG");
+
+/// Splits `spec` into modules and levels, with no module for the
+/// default level. Directives that don't parse are returned as errors.
+fn parse_levels(
+    spec: &'static str,
+) -> impl Iterator<Item = Result<(Option<&'static str>, LevelFilter), &'static str>> {
+    spec.split(',')
+        .map(str::trim)
+        .filter(|directive| !directive.is_empty())
+        .map(|directive| match directive.split_once('=') {
+            Some((module, level)) => level
+                .parse()
+                .map(|level| (Some(module), level))
+                .map_err(|_| directive),
+            None => directive
+                .parse()
+                .map(|level| (None, level))
+                .map_err(|_| directive),
+        })
+}
+
+/// Installs the kernel logger. The heap must be set up first.
+pub fn init() {
+    log::set_logger(&LOGGER).expect("Logger should only be installed once");
+    log::set_max_level(DEFAULT_LEVEL);
+
+    for directive in parse_levels(BUILD_LEVELS.unwrap_or("")) {
+        match directive {
+            Ok((None, level)) => set_level(level),
+            Ok((Some(module), level)) => set_module_level(module, level),
+            Err(directive) => log::warn!("Ignoring WINDSOR_LOG directive {:?}", directive),
+        }
+    }
+}
+
+/// Sets the level for modules without their own
+pub fn set_level(level: LevelFilter) {
+    let mut filters = FILTERS.lock();
+    filters.default = level;
+    log::set_max_level(filters.max_level());
+}
+
+/// Sets the level for `module` and the modules below it,
+/// given as a path like `windsor_kernel::smbus`
+pub fn set_module_level(module: &'static str, level: LevelFilter) {
+    let mut filters = FILTERS.lock();
+    match filters.modules.iter_mut().find(|(m, _)| *m == module) {
+        Some(entry) => entry.1 = level,
+        None => filters.modules.push((module, level)),
+    }
+    log::set_max_level(filters.max_level());
+}
+
+/// Attaches a sink, first replaying the output it missed
+pub fn add_sink(mut sink: Box<dyn Sink>) {
+    // Holding the ring lock keeps new output from slipping
+    // in between the replay and attaching the sink
+    let ring = RING.lock();
+    sink.write_str(&ring.contents());
+    SINKS.lock().push(sink);
+}
+
+/// Passes output to the ring and the sinks piece by piece as it is
+/// formatted, so printing doesn't allocate
+struct Output<'a> {
+    ring: &'a mut Ring,
+    sinks: &'a mut [Box<dyn Sink>],
+}
+
+impl fmt::Write for Output<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.ring.push(s.as_bytes());
+        for sink in self.sinks.iter_mut() {
+            sink.write_str(s);
+        }
+        Ok(())
+    }
+}
+
+/// Formats with the log locked, so the arguments must not log themselves
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    let mut ring = RING.lock();
+    let mut sinks = SINKS.lock();
+    let mut output = Output {
+        ring: &mut ring,
+        sinks: &mut sinks,
+    };
+    let _ = output.write_fmt(args);
+}
+
+/// Adds panic output to the history and passes it to the sinks,
+/// without locking or allocating.
+/// Safety: for the panic handler only, with interrupts disabled
+pub unsafe fn write_panic(s: &str) {
+    (*RING.steal()).push(s.as_bytes());
+    for sink in (*SINKS.steal()).iter_mut() {
+        sink.write_panic(s);
+    }
+}
+
+/// Writes to the framebuffer console, once it exists
+pub struct ConsoleSink;
+
+impl Sink for ConsoleSink {
+    fn write_str(&mut self, s: &str) {
+        if let Some(console) = crate::CONSOLE.lock().as_mut() {
+            let _ = console.write_str(s);
+        }
+    }
+
+    // The panic screen takes over the framebuffer
+    fn write_panic(&mut self, _s: &str) {}
+}
+
+impl Sink for serial::Serial {
+    fn write_str(&mut self, s: &str) {
+        let _ = Write::write_str(self, s);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_ring() -> Box<Ring> {
+        Box::new(Ring {
+            buf: [0; RING_SIZE],
+            start: 0,
+            len: 0,
+        })
+    }
+
+    #[test]
+    fn ring_keeps_everything_until_full() {
+        let mut ring = new_ring();
+        ring.push(b"first\n");
+        ring.push(b"second\n");
+        assert_eq!(ring.contents(), "first\nsecond\n");
+    }
+
+    #[test]
+    fn ring_drops_partial_oldest_line() {
+        let mut ring = new_ring();
+        let line = [b'x'; 99];
+        for _ in 0..RING_SIZE / 100 + 2 {
+            ring.push(&line);
+            ring.push(b"\n");
+        }
+
+        let contents = ring.contents();
+        assert!(contents.len() < RING_SIZE);
+        assert!(contents.split_terminator('\n').all(|l| l.len() == 99));
+    }
+
+    #[test]
+    fn module_filters_match_whole_path_components() {
+        let filters = Filters {
+            default: LevelFilter::Info,
+            modules: alloc::vec![
+                ("windsor_kernel::nv2a", LevelFilter::Debug),
+                ("windsor_kernel::nv2a::vblank", LevelFilter::Off),
+            ],
+        };
+
+        let level = |target| filters.level_for(target);
+        assert_eq!(level("windsor_kernel::nv2a"), LevelFilter::Debug);
+        assert_eq!(level("windsor_kernel::nv2a::pll"), LevelFilter::Debug);
+        assert_eq!(level("windsor_kernel::nv2a::vblank"), LevelFilter::Off);
+        assert_eq!(level("windsor_kernel::nv2ax"), LevelFilter::Info);
+        assert_eq!(filters.max_level(), LevelFilter::Debug);
+    }
+
+    #[test]
+    fn build_levels_parse() {
+        let levels: Vec<_> =
+            parse_levels("warn, windsor_kernel::nv2a=debug,,loud,x=nope").collect();
+        assert_eq!(
+            levels,
+            [
+                Ok((None, LevelFilter::Warn)),
+                Ok((Some("windsor_kernel::nv2a"), LevelFilter::Debug)),
+                Err("loud"),
+                Err("x=nope"),
+            ]
+        );
+    }
+}
@@ -1,5 +1,5 @@
-#![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
 #![feature(pointer_byte_offsets)]
 #![feature(panic_info_message)]
 #![feature(const_mut_refs)]
@@ -8,20 +8,50 @@
 #![feature(int_roundings)]
 #![feature(naked_functions)]
 
+#[macro_use]
+mod logger;
+
+mod console;
 mod cpu;
 mod encoder;
+mod executor;
 mod font;
+mod framebuffer;
+mod gfx;
+mod heap;
 mod i2c;
+mod image;
+mod ksyms;
 mod nv2a;
+#[cfg(not(test))]
+mod panic;
 mod pci;
 mod physram;
 mod print;
+mod serial;
 mod smbus;
+mod smc;
+mod splash;
+mod sync;
+mod test_card;
+mod thread;
+mod time;
+
+extern crate alloc;
 
-use core::panic::PanicInfo;
+use alloc::boxed::Box;
+use core::ops::Range;
+use cpu::mmu::PhysramAllocator;
+use sync::SpinLock;
 
-pub static mut PRINTER: Option<print::VGAPrinter> = None;
+/// Text console along the bottom of the screen, shared with interrupt
+/// handlers. Each flip redraws it into the buffer going on screen.
+pub static CONSOLE: SpinLock<Option<console::Console>> = SpinLock::new(None);
 
+/// Rows the console keeps at the bottom of the screen, below the UI
+const CONSOLE_ROWS: u32 = 10;
+
+#[cfg(not(test))]
 #[no_mangle]
 #[naked]
 pub unsafe extern "C" fn kenter() {
@@ -42,6 +72,8 @@ pub unsafe extern "C" fn kenter() {
 
 extern "C" {
     static mut __start_code_ram: u32;
+    static mut __kernel_stack_guard: u32;
+    static mut __kernel_stack_bottom: u32;
     static mut __kernel_stack: u32;
 }
 
@@ -59,86 +91,252 @@ pub fn kernel_region() -> &'static [u8] {
     }
 }
 
-const FB_SIZE: u32 = 0x40_0000;
-const FB_START: u32 = 0xf000_0000 | (64 * 1024 * 1024 - FB_SIZE);
+pub fn kernel_stack_top() -> u32 {
+    unsafe { linker_var!(__kernel_stack) }
+}
+
+/// Bounds of the stack the boot thread runs on
+pub fn kernel_stack() -> Range<u32> {
+    unsafe { linker_var!(__kernel_stack_bottom)..linker_var!(__kernel_stack) }
+}
+
+/// Unmapped page directly below the kernel stack
+pub fn kernel_stack_guard() -> u32 {
+    unsafe { linker_var!(__kernel_stack_guard) }
+}
 
-fn clear_screen(vm: &encoder::VideoModeInfo, argb: u32) {
-    let fb = unsafe {
-        core::slice::from_raw_parts_mut(FB_START as *mut u32, (vm.height * vm.width) as usize)
+fn clear_screen(fb: *mut u8, vm: &encoder::VideoModeInfo, color: print::RGBA) {
+    let mut surface = unsafe {
+        gfx::Surface::from_raw(
+            fb,
+            vm.width,
+            vm.height,
+            vm.pitch() as usize,
+            vm.pixel_format,
+        )
     };
-    fb.fill(argb);
+    surface.clear(color);
 }
 
+#[cfg(not(test))]
 #[no_mangle]
 pub extern "C" fn kmain() -> ! {
     unsafe {
-        cpu::gdt::lgdt(&mut cpu::gdt::GDTR, cpu::gdt::GDT);
+        let mut gdt = cpu::gdt::INITIAL_GDT;
+        cpu::tss::install(&mut gdt);
+        cpu::gdt::lgdt(gdt);
+        cpu::tss::load();
         cpu::irq::setup_irq();
 
-        let mut pmm = physram::BitmapAlloc::new();
-        let _mmu = cpu::mmu::Mapping::from_bootstrap(&mut pmm);
+        let mapping = cpu::mmu::Mapping::from_bootstrap(&mut *physram::ALLOCATOR.lock());
+        let _ = cpu::mmu::KERNEL_MAPPING.set(SpinLock::new(mapping));
+
+        let guard = kernel_stack_guard();
+        let mut mapping = cpu::mmu::kernel_mapping();
+        let mut pm = physram::ALLOCATOR.lock();
+        if let Some(frame) = mapping.translate(guard) {
+            mapping.unmap(guard, 0x1000, &mut *pm).unwrap();
+            pm.free(frame);
+        }
+        drop(pm);
+        drop(mapping);
+
+        cpu::pagefault::register(
+            guard,
+            0x1000,
+            cpu::pagefault::RegionKind::Guard("Kernel stack overflow"),
+        )
+        .unwrap();
+
+        nv2a::map_registers().expect("NV2A registers should be mappable");
+        heap::init();
+    }
+
+    logger::init();
+    font::init();
+    println!("windsor {}", env!("CARGO_PKG_VERSION"));
+    if let Some(com1) = serial::Serial::probe(serial::COM1, 115_200) {
+        logger::add_sink(Box::new(com1));
     }
 
+    // Video memory offset 0 is used by the GPU, as is instance memory
+    nv2a::vram::reserve(0, cpu::mmu::PAGE_SIZE);
+    nv2a::vram::reserve(nv2a::ramin::VRAM_OFFSET, nv2a::ramin::SIZE);
+
+    cpu::pic::init();
+    time::init();
+    thread::init();
+    log::info!(
+        "Timer tick at {} Hz, TSC calibrated at {} kHz",
+        cpu::pit::frequency(),
+        time::tsc_khz()
+    );
+    log::info!("Kernel heap {} KiB", heap::stats().size / 1024);
+
+    let free_kib = |zone| {
+        let frames = physram::ALLOCATOR.lock().free_frames(zone);
+        frames * cpu::mmu::PAGE_SIZE as usize / 1024
+    };
+    log::info!(
+        "Free RAM: {} KiB DMA, {} KiB general, {} KiB GPU",
+        free_kib(physram::Zone::Dma),
+        free_kib(physram::Zone::General),
+        free_kib(physram::Zone::Gpu)
+    );
+
     pci::initialize_devices();
     pci::initialize_agp();
 
     let encoder = encoder::Model::detect();
     let av_mode = encoder::AVMode::detect();
     let video_mode = av_mode.get_video_mode(&encoder).unwrap();
-    //clear_screen(&video_mode, 0xff7aa0ff);
-    clear_screen(&video_mode, 0xff00_0000);
 
-    let gpu = nv2a::get_device();
-    gpu.init(FB_START);
+    let clocks = nv2a::pll::ClockProfile::from_build().unwrap_or_else(|spec| {
+        log::warn!("Ignoring WINDSOR_CLOCKS {:?}, using stock clocks", spec);
+        nv2a::pll::ClockProfile::STOCK
+    });
 
-    let mut printer = print::VGAPrinter::new(FB_START as *mut u32, &video_mode);
-    printer.print_string_bytes(print::COLOR_WHITE, "windsor ".as_bytes());
-    printer.print_string_bytes(print::COLOR_WHITE, env!("CARGO_PKG_VERSION").as_bytes());
+    let fb = {
+        let mut gpu = nv2a::lock();
+        let fb = unsafe {
+            framebuffer::Framebuffer::new(&mut gpu, &video_mode, framebuffer::FRAMEBUFFER_COUNT)
+        }
+        .expect("Framebuffer should fit in GPU memory");
+        panic::set_framebuffer(
+            fb.front_buffer_addr() as u32,
+            fb.front_buffer_offset(),
+            &video_mode,
+        );
 
-    cpu::pic::init();
-    cpu::sti();
+        //clear_screen(fb.front_buffer_addr(), &video_mode, print::RGBA::rgb(0x7a, 0xa0, 0xff));
+        clear_screen(fb.front_buffer_addr(), &video_mode, print::COLOR_BLACK);
+        gpu.init(fb.front_buffer_offset(), &video_mode);
+        gpu.set_clock_profile(&clocks);
+        fb
+    };
+    log::info!(
+        "Framebuffer: {} buffers of {} KiB",
+        fb.buffer_count(),
+        fb.buffer_size() / 1024
+    );
 
-    let mut frame_count: u64 = 0;
-    let colors = [0xff00_0000, 0xff7aa0ff];
-    let text_colors = [print::COLOR_WHITE, print::COLOR_BLACK];
-    let mut color_toggle = 0;
+    if unsafe { nv2a::accel::init() }.is_err() {
+        log::warn!("NV2A 2D engine didn't start, drawing with the CPU");
+    }
 
-    loop {
-        if gpu.pmc.intr.read() != 0 {
-            frame_count += 1;
-            unsafe { gpu.pcrtc.intr.write(0x1) };
-
-            if frame_count % 60 == 0 {
-                clear_screen(&video_mode, colors[color_toggle]);
-                printer.reset();
-                printer.print_string_bytes(text_colors[color_toggle], "windsor ".as_bytes());
-                printer.print_string_bytes(text_colors[color_toggle], env!("CARGO_PKG_VERSION").as_bytes());
-
-                color_toggle += 1;
-                color_toggle %= 2;
-            }
+    match console::Console::with_rows(fb.front_buffer_addr(), &video_mode, CONSOLE_ROWS) {
+        Some(mut console) => {
+            console.set_accel(fb.front_buffer_accel());
+            *CONSOLE.lock() = Some(console);
+            logger::add_sink(Box::new(logger::ConsoleSink));
         }
+        None => log::warn!("Video mode too small for a console"),
     }
+
+    cpu::pic::register(nv2a::IRQ, nv2a::handle_irq).expect("NV2A IRQ line should be free");
+    cpu::sti();
+
+    // Runs on its own thread, so that spawning, exiting and
+    // joining are checked too before anything relies on them
+    let check = thread::spawn("selfcheck", thread::Priority::Normal, || {
+        let start = time::uptime_ms();
+        match time::self_check() {
+            Ok(()) => log::debug!("Timers checked in {} ms", time::uptime_ms() - start),
+            Err(e) => log::warn!("Timer self-check failed: {}", e),
+        }
+    })
+    .expect("Self-check thread should start");
+    log::debug!("Joining self-check thread {:?}", check.id());
+    check.join();
+
+    thread::spawn("ui", thread::Priority::Normal, move || {
+        ui_main(fb, video_mode)
+    })
+    .expect("UI thread should start");
+
+    executor::spawn(smc::monitor()).expect("SMC task should start");
+    executor::spawn(nv2a::vblank::report_refresh_rate()).expect("Refresh rate task should start");
+    executor::spawn(nv2a::accel::report_errors()).expect("GPU error task should start");
+
+    // Drivers run as tasks from here on, and the
+    // CPU halts whenever none of them are ready
+    executor::run();
 }
 
-#[panic_handler]
-fn panic(info: &PanicInfo) -> ! {
-    unsafe {
-        core::ptr::write_bytes(FB_START as *mut u8, 0xff, FB_SIZE as usize);
-    }
+#[cfg(not(test))]
+fn ui_main(mut fb: framebuffer::Framebuffer, video_mode: encoder::VideoModeInfo) -> ! {
+    let colors = [print::COLOR_BLACK, print::RGBA::rgb(0x7a, 0xa0, 0xff)];
+    let text_colors = [print::COLOR_WHITE, print::COLOR_BLACK];
+    let mut color_toggle = 0;
 
-    let encoder = encoder::Model::detect();
-    let av_mode = encoder::AVMode::detect();
-    let video_mode = av_mode.get_video_mode(&encoder).unwrap();
+    splash::show(&mut fb, &video_mode);
 
-    let mut printer = print::VGAPrinter::new(FB_START as *mut u32, &video_mode);
-    printer.print_string_bytes(print::COLOR_BLACK, b"Kernel panic!\n\n");
+    // The UI keeps above the console
+    let console_top = CONSOLE
+        .lock()
+        .as_ref()
+        .map(|console| console.area().y as u32);
 
-    if let Some(args) = info.message() {
-        if let Some(msg) = args.as_str() {
-            printer.print_string_bytes(print::COLOR_BLACK, msg.as_bytes());
+    let card = match unsafe { test_card::TestCard::new(&video_mode) } {
+        Ok(card) if card.show(&mut nv2a::lock(), &video_mode).is_ok() => Some(card),
+        Ok(_) => {
+            log::warn!("Test card doesn't fit the overlay");
+            None
         }
+        Err(e) => {
+            log::warn!("No GPU memory for the test card: {:?}", e);
+            None
+        }
+    };
+
+    loop {
+        time::sleep_ms(1000);
+        fb.wait_flip();
+
+        fb.clear(colors[color_toggle]);
+        let uptime = alloc::format!("up {} s", time::uptime().as_secs());
+        let mut surface = fb.surface();
+        let ui_height = console_top.unwrap_or(surface.height());
+        let ui_area = gfx::Rect::new(0, 0, surface.width(), ui_height);
+        if let Some(card) = &card {
+            surface.fill_rect(card.window(), test_card::KEY);
+        }
+        draw_status(&mut surface, ui_area, &uptime, text_colors[color_toggle]);
+        fb.flip();
+
+        color_toggle += 1;
+        color_toggle %= 2;
     }
+}
+
+/// Draws a card in the middle of `area` with the kernel
+/// version above `status`, separated by a line
+#[cfg(not(test))]
+fn draw_status(surface: &mut gfx::Surface, area: gfx::Rect, status: &str, color: print::RGBA) {
+    let font = font::default();
+    let scale = font.scale_for(surface.width());
+    let padding = font.width() * scale;
+    let title = concat!("windsor ", env!("CARGO_PKG_VERSION"));
+
+    let (title_width, line_height) = gfx::text_size(title, font, scale);
+    let (status_width, _) = gfx::text_size(status, font, scale);
+    let width = (title_width.max(status_width) + 2 * padding).min(area.width);
+    let height = 2 * line_height + 3 * padding;
+    let card = gfx::Rect::new(
+        area.x + (area.width - width) as i32 / 2,
+        area.y + (area.height.saturating_sub(height)) as i32 / 2,
+        width,
+        height,
+    );
+    surface.draw_rect(card, color);
 
-    loop {}
+    // Text too wide for the screen is cut off at the card's edge
+    surface.set_clip(card);
+    let left = card.x + padding as i32;
+    let title = surface.draw_text(left, card.y + padding as i32, title, font, scale, color);
+    let separator = title.bottom() + padding as i32 / 2;
+    surface.draw_line((card.x, separator), (card.right() - 1, separator), color);
+    let status_top = title.bottom() + padding as i32;
+    surface.draw_text(left, status_top, status, font, scale, color);
+    surface.reset_clip();
 }
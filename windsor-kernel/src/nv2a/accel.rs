@@ -0,0 +1,591 @@
+use core::ptr;
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::time::Duration;
+
+use super::ramin::{self, Engine, Ramin};
+use super::vram::{self, GpuMemory};
+use super::NV2A;
+use crate::executor;
+use crate::gfx::{PixelFormat, Rect};
+use crate::print::RGBA;
+use crate::sync::{OnceCell, SpinLock, SpinLockGuard};
+use crate::time;
+
+/// The kernel submits everything through one channel
+const CHID: u32 = 0;
+
+const PUSH_BUFFER_SIZE: u32 = 0x1_0000;
+const NOTIFIER_SIZE: u32 = 0x1000;
+
+/// Surface offsets and pitches must be multiples of this
+pub const SURFACE_ALIGN: u32 = 64;
+/// Coordinates and sizes are sent as 16 bit fields
+const MAX_COORD: u32 = 0x7fff;
+
+/// Time the GPU gets to catch up before it's considered hung
+const TIMEOUT: Duration = Duration::from_millis(100);
+
+/// PGRAPH context size for NV2A, from nouveau
+const GRCTX_SIZE: u32 = 0x36b0;
+
+const PMC_ENABLE_PFIFO: u32 = 1 << 8;
+const PMC_ENABLE_PGRAPH: u32 = 1 << 12;
+
+const HANDLE_NULL: u32 = 0x1000;
+const HANDLE_VRAM: u32 = 0x1001;
+const HANDLE_NOTIFIER: u32 = 0x1002;
+const HANDLE_SURFACES: u32 = 0x1003;
+const HANDLE_RECT: u32 = 0x1004;
+const HANDLE_BLIT: u32 = 0x1005;
+
+const CLASS_NULL: u32 = 0x30;
+const CLASS_GDI_RECT: u32 = 0x4a;
+const CLASS_SURFACES_2D: u32 = 0x62;
+const CLASS_IMAGE_BLIT: u32 = 0x9f;
+
+const OBJECTS: [(u32, u32); 4] = [
+    (HANDLE_NULL, CLASS_NULL),
+    (HANDLE_SURFACES, CLASS_SURFACES_2D),
+    (HANDLE_RECT, CLASS_GDI_RECT),
+    (HANDLE_BLIT, CLASS_IMAGE_BLIT),
+];
+
+const SUBCH_SURFACES: u32 = 0;
+const SUBCH_RECT: u32 = 1;
+const SUBCH_BLIT: u32 = 2;
+
+/// Binds an object to a subchannel
+const SET_OBJECT: u32 = 0x000;
+const NOP: u32 = 0x100;
+const NOTIFY: u32 = 0x104;
+const SET_CONTEXT_DMA_NOTIFY: u32 = 0x180;
+
+/// Followed by the destination DMA object
+const SURFACES_SET_CONTEXT_DMA_SOURCE: u32 = 0x184;
+/// Followed by the pitches and the source and destination offsets
+const SURFACES_FORMAT: u32 = 0x300;
+
+const RECT_SET_CONTEXT_SURFACE: u32 = 0x198;
+const RECT_OPERATION: u32 = 0x2fc;
+const RECT_COLOR_FORMAT: u32 = 0x300;
+const RECT_COLOR: u32 = 0x3fc;
+/// Followed by the size
+const RECT_POINT: u32 = 0x400;
+
+const BLIT_SET_CONTEXT_COLOR_KEY: u32 = 0x184;
+const BLIT_SET_CONTEXT_SURFACES: u32 = 0x19c;
+const BLIT_OPERATION: u32 = 0x2fc;
+/// Followed by the destination point and the size
+const BLIT_POINT_IN: u32 = 0x300;
+
+/// Copy without any masks
+const OPERATION_SRCCOPY: u32 = 3;
+
+/// Writes the notifier without raising an interrupt
+const NOTIFY_WRITE: u32 = 0;
+/// Status the notifier holds until the GPU writes it
+const NOTIFY_PENDING: u32 = 0xffff;
+/// Offset of the info16 and status words in the notifier
+const NOTIFY_STATUS: u32 = 0xc;
+
+/// Push buffer command that continues fetching at an offset
+const JUMP: u32 = 0x2000_0000;
+
+static ACCEL: OnceCell<SpinLock<Accel>> = OnceCell::new();
+
+/// Interrupt status acknowledged since the errors were last reported
+static PFIFO_INTR: AtomicU32 = AtomicU32::new(0);
+static PGRAPH_INTR: AtomicU32 = AtomicU32::new(0);
+
+/// Pixels in video memory that the 2D engine can draw into
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Surface {
+    offset: u32,
+    width: u32,
+    height: u32,
+    pitch: u32,
+    format: PixelFormat,
+}
+
+impl Surface {
+    /// Describes a surface at `offset` in video memory,
+    /// failing if the 2D engine can't address it
+    pub fn new(
+        offset: u32,
+        width: u32,
+        height: u32,
+        pitch: u32,
+        format: PixelFormat,
+    ) -> Result<Self, ()> {
+        if offset % SURFACE_ALIGN != 0
+            || pitch % SURFACE_ALIGN != 0
+            || pitch > 0xffff
+            || width > MAX_COORD
+            || height > MAX_COORD
+        {
+            return Err(());
+        }
+
+        Ok(Self {
+            offset,
+            width,
+            height,
+            pitch,
+            format,
+        })
+    }
+
+    pub fn bounds(&self) -> Rect {
+        Rect::new(0, 0, self.width, self.height)
+    }
+}
+
+/// Marks a point in the command stream, see `Accel::fence`
+#[must_use]
+pub struct Fence(u32);
+
+/// The 2D engine, fed through a push buffer on the kernel's channel
+pub struct Accel {
+    pushbuf: GpuMemory,
+    notifier: GpuMemory,
+    /// Offset in the push buffer of the next command
+    put: u32,
+
+    /// Surfaces last programmed into the surfaces object
+    surfaces: Option<(Surface, Surface)>,
+
+    /// Sequence number of the last fence
+    sequence: u32,
+    /// Whether the last fence's notification is still to be written
+    notify_pending: bool,
+    /// Set once the GPU stops responding, after which it isn't used
+    failed: bool,
+}
+
+/// Sets up the 2D engine and the kernel's channel. Fails if the GPU
+/// doesn't get through the setup commands, leaving drawing to the CPU.
+/// Safety: the kernel mapping must be set up and the GPU initialized
+pub unsafe fn init() -> Result<(), ()> {
+    let pushbuf = GpuMemory::alloc(PUSH_BUFFER_SIZE, 0x1000).map_err(|_| ())?;
+    let notifier = match GpuMemory::alloc(NOTIFIER_SIZE, 0x1000) {
+        Ok(notifier) => notifier,
+        Err(_) => {
+            pushbuf.free();
+            return Err(());
+        }
+    };
+
+    if start_channel(&mut super::lock(), &pushbuf, &notifier).is_err() {
+        pushbuf.free();
+        notifier.free();
+        return Err(());
+    }
+
+    let mut accel = Accel {
+        pushbuf,
+        notifier,
+        put: 0,
+        surfaces: None,
+        sequence: 0,
+        notify_pending: false,
+        failed: false,
+    };
+
+    if accel.setup_objects().and_then(|_| accel.finish()).is_err() {
+        // The channel may still be fetching from the push buffer or
+        // writing the notifier, so neither is given back to GPU memory
+        return Err(());
+    }
+
+    let _ = ACCEL.set(SpinLock::new(accel));
+    Ok(())
+}
+
+/// Locks the 2D engine, if it was set up and hasn't stopped responding.
+/// Lock it before the GPU registers, which it locks to submit commands.
+pub fn lock() -> Option<SpinLockGuard<'static, Accel>> {
+    let accel = ACCEL.get()?.lock();
+    (!accel.failed).then_some(accel)
+}
+
+/// Resets PFIFO and PGRAPH and starts channel `CHID` fetching from
+/// `pushbuf`, with the 2D objects in instance memory
+unsafe fn start_channel(
+    gpu: &mut NV2A,
+    pushbuf: &GpuMemory,
+    notifier: &GpuMemory,
+) -> Result<(), ()> {
+    gpu.pmc
+        .blk_en
+        .modify(|en| en & !(PMC_ENABLE_PFIFO | PMC_ENABLE_PGRAPH));
+    gpu.pmc
+        .blk_en
+        .modify(|en| en | PMC_ENABLE_PFIFO | PMC_ENABLE_PGRAPH);
+
+    let mut ramin = Ramin::new(gpu);
+
+    // PGRAPH only loads this on a channel switch, which
+    // never happens with one channel, but it has to exist
+    let grctx = ramin.alloc(gpu, GRCTX_SIZE, 0x10)?;
+    ramin::write(gpu, grctx, (CHID << 24) | 1);
+    ramin::write(gpu, ramin::CTX_TABLE + CHID * 4, grctx >> 4);
+
+    let vram = ramin.new_dma_object(gpu, 0, vram::SIZE)?;
+    ramin::bind(gpu, HANDLE_VRAM, vram, Engine::Software, CHID)?;
+
+    let notify = ramin.new_dma_object(gpu, notifier.offset(), notifier.size())?;
+    ramin::bind(gpu, HANDLE_NOTIFIER, notify, Engine::Software, CHID)?;
+
+    for (handle, class) in OBJECTS {
+        let object = ramin.new_object(gpu, class)?;
+        ramin::bind(gpu, handle, object, Engine::Graphics, CHID)?;
+    }
+
+    // The channel fetches through the push buffer's own DMA object
+    let fetch = ramin.new_dma_object(gpu, pushbuf.offset(), pushbuf.size())?;
+
+    gpu.pfifo
+        .init(ramin::RAMHT, ramin::RAMHT_BITS, ramin::RAMRO, ramin::RAMFC);
+    gpu.pgraph.init(ramin::CTX_TABLE, CHID);
+    gpu.pfifo.start_dma_channel(CHID, fetch);
+    Ok(())
+}
+
+/// Command header for `count` consecutive methods from `method`
+fn method_header(subch: u32, method: u32, count: u32) -> u32 {
+    (count << 18) | (subch << 13) | method
+}
+
+/// Surfaces object format
+fn surface_format(format: PixelFormat) -> u32 {
+    match format {
+        PixelFormat::Argb8888 => 6, // X8R8G8B8_Z8R8G8B8
+        PixelFormat::Rgb565 => 4,   // R5G6B5
+        PixelFormat::Xrgb1555 => 2, // X1R5G5B5_Z1R5G5B5
+    }
+}
+
+/// Color format of the rectangle object
+fn color_format(format: PixelFormat) -> u32 {
+    match format {
+        PixelFormat::Argb8888 => 3, // A8R8G8B8
+        PixelFormat::Rgb565 => 1,   // A16R5G6B5
+        PixelFormat::Xrgb1555 => 2, // X16A1R5G5B5
+    }
+}
+
+/// Clips a copy of `to` sized pixels from `from` in `src` to `to` in
+/// `dst`, returning the source point and destination that are in bounds
+fn clip_copy(src: Rect, from: (i32, i32), dst: Rect, to: Rect) -> Option<((u32, u32), Rect)> {
+    let area = to.intersect(&dst);
+    let (x, y) = (from.0 + area.x - to.x, from.1 + area.y - to.y);
+
+    let source = Rect::new(x, y, area.width, area.height).intersect(&src);
+    if source.is_empty() {
+        return None;
+    }
+
+    let dest = Rect::new(
+        area.x + source.x - x,
+        area.y + source.y - y,
+        source.width,
+        source.height,
+    );
+    Some(((source.x as u32, source.y as u32), dest))
+}
+
+impl Accel {
+    /// Binds the objects to their subchannels and sets their fixed state
+    fn setup_objects(&mut self) -> Result<(), ()> {
+        self.method(SUBCH_SURFACES, SET_OBJECT, &[HANDLE_SURFACES])?;
+        self.method(SUBCH_RECT, SET_OBJECT, &[HANDLE_RECT])?;
+        self.method(SUBCH_BLIT, SET_OBJECT, &[HANDLE_BLIT])?;
+
+        self.method(
+            SUBCH_SURFACES,
+            SURFACES_SET_CONTEXT_DMA_SOURCE,
+            &[HANDLE_VRAM, HANDLE_VRAM],
+        )?;
+
+        self.method(SUBCH_RECT, SET_CONTEXT_DMA_NOTIFY, &[HANDLE_NOTIFIER])?;
+        self.method(SUBCH_RECT, RECT_SET_CONTEXT_SURFACE, &[HANDLE_SURFACES])?;
+        self.method(SUBCH_RECT, RECT_OPERATION, &[OPERATION_SRCCOPY])?;
+
+        self.method(SUBCH_BLIT, BLIT_SET_CONTEXT_COLOR_KEY, &[HANDLE_NULL])?;
+        self.method(SUBCH_BLIT, BLIT_SET_CONTEXT_SURFACES, &[HANDLE_SURFACES])?;
+        self.method(SUBCH_BLIT, BLIT_OPERATION, &[OPERATION_SRCCOPY])?;
+
+        self.kick();
+        Ok(())
+    }
+
+    /// Fills `rect`, clipped to `dst`, with `color`
+    pub fn fill_rect(&mut self, dst: &Surface, rect: Rect, color: RGBA) -> Result<(), ()> {
+        let rect = rect.intersect(&dst.bounds());
+        if rect.is_empty() {
+            return Ok(());
+        }
+
+        self.set_surfaces(dst, dst)?;
+        self.method(SUBCH_RECT, RECT_COLOR, &[dst.format.encode(color)])?;
+        self.method(
+            SUBCH_RECT,
+            RECT_POINT,
+            &[
+                (rect.x as u32) << 16 | rect.y as u32,
+                rect.width << 16 | rect.height,
+            ],
+        )?;
+
+        self.kick();
+        Ok(())
+    }
+
+    /// Copies the pixels at `from` in `src` to `to` in `dst`, clipped to
+    /// both. The surfaces may be the same, and the areas may overlap.
+    pub fn copy_rect(
+        &mut self,
+        src: &Surface,
+        from: (i32, i32),
+        dst: &Surface,
+        to: Rect,
+    ) -> Result<(), ()> {
+        if src.format != dst.format {
+            return Err(());
+        }
+
+        let Some(((x, y), to)) = clip_copy(src.bounds(), from, dst.bounds(), to) else {
+            return Ok(());
+        };
+
+        self.set_surfaces(src, dst)?;
+        self.method(
+            SUBCH_BLIT,
+            BLIT_POINT_IN,
+            &[
+                y << 16 | x,
+                (to.y as u32) << 16 | to.x as u32,
+                to.height << 16 | to.width,
+            ],
+        )?;
+
+        self.kick();
+        Ok(())
+    }
+
+    fn set_surfaces(&mut self, src: &Surface, dst: &Surface) -> Result<(), ()> {
+        let previous = self.surfaces.replace((*src, *dst));
+        if previous == Some((*src, *dst)) {
+            return Ok(());
+        }
+
+        self.method(
+            SUBCH_SURFACES,
+            SURFACES_FORMAT,
+            &[
+                surface_format(dst.format),
+                src.pitch | dst.pitch << 16,
+                src.offset,
+                dst.offset,
+            ],
+        )?;
+
+        if previous.map(|(_, dst)| dst.format) != Some(dst.format) {
+            self.method(SUBCH_RECT, RECT_COLOR_FORMAT, &[color_format(dst.format)])?;
+        }
+
+        Ok(())
+    }
+
+    /// Queues a notification after the commands submitted so far.
+    /// There is a single notifier, so this waits for the previous fence.
+    pub fn fence(&mut self) -> Result<Fence, ()> {
+        self.wait(Fence(self.sequence))?;
+
+        unsafe {
+            ptr::write_volatile(self.notify_status(), 0xffff_ffff);
+        }
+        self.method(SUBCH_RECT, NOTIFY, &[NOTIFY_WRITE])?;
+        // The notification is written when the next method runs
+        self.method(SUBCH_RECT, NOP, &[0])?;
+        self.kick();
+
+        self.sequence = self.sequence.wrapping_add(1);
+        self.notify_pending = true;
+        Ok(Fence(self.sequence))
+    }
+
+    /// Whether the GPU has finished everything submitted before `fence`
+    pub fn is_done(&mut self, fence: &Fence) -> bool {
+        // Earlier fences were waited for before the notifier was reused
+        if fence.0 != self.sequence || !self.notify_pending {
+            return true;
+        }
+
+        let status = unsafe { ptr::read_volatile(self.notify_status()) } >> 16;
+        if status == NOTIFY_PENDING {
+            return false;
+        }
+
+        if status != 0 {
+            self.failed = true;
+        }
+        self.notify_pending = false;
+        true
+    }
+
+    /// Waits for the GPU to get to `fence`
+    pub fn wait(&mut self, fence: Fence) -> Result<(), ()> {
+        self.wait_until(|accel| accel.is_done(&fence))?;
+        if self.failed {
+            Err(())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Waits for everything submitted so far to complete
+    pub fn finish(&mut self) -> Result<(), ()> {
+        let fence = self.fence()?;
+        self.wait(fence)
+    }
+
+    fn notify_status(&self) -> *mut u32 {
+        (self.notifier.vaddr() + NOTIFY_STATUS) as *mut u32
+    }
+
+    /// Spins rather than sleeping, as callers may hold spinlocks
+    fn wait_until(&mut self, mut done: impl FnMut(&mut Self) -> bool) -> Result<(), ()> {
+        let deadline = time::Deadline::after(TIMEOUT);
+        while !done(self) {
+            if deadline.expired() {
+                self.failed = true;
+                return Err(());
+            }
+            core::hint::spin_loop();
+        }
+
+        Ok(())
+    }
+
+    fn method(&mut self, subch: u32, method: u32, args: &[u32]) -> Result<(), ()> {
+        let words = 1 + args.len() as u32;
+
+        // Leave room for the jump back to the start
+        if self.put + (words + 1) * 4 > self.pushbuf.size() {
+            self.wrap()?;
+        }
+
+        self.push(method_header(subch, method, args.len() as u32));
+        for &arg in args {
+            self.push(arg);
+        }
+
+        Ok(())
+    }
+
+    fn push(&mut self, word: u32) {
+        unsafe {
+            ptr::write_volatile((self.pushbuf.vaddr() + self.put) as *mut u32, word);
+        }
+        self.put += 4;
+    }
+
+    /// Sends the GPU back to the start of the push buffer, which
+    /// can only be reused once it has fetched everything before
+    fn wrap(&mut self) -> Result<(), ()> {
+        self.push(JUMP);
+        self.put = 0;
+        self.kick();
+        self.wait_until(|_| super::lock().user.dma_get.read() == 0)
+    }
+
+    /// Lets the GPU fetch up to `put`
+    fn kick(&mut self) {
+        unsafe {
+            super::lock().user.dma_put.write(self.put);
+        }
+    }
+}
+
+/// Acknowledges PFIFO and PGRAPH interrupts, latching their status
+/// for `report_errors`. Returns whether there were any.
+/// Safety: must only be called from the NV2A interrupt handler
+pub(super) unsafe fn handle_irq(gpu: &mut NV2A) -> bool {
+    let (pfifo, pgraph) = (gpu.pfifo.intr.read(), gpu.pgraph.intr.read());
+    if pfifo == 0 && pgraph == 0 {
+        return false;
+    }
+
+    gpu.pfifo.intr.write(pfifo);
+    gpu.pgraph.intr.write(pgraph);
+    // PGRAPH stops taking methods until its interrupts are handled
+    gpu.pgraph.fifo.write(1);
+
+    PFIFO_INTR.fetch_or(pfifo, Ordering::AcqRel);
+    PGRAPH_INTR.fetch_or(pgraph, Ordering::AcqRel);
+    true
+}
+
+fn take_errors() -> Option<(u32, u32)> {
+    let pfifo = PFIFO_INTR.swap(0, Ordering::AcqRel);
+    let pgraph = PGRAPH_INTR.swap(0, Ordering::AcqRel);
+    if pfifo == 0 && pgraph == 0 {
+        None
+    } else {
+        Some((pfifo, pgraph))
+    }
+}
+
+/// Logs the PFIFO and PGRAPH errors the interrupt handler latched,
+/// as logging isn't safe in interrupt context
+pub async fn report_errors() {
+    loop {
+        let (pfifo, pgraph) = executor::irq_until(super::IRQ, take_errors).await;
+        log::warn!(
+            "NV2A interrupt: PFIFO {:#010x}, PGRAPH {:#010x}",
+            pfifo,
+            pgraph
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn method_headers_pack_count_subchannel_and_method() {
+        assert_eq!(method_header(SUBCH_BLIT, BLIT_POINT_IN, 3), 0x000c_4300);
+        assert_eq!(method_header(0, SET_OBJECT, 1), 0x0004_0000);
+    }
+
+    #[test]
+    fn copies_are_clipped_to_both_surfaces() {
+        let bounds = Rect::new(0, 0, 100, 50);
+
+        // In bounds
+        assert_eq!(
+            clip_copy(bounds, (0, 10), bounds, Rect::new(0, 0, 100, 40)),
+            Some(((0, 10), Rect::new(0, 0, 100, 40)))
+        );
+
+        // Destination off the top left, source off the bottom
+        assert_eq!(
+            clip_copy(bounds, (10, 30), bounds, Rect::new(-5, -5, 20, 30)),
+            Some(((15, 35), Rect::new(0, 0, 15, 15)))
+        );
+
+        assert_eq!(
+            clip_copy(bounds, (100, 0), bounds, Rect::new(0, 0, 10, 10)),
+            None
+        );
+    }
+
+    #[test]
+    fn surfaces_must_be_aligned() {
+        assert!(Surface::new(0x1000, 640, 480, 2560, PixelFormat::Argb8888).is_ok());
+        assert!(Surface::new(0x1000, 720, 480, 1440, PixelFormat::Rgb565).is_err());
+        assert!(Surface::new(0x1020, 640, 480, 2560, PixelFormat::Argb8888).is_err());
+    }
+}
@@ -0,0 +1,73 @@
+use core::ptr;
+
+use super::vram::GpuMemory;
+use super::NV2A;
+use crate::cpu::mmu::MapError;
+use crate::gfx;
+use crate::print::RGBA;
+
+/// Width and height of the cursor image
+pub const SIZE: u32 = 64;
+
+/// Cursor images are 2K aligned, as only the upper address bits are set
+const ALIGN: u32 = 0x800;
+
+/// Hardware cursor image in GPU memory, with the point in it that
+/// `move_to` places. The CRTC blends it over the framebuffer in 32 bit
+/// premultiplied ARGB, whatever the framebuffer format.
+pub struct Cursor {
+    mem: GpuMemory,
+    hotspot: (i32, i32),
+}
+
+/// Scales the color by the alpha, as the CRTC expects
+fn premultiply(px: RGBA) -> u32 {
+    let alpha = px.a() as u32;
+    let scale = |c: u8| c as u32 * alpha / 0xff;
+    alpha << 24 | scale(px.r()) << 16 | scale(px.g()) << 8 | scale(px.b())
+}
+
+impl Cursor {
+    /// Copies `image` into GPU memory, cropped to 64x64,
+    /// with the area outside of it transparent.
+    /// Safety: the kernel mapping must be set up
+    pub unsafe fn new(image: &gfx::Image, hotspot: (i32, i32)) -> Result<Self, MapError> {
+        let mem = GpuMemory::alloc(SIZE * SIZE * 4, ALIGN)?;
+        let dest = mem.vaddr() as *mut u32;
+
+        for y in 0..SIZE {
+            for x in 0..SIZE {
+                let pixel = if x < image.width && y < image.height {
+                    premultiply(image.pixels[(y * image.width + x) as usize])
+                } else {
+                    0
+                };
+                ptr::write_volatile(dest.add((y * SIZE + x) as usize), pixel);
+            }
+        }
+
+        Ok(Self { mem, hotspot })
+    }
+
+    /// Makes this the image of the hardware cursor
+    pub fn install(&self, gpu: &mut NV2A) {
+        gpu.set_cursor_image(self.mem.offset());
+    }
+
+    /// Moves the hardware cursor so that the hotspot is at `x`, `y`
+    pub fn move_to(&self, gpu: &mut NV2A, x: i32, y: i32) {
+        gpu.set_cursor_position(x - self.hotspot.0, y - self.hotspot.1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pixels_are_premultiplied() {
+        assert_eq!(premultiply(RGBA::rgba(0xff, 0x80, 0x00, 0xff)), 0xffff_8000);
+        assert_eq!(premultiply(RGBA::rgba(0xff, 0x80, 0x40, 0x80)), 0x8080_4020);
+        assert_eq!(premultiply(RGBA::rgba(0xff, 0xff, 0xff, 0)), 0);
+    }
+}
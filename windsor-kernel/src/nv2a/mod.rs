@@ -1,10 +1,34 @@
-use super::{cpu::io, encoder};
+use super::{cpu, cpu::io, encoder, gfx};
+use crate::print::RGBA;
+use crate::sync::{OnceCell, SpinLock, SpinLockGuard};
 use autopad::autopad;
 use volatile_register::RW;
 
+pub mod accel;
+pub mod cursor;
+mod pfifo;
+mod pgraph;
+pub mod pll;
 mod pramdac;
 mod prmcio;
 mod prmvio;
+pub mod pvideo;
+pub mod ramin;
+pub mod vblank;
+pub mod vram;
+
+/// Interrupt line assigned to the GPU in `pci::initialize_devices`
+pub const IRQ: u8 = 3;
+
+const PCRTC_INTR_VBLANK: u32 = 0x1;
+
+const PMC_ENABLE_PVIDEO: u32 = 1 << 28;
+
+/// 64x64 32 bit cursor image, in video memory
+const CURSOR_CONFIG_ADDRESS_SPACE_VRAM: u32 = 1 << 8;
+const CURSOR_CONFIG_BPP_32: u32 = 1 << 12;
+const CURSOR_CONFIG_PIXELS_64: u32 = 1 << 16;
+const CURSOR_CONFIG_LINES_64: u32 = 4 << 24;
 
 autopad!(
 #[repr(C)]
@@ -51,6 +75,8 @@ pub struct PCRTC {
     0x800 => pub start: RW<u32>,
     pub config: RW<u32>,
     pub raster: RW<u32>,
+
+    0x810 => pub cursor_config: RW<u32>,
 }
 );
 
@@ -58,21 +84,80 @@ autopad!(
 #[repr(C)]
 pub struct NV2A {
     pub pmc: PMC,
+    0x2000 => pfifo: pfifo::PFIFO,
+    0x8000 => pvideo: pvideo::PVIDEO,
     0xc_0000 => prmvio: prmvio::PRMVIO,
     0x10_0000 => pfb: PFB,
+    0x40_0000 => pgraph: pgraph::PGRAPH,
     0x60_0000 => pub pcrtc: PCRTC,
     0x60_1000 => prmcio: prmcio::PRMCIO,
     0x68_0000 => pramdac: pramdac::PRAMDAC,
+    0x70_0000 => pramin: [RW<u32>; ramin::SIZE as usize / 4],
+    0x80_0000 => user: pfifo::USER,
 }
 );
 
-pub fn get_device<'a>() -> &'a mut NV2A {
-    unsafe { &mut *(0xfd00_0000 as *mut NV2A) }
+/// Register BAR, as assigned by the boot stage
+pub const MMIO_BASE: u32 = 0xfd00_0000;
+pub const MMIO_SIZE: u32 = 0x100_0000;
+
+/// Memory BAR, a window onto system RAM used for scanout
+pub const VRAM_BASE: u32 = 0xf000_0000;
+
+static DEVICE: OnceCell<SpinLock<&'static mut NV2A>> = OnceCell::new();
+
+/// Maps the GPU registers into the kernel address space.
+/// Safety: the kernel mapping must be set up
+pub unsafe fn map_registers() -> Result<(), cpu::mmu::MapError> {
+    if DEVICE.get().is_none() {
+        let regs = &mut *(cpu::mmu::ioremap(MMIO_BASE, MMIO_SIZE)? as *mut NV2A);
+        let _ = DEVICE.set(SpinLock::new(regs));
+    }
+
+    Ok(())
+}
+
+/// Locks the GPU registers, which are shared with the GPU interrupt handler.
+/// The lock disables interrupts, so it must not be held while sleeping.
+pub fn lock() -> SpinLockGuard<'static, &'static mut NV2A> {
+    DEVICE
+        .get()
+        .expect("NV2A registers should be mapped")
+        .lock()
+}
+
+/// Returns the GPU registers without locking, if they have been mapped.
+/// Safety: for the panic screen only, which can't wait on the lock
+pub unsafe fn steal_device() -> Option<&'static mut NV2A> {
+    DEVICE.get().map(|device| &mut **device.steal())
+}
+
+/// Services pending GPU interrupts, registered on the GPU's PIC line
+pub fn handle_irq() {
+    let vblank = {
+        let mut gpu = lock();
+        unsafe {
+            // Engine errors are latched for `accel::report_errors`
+            accel::handle_irq(&mut gpu);
+            vblank::handle_irq(&mut gpu)
+        }
+    };
+
+    if vblank {
+        vblank::wake_waiters();
+    }
 }
 
 impl NV2A {
     pub fn set_video_mode(&mut self, vm: &encoder::VideoModeInfo) {
+        if let Some(pixel_clock) = vm.pixel_clock {
+            self.pramdac
+                .set_pixel_clock(pixel_clock)
+                .expect("Pixel clock should be in VPLL range");
+        }
+
         unsafe {
+            self.pramdac.set_pixel_format(vm.pixel_format);
             self.pramdac.set_horizontal_video_mode(vm);
             self.prmcio.set_horizontal_video_mode(vm);
             self.pramdac.set_vertical_video_mode(vm);
@@ -80,12 +165,59 @@ impl NV2A {
         }
     }
 
+    pub fn set_clock_profile(&mut self, profile: &pll::ClockProfile) {
+        self.pramdac.set_clock_profile(profile);
+    }
+
     pub unsafe fn set_fb(&mut self, fbaddr: u32) {
         //let fbaddr = fbaddr & 0x0fff_ffff;
         self.pcrtc.start.write(fbaddr);
     }
 
-    pub fn init(&mut self, fbaddr: u32) -> encoder::VideoModeInfo {
+    /// Points the hardware cursor at a 64x64 image at `offset`
+    /// in video memory, see `cursor::Cursor`
+    pub fn set_cursor_image(&mut self, offset: u32) {
+        unsafe {
+            self.pcrtc.cursor_config.write(
+                CURSOR_CONFIG_LINES_64
+                    | CURSOR_CONFIG_PIXELS_64
+                    | CURSOR_CONFIG_BPP_32
+                    | CURSOR_CONFIG_ADDRESS_SPACE_VRAM,
+            );
+            self.prmcio.set_cursor_offset(offset);
+        }
+    }
+
+    /// Moves the top left of the hardware cursor to `x`, `y` on screen
+    pub fn set_cursor_position(&mut self, x: i32, y: i32) {
+        unsafe { self.pramdac.set_cursor_position(x, y) };
+    }
+
+    pub fn show_cursor(&mut self) {
+        unsafe { self.prmcio.enable_cursor(true) };
+    }
+
+    pub fn hide_cursor(&mut self) {
+        unsafe { self.prmcio.enable_cursor(false) };
+    }
+
+    /// Scales `overlay` over the framebuffer from the next frame, clipped
+    /// to the video mode. Showing it again moves or rescales it.
+    pub fn show_overlay(
+        &mut self,
+        overlay: &pvideo::Overlay,
+        vm: &encoder::VideoModeInfo,
+    ) -> Result<(), ()> {
+        let screen = gfx::Rect::new(0, 0, vm.width, vm.height);
+        unsafe { self.pvideo.show(overlay, screen) }
+    }
+
+    /// Sets the framebuffer color that color keyed overlays show through
+    pub fn set_overlay_color_key(&mut self, format: gfx::PixelFormat, color: RGBA) {
+        unsafe { self.pvideo.set_color_key(format.encode(color)) };
+    }
+
+    pub fn init(&mut self, fbaddr: u32, video_mode: &encoder::VideoModeInfo) {
         // FIXME: Support 128MB
         unsafe {
             self.set_fb(fbaddr & 0x0fff_ffff);
@@ -93,7 +225,6 @@ impl NV2A {
         self.pfb.init(false);
 
         let encoder = encoder::Model::detect();
-        let av_mode = encoder::AVMode::detect();
 
         self.prmcio.lock(false);
 
@@ -107,10 +238,7 @@ impl NV2A {
         self.prmcio.init();
 
         // FIXME: Set video mode in encoder
-        let video_mode = av_mode
-            .get_video_mode(&encoder)
-            .expect("AV Mode should have video mode");
-        self.set_video_mode(&video_mode);
+        self.set_video_mode(video_mode);
 
         self.prmcio.disable_palette();
         unsafe {
@@ -121,6 +249,12 @@ impl NV2A {
 
         self.prmcio.init_attr();
 
+        unsafe {
+            self.pmc.blk_en.modify(|en| en | PMC_ENABLE_PVIDEO);
+            self.pvideo.init(vram::SIZE);
+        }
+        self.hide_cursor();
+
         unsafe {
             io::write_u8(0x80d8, 4);
             io::write_u8(0x80d6, 5);
@@ -145,6 +279,5 @@ impl NV2A {
         }
 
         // FIXME: Enable encoder output
-        return video_mode;
     }
 }
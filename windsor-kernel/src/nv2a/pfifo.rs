@@ -0,0 +1,106 @@
+use autopad::autopad;
+use volatile_register::RW;
+
+autopad!(
+#[repr(C)]
+pub struct PFIFO {
+    0x040 => pub delay_0: RW<u32>,
+    pub dma_timeslice: RW<u32>,
+
+    0x100 => pub intr: RW<u32>,
+    0x140 => pub intr_en: RW<u32>,
+
+    0x210 => pub ramht: RW<u32>,
+    pub ramfc: RW<u32>,
+    pub ramro: RW<u32>,
+
+    0x500 => pub caches: RW<u32>,
+    pub mode: RW<u32>,
+
+    0x1200 => pub cache1_push0: RW<u32>,
+    pub cache1_push1: RW<u32>,
+
+    0x1220 => pub cache1_dma_push: RW<u32>,
+    pub cache1_dma_fetch: RW<u32>,
+
+    0x122c => pub cache1_dma_instance: RW<u32>,
+
+    0x1240 => pub cache1_dma_put: RW<u32>,
+    pub cache1_dma_get: RW<u32>,
+
+    0x1250 => pub cache1_pull0: RW<u32>,
+}
+);
+
+autopad!(
+/// Per channel submission registers, 64K apart from channel 0
+#[repr(C)]
+pub struct USER {
+    0x40 => pub dma_put: RW<u32>,
+    pub dma_get: RW<u32>,
+}
+);
+
+/// Entries looked up per RAMHT search, fixed at 128
+const RAMHT_SEARCH_128: u32 = 3 << 24;
+/// RAMFC entries hold the extra DMA state NV17 and later keep per channel
+const RAMFC_SIZE_64: u32 = 1 << 16;
+
+/// Interrupts for a method PFIFO couldn't hand to an engine,
+/// and for a push buffer it couldn't parse
+const INTR_CACHE_ERROR: u32 = 1 << 0;
+const INTR_DMA_PUSHER: u32 = 1 << 12;
+
+/// CACHE1_PUSH1 flag for a channel fetching from a push buffer
+const PUSH1_MODE_DMA: u32 = 1 << 8;
+
+/// Fetch 128 bytes at a time, once 128 bytes are queued, with
+/// up to 8 requests in flight. Values from nouveau.
+const DMA_FETCH_TRIG_128: u32 = 0xf << 3;
+const DMA_FETCH_SIZE_128: u32 = 3 << 13;
+const DMA_FETCH_MAX_REQS_8: u32 = 8 << 16;
+
+impl PFIFO {
+    /// Points PFIFO at its tables in instance memory and enables CACHE1.
+    /// `ramht_bits` is log2 of the hash table's entry count.
+    pub unsafe fn init(&mut self, ramht: u32, ramht_bits: u32, ramro: u32, ramfc: u32) {
+        self.delay_0.write(0xff);
+        self.dma_timeslice.write(0x0101_ffff);
+
+        self.ramht
+            .write(RAMHT_SEARCH_128 | ((ramht_bits - 9) << 16) | (ramht >> 8));
+        self.ramro.write(ramro >> 8);
+        self.ramfc.write(RAMFC_SIZE_64 | (ramfc >> 8));
+
+        self.intr.write(0xffff_ffff);
+        self.intr_en.write(INTR_CACHE_ERROR | INTR_DMA_PUSHER);
+
+        self.cache1_push0.write(1);
+        self.cache1_pull0.write(1);
+        self.caches.write(1);
+    }
+
+    /// Makes `chid` the channel in CACHE1, fetching commands from the push
+    /// buffer described by the DMA object at `instance`. Loading CACHE1
+    /// directly skips the context switch through RAMFC, which is only
+    /// needed once there is more than one channel.
+    pub unsafe fn start_dma_channel(&mut self, chid: u32, instance: u32) {
+        self.caches.write(0);
+        self.cache1_push0.write(0);
+        self.cache1_pull0.write(0);
+
+        self.cache1_push1.write(PUSH1_MODE_DMA | chid);
+        self.cache1_dma_instance.write(instance >> 4);
+        self.cache1_dma_put.write(0);
+        self.cache1_dma_get.write(0);
+        self.cache1_dma_fetch
+            .write(DMA_FETCH_TRIG_128 | DMA_FETCH_SIZE_128 | DMA_FETCH_MAX_REQS_8);
+        self.cache1_dma_push.write(1);
+
+        self.mode.modify(|mode| mode | (1 << chid));
+
+        self.cache1_push0.write(1);
+        self.cache1_pull0.write(1);
+        self.caches.write(1);
+    }
+}
@@ -0,0 +1,70 @@
+use autopad::autopad;
+use volatile_register::{RO, RW};
+
+autopad!(
+#[repr(C)]
+pub struct PGRAPH {
+    0x080 => pub debug_0: RW<u32>,
+    pub debug_1: RW<u32>,
+    pub debug_2: RW<u32>,
+    pub debug_3: RW<u32>,
+    pub debug_4: RW<u32>,
+
+    0x100 => pub intr: RW<u32>,
+    0x140 => pub intr_en: RW<u32>,
+    pub ctx_control: RW<u32>,
+    pub ctx_user: RW<u32>,
+
+    0x53c => pub abs_uclip_xmin: RW<u32>,
+    pub abs_uclip_ymin: RW<u32>,
+    pub abs_uclip_xmax: RW<u32>,
+    pub abs_uclip_ymax: RW<u32>,
+
+    0x700 => pub status: RO<u32>,
+    0x710 => pub state: RW<u32>,
+    0x720 => pub fifo: RW<u32>,
+
+    0x780 => pub channel_ctx_table: RW<u32>,
+}
+);
+
+/// The channel in CTX_USER is loaded and current
+const CTX_CONTROL_CHID_VALID: u32 = 1 << 16;
+/// Context switches time out rather than hanging PGRAPH
+const CTX_CONTROL_TIME: u32 = 1 << 8;
+const CTX_CONTROL_DEVICE_ENABLED: u32 = 1 << 28;
+
+/// Interrupts for a method the engine doesn't implement,
+/// and for one it rejected
+const INTR_MISSING_HW: u32 = 1 << 4;
+const INTR_ERROR: u32 = 1 << 20;
+
+impl PGRAPH {
+    /// Resets PGRAPH and makes `chid` its current channel.
+    /// `ctx_table` is the instance address of the table of
+    /// per channel contexts, used for switching channels.
+    pub unsafe fn init(&mut self, ctx_table: u32, chid: u32) {
+        self.intr.write(0xffff_ffff);
+        self.intr_en.write(INTR_MISSING_HW | INTR_ERROR);
+
+        // FIXME: Document these, they are nouveau's NV20 values
+        self.debug_0.write(0xffff_ffff);
+        self.debug_0.write(0x0000_0000);
+        self.debug_1.write(0x0011_8700);
+        self.debug_3.write(0xf3ce_0475);
+        self.debug_4.write(0x0000_0000);
+
+        self.channel_ctx_table.write(ctx_table >> 4);
+        self.ctx_user.write(chid << 24);
+        self.ctx_control
+            .write(CTX_CONTROL_DEVICE_ENABLED | CTX_CONTROL_CHID_VALID | CTX_CONTROL_TIME);
+        self.state.write(0xffff_ffff);
+        self.fifo.write(1);
+
+        // Drawing is clipped before submission, so the user clip covers everything
+        self.abs_uclip_xmin.write(0);
+        self.abs_uclip_ymin.write(0);
+        self.abs_uclip_xmax.write(0x7fff);
+        self.abs_uclip_ymax.write(0x7fff);
+    }
+}
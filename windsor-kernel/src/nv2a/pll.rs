@@ -0,0 +1,261 @@
+use arbitrary_int::u3;
+use bitbybit::bitfield;
+
+/// Frequency of the NV2A reference crystal, in Hz
+pub const CRYSTAL_HZ: u32 = 16_666_666;
+
+/// Layout of the NVPLL/MPLL/VPLL coefficient registers
+/// The output frequency is `crystal * n / m / 2^p`
+#[bitfield(u32, default: 0)]
+#[derive(PartialEq, Eq, Debug)]
+pub struct Coefficients {
+    #[bits(16..=18, rw)]
+    pub p: u3,
+
+    #[bits(8..=15, rw)]
+    pub n: u8,
+
+    #[bits(0..=7, rw)]
+    pub m: u8,
+}
+
+impl Coefficients {
+    /// Output frequency of the PLL in kHz
+    pub fn frequency_khz(&self) -> u32 {
+        if self.m() == 0 {
+            return 0;
+        }
+
+        let vco = (CRYSTAL_HZ as u64) * (self.n() as u64) / (self.m() as u64);
+        ((vco >> self.p().value()) / 1000) as u32
+    }
+}
+
+/// Constraints on the dividers and VCO frequency of a PLL
+pub struct Limits {
+    pub min_m: u8,
+    pub max_m: u8,
+    pub max_p: u8,
+    pub min_vco_khz: u32,
+    pub max_vco_khz: u32,
+}
+
+/// Pixel clock PLL limits, as used by Cromwell
+pub const VPLL_LIMITS: Limits = Limits {
+    min_m: 8,
+    max_m: 14,
+    max_p: 4,
+    min_vco_khz: 128_000,
+    max_vco_khz: 350_000,
+};
+
+/// Core and memory PLL limits
+pub const CLOCK_PLL_LIMITS: Limits = Limits {
+    min_m: 1,
+    max_m: 14,
+    max_p: 4,
+    min_vco_khz: 128_000,
+    max_vco_khz: 1_000_000,
+};
+
+/// Core and memory clocks set at build time through WINDSOR_CLOCKS,
+/// in kHz, in the form `233333,200000`
+const BUILD_CLOCKS: Option<&str> = option_env!("WINDSOR_CLOCKS");
+
+/// Finds the coefficients that best approximate `target_khz`
+/// within the given limits, or None if no combination is in range
+pub fn solve(target_khz: u32, limits: &Limits) -> Option<Coefficients> {
+    let crystal_hz = CRYSTAL_HZ as u64;
+    let mut best: Option<(Coefficients, u32)> = None;
+
+    for p in 0..=limits.max_p {
+        let vco_khz = (target_khz as u64) << p;
+        if vco_khz < limits.min_vco_khz as u64 || vco_khz > limits.max_vco_khz as u64 {
+            continue;
+        }
+
+        for m in limits.min_m..=limits.max_m {
+            // Round to the nearest N, working in Hz to keep precision
+            let n = (vco_khz * 1000 * (m as u64) + crystal_hz / 2) / crystal_hz;
+            if n == 0 || n > 0xff {
+                continue;
+            }
+
+            let coeff = Coefficients::DEFAULT
+                .with_m(m)
+                .with_n(n as u8)
+                .with_p(u3::new(p));
+            let delta = coeff.frequency_khz().abs_diff(target_khz);
+
+            let is_better = match best {
+                Some((_, best_delta)) => delta < best_delta,
+                None => true,
+            };
+
+            if is_better {
+                best = Some((coeff, delta));
+            }
+        }
+    }
+
+    best.map(|(coeff, _)| coeff)
+}
+
+/// Core (NVPLL) and memory (MPLL) clock settings
+pub struct ClockProfile {
+    pub nvpll: Coefficients,
+    pub mpll: Coefficients,
+}
+
+impl ClockProfile {
+    /// Clocks programmed by Cromwell for retail units
+    pub const STOCK: Self = Self {
+        nvpll: Coefficients::new_with_raw_value(0x0001_1c01),
+        mpll: Coefficients::new_with_raw_value(0x0000_7702),
+    };
+
+    pub fn new(core_khz: u32, memory_khz: u32) -> Option<Self> {
+        Some(Self {
+            nvpll: solve(core_khz, &CLOCK_PLL_LIMITS)?,
+            mpll: solve(memory_khz, &CLOCK_PLL_LIMITS)?,
+        })
+    }
+
+    /// Parses `core_khz,memory_khz`, None if it doesn't parse
+    /// or the clocks are out of the PLLs' range
+    pub fn parse(spec: &str) -> Option<Self> {
+        let (core, memory) = spec.split_once(',')?;
+        Self::new(core.trim().parse().ok()?, memory.trim().parse().ok()?)
+    }
+
+    /// The profile set through WINDSOR_CLOCKS, or STOCK without one.
+    /// Err with the setting if it isn't a valid profile.
+    pub fn from_build() -> Result<Self, &'static str> {
+        match BUILD_CLOCKS {
+            Some(spec) => Self::parse(spec).ok_or(spec),
+            None => Ok(Self::STOCK),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_within(actual_khz: u32, expected_khz: u32, tolerance_khz: u32) {
+        let delta = actual_khz.abs_diff(expected_khz);
+        assert!(
+            delta <= tolerance_khz,
+            "{} kHz is not within {} kHz of {} kHz",
+            actual_khz,
+            tolerance_khz,
+            expected_khz
+        );
+    }
+
+    fn assert_in_limits(coeff: &Coefficients, limits: &Limits) {
+        assert!(coeff.m() >= limits.min_m && coeff.m() <= limits.max_m);
+        assert!(coeff.p().value() <= limits.max_p);
+
+        let vco_khz = coeff.frequency_khz() << coeff.p().value();
+        assert!(vco_khz >= limits.min_vco_khz && vco_khz <= limits.max_vco_khz);
+    }
+
+    #[test]
+    fn register_layout() {
+        let coeff = Coefficients::new_with_raw_value(0x0001_1c01);
+        assert_eq!(coeff.m(), 0x01);
+        assert_eq!(coeff.n(), 0x1c);
+        assert_eq!(coeff.p().value(), 1);
+
+        let coeff = Coefficients::DEFAULT
+            .with_m(0x0d)
+            .with_n(0x9d)
+            .with_p(u3::new(3));
+        assert_eq!(coeff.raw_value(), 0x0003_9d0d);
+    }
+
+    #[test]
+    fn stock_clocks() {
+        // Cromwell NVPLL, 233.33MHz core clock
+        assert_eq!(ClockProfile::STOCK.nvpll.frequency_khz(), 233_333);
+    }
+
+    #[test]
+    fn solve_stock_core_clock() {
+        let stock_khz = ClockProfile::STOCK.nvpll.frequency_khz();
+        let coeff = solve(stock_khz, &CLOCK_PLL_LIMITS).unwrap();
+
+        assert_in_limits(&coeff, &CLOCK_PLL_LIMITS);
+        assert_eq!(coeff.frequency_khz(), stock_khz);
+    }
+
+    #[test]
+    fn solve_clock_profile() {
+        let stock = &ClockProfile::STOCK;
+        let profile =
+            ClockProfile::new(stock.nvpll.frequency_khz(), stock.mpll.frequency_khz()).unwrap();
+
+        assert_eq!(profile.nvpll.frequency_khz(), stock.nvpll.frequency_khz());
+        assert_within(profile.mpll.frequency_khz(), stock.mpll.frequency_khz(), 1);
+    }
+
+    #[test]
+    fn parse_clock_profile() {
+        let profile = ClockProfile::parse("233333, 200000").unwrap();
+        assert_eq!(profile.nvpll.frequency_khz(), 233_333);
+        assert_within(profile.mpll.frequency_khz(), 200_000, 1);
+
+        assert!(ClockProfile::parse("233333").is_none());
+        assert!(ClockProfile::parse("fast,200000").is_none());
+        // Beyond the VCO limit
+        assert!(ClockProfile::parse("2000000,200000").is_none());
+    }
+
+    #[test]
+    fn solve_pixel_clocks() {
+        // 480p/576p (27MHz), VGA 640x480 (25.175MHz), 720p (74.25MHz),
+        // 800x600 (40MHz) and 1024x768 (65MHz)
+        for target_khz in [27_000, 25_175, 74_250, 40_000, 65_000] {
+            let coeff = solve(target_khz, &VPLL_LIMITS).unwrap();
+            assert_in_limits(&coeff, &VPLL_LIMITS);
+
+            // Within 0.5%, the VESA pixel clock tolerance
+            assert_within(coeff.frequency_khz(), target_khz, target_khz / 200);
+        }
+    }
+
+    #[test]
+    fn solve_matches_calc_vclock() {
+        // What xf86-video-nv's CalcVClock picks with the same limits,
+        // for 480p/576p, VGA, 720p, 800x600 and 1024x768
+        let expected = [
+            (27_000, 0x0003_b50e),
+            (25_175, 0x0003_910c),
+            (74_250, 0x0001_620b),
+            (40_000, 0x0002_600a),
+            (65_000, 0x0001_4e0a),
+        ];
+
+        for (target_khz, raw) in expected {
+            let coeff = solve(target_khz, &VPLL_LIMITS).unwrap();
+            assert_eq!(coeff.raw_value(), raw, "{} kHz", target_khz);
+        }
+    }
+
+    #[test]
+    fn solve_exact_pixel_clock() {
+        // 16.666MHz * 81 / 10 / 2^2 is 33.749MHz, hit with no error
+        let coeff = solve(33_749, &VPLL_LIMITS).unwrap();
+        assert_eq!(coeff.frequency_khz(), 33_749);
+    }
+
+    #[test]
+    fn solve_out_of_range() {
+        // Above the VCO limit even without post-division
+        assert!(solve(400_000, &VPLL_LIMITS).is_none());
+
+        // Below the VCO limit with maximum post-division
+        assert!(solve(7_000, &VPLL_LIMITS).is_none());
+    }
+}
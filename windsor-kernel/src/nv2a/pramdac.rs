@@ -1,14 +1,18 @@
 use autopad::autopad;
 use volatile_register::RW;
 
-use crate::encoder;
+use super::pll;
+use crate::{encoder, gfx};
 
 autopad!(
 #[repr(C)]
 pub struct PRAMDAC {
+    0x300 => pub cu_start_pos: RW<u32>,
+
     0x500 => pub nvpll: RW<u32>,
     pub mpll: RW<u32>,
     pub vpll: RW<u32>,
+    pub pll_coeff_select: RW<u32>,
 
     0x514 => pub pll_test_counter: RW<u32>,
     0x600 => pub gen_ctl: RW<u32>,
@@ -47,6 +51,13 @@ pub struct PRAMDAC {
 }
 );
 
+const PLL_COEFF_SELECT_SOURCE_PROG_MPLL: u32 = 1 << 8;
+const PLL_COEFF_SELECT_SOURCE_PROG_VPLL: u32 = 1 << 9;
+const PLL_COEFF_SELECT_SOURCE_PROG_NVPLL: u32 = 1 << 10;
+
+/// Scans out 16 bit pixels as R5G6B5 rather than X1R5G5B5
+const GEN_CTL_ALT_MODE_SEL: u32 = 1 << 12;
+
 impl PRAMDAC {
     pub fn init(&mut self, enc: &encoder::Model) {
         unsafe {
@@ -74,6 +85,50 @@ impl PRAMDAC {
         }
     }
 
+    /// Programs VPLL for the given pixel clock and selects it as the
+    /// pixel clock source. Returns the frequency actually achieved.
+    pub fn set_pixel_clock(&mut self, khz: u32) -> Option<u32> {
+        let coeff = pll::solve(khz, &pll::VPLL_LIMITS)?;
+
+        unsafe {
+            self.vpll.write(coeff.raw_value());
+            self.pll_coeff_select
+                .modify(|sel| sel | PLL_COEFF_SELECT_SOURCE_PROG_VPLL);
+        }
+
+        Some(coeff.frequency_khz())
+    }
+
+    pub fn set_clock_profile(&mut self, profile: &pll::ClockProfile) {
+        unsafe {
+            self.nvpll.write(profile.nvpll.raw_value());
+            self.mpll.write(profile.mpll.raw_value());
+            self.pll_coeff_select.modify(|sel| {
+                sel | PLL_COEFF_SELECT_SOURCE_PROG_NVPLL | PLL_COEFF_SELECT_SOURCE_PROG_MPLL
+            });
+        }
+    }
+
+    /// Selects how pixels are laid out in the scanout buffer. The depth
+    /// itself is set through PRMCIO, this picks the 16 bit layout.
+    /// The rest of general control is left as the boot stage set it.
+    pub unsafe fn set_pixel_format(&mut self, format: gfx::PixelFormat) {
+        let alt_mode = match format {
+            gfx::PixelFormat::Rgb565 => GEN_CTL_ALT_MODE_SEL,
+            gfx::PixelFormat::Argb8888 | gfx::PixelFormat::Xrgb1555 => 0,
+        };
+
+        self.gen_ctl
+            .modify(|ctl| (ctl & !GEN_CTL_ALT_MODE_SEL) | alt_mode);
+    }
+
+    /// Moves the top left of the hardware cursor, which
+    /// may be off the top or left edge of the screen
+    pub unsafe fn set_cursor_position(&mut self, x: i32, y: i32) {
+        self.cu_start_pos
+            .write((y as u16 as u32) << 16 | x as u16 as u32);
+    }
+
     pub unsafe fn set_horizontal_video_mode(&mut self, vm: &encoder::VideoModeInfo) {
         self.hdisplay_end.write(vm.crtc_hend - 1);
         self.htotal.write(vm.nvhtotal);
@@ -1,4 +1,4 @@
-use crate::encoder;
+use crate::{encoder, gfx};
 use autopad::autopad;
 use volatile_register::RW;
 
@@ -14,6 +14,15 @@ pub struct PRMCIO {
 }
 );
 
+/// Hardware cursor address, split over three CRTC registers
+const CR_HCUR_ADDR2: u8 = 0x2f;
+const CR_HCUR_ADDR0: u8 = 0x30;
+const CR_HCUR_ADDR1: u8 = 0x31;
+
+/// The cursor image is in video memory rather than instance memory
+const HCUR_ADDR0_VRAM: u8 = 1 << 7;
+const HCUR_ADDR1_ENABLE: u8 = 1 << 0;
+
 impl PRMCIO {
     pub unsafe fn write_attr(&mut self, idx: u8, data: u8) {
         self.vga_attr.write(idx);
@@ -30,6 +39,21 @@ impl PRMCIO {
         self.vga_color_data.read()
     }
 
+    /// Points the hardware cursor at its image, which must be 2K aligned
+    pub unsafe fn set_cursor_offset(&mut self, offset: u32) {
+        let enable = self.read_reg(CR_HCUR_ADDR1) & HCUR_ADDR1_ENABLE;
+
+        self.write_reg(CR_HCUR_ADDR0, HCUR_ADDR0_VRAM | (offset >> 17) as u8 & 0x7f);
+        self.write_reg(CR_HCUR_ADDR1, ((offset >> 11) as u8 & 0x3f) << 2 | enable);
+        self.write_reg(CR_HCUR_ADDR2, (offset >> 24) as u8);
+    }
+
+    pub unsafe fn enable_cursor(&mut self, enable: bool) {
+        let addr1 = self.read_reg(CR_HCUR_ADDR1) & !HCUR_ADDR1_ENABLE;
+        let enable = if enable { HCUR_ADDR1_ENABLE } else { 0 };
+        self.write_reg(CR_HCUR_ADDR1, addr1 | enable);
+    }
+
     pub fn lock(&mut self, lock: bool) {
         let val = if lock { 0x99 } else { 0x57 };
         unsafe { self.write_reg(0x1f, val) };
@@ -108,8 +132,8 @@ impl PRMCIO {
         let tmp = self.read_reg(0x17) & 0x7f;
         self.write_reg(0x17, tmp | 0x80);
 
-        // LINESTRIDE
-        let linestride = vm.width / 8 * vm.pixel_depth;
+        // LINESTRIDE, in units of 8 bytes
+        let linestride = vm.pitch() / 8;
         let tmp = self.read_reg(0x19) & 0x1f;
         self.write_reg(0x19, tmp | ((linestride >> 3) & 0xe0) as u8);
         self.write_reg(0x13, (linestride & 0xff) as u8);
@@ -168,7 +192,11 @@ impl PRMCIO {
         tmp |= (vm.crtc_vstart & 0x400) >> 10;
         self.write_reg(0x25, tmp as u8);
 
-        let tmp = core::cmp::min(vm.pixel_depth, 3) as u8;
+        // PIXEL depth: 2 for both 16 bit layouts, PRAMDAC tells them apart
+        let tmp = match vm.pixel_format {
+            gfx::PixelFormat::Argb8888 => 3,
+            gfx::PixelFormat::Rgb565 | gfx::PixelFormat::Xrgb1555 => 2,
+        };
         self.write_reg(0x28, tmp | 0x80);
 
         let mut tmp = self.read_reg(0x2d) & 0xe0;
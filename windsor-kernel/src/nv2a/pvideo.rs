@@ -0,0 +1,260 @@
+use autopad::autopad;
+use volatile_register::RW;
+
+use crate::gfx::Rect;
+
+autopad!(
+#[repr(C)]
+pub struct PVIDEO {
+    0x100 => pub intr: RW<u32>,
+    0x140 => pub intr_en: RW<u32>,
+
+    0x700 => pub buffer: RW<u32>,
+    pub stop: RW<u32>,
+
+    // Each register has a copy per buffer
+    0x900 => pub base: [RW<u32>; 2],
+    pub limit: [RW<u32>; 2],
+    pub luminance: [RW<u32>; 2],
+    pub chrominance: [RW<u32>; 2],
+    pub offset: [RW<u32>; 2],
+    pub size_in: [RW<u32>; 2],
+    pub point_in: [RW<u32>; 2],
+    pub ds_dx: [RW<u32>; 2],
+    pub dt_dy: [RW<u32>; 2],
+    pub point_out: [RW<u32>; 2],
+    pub size_out: [RW<u32>; 2],
+    pub format: [RW<u32>; 2],
+
+    0xb00 => pub color_key: RW<u32>,
+}
+);
+
+/// Byte order of packed 4:2:2 pixels, UYVY if not set
+const FORMAT_COLOR_YUYV: u32 = 1 << 16;
+/// Pitches are the low 14 bits of the format register
+const PITCH_LIMIT: u32 = 0x4000;
+/// Only show the overlay over framebuffer pixels matching the color key
+const FORMAT_DISPLAY_COLOR_KEY: u32 = 1 << 20;
+
+/// Surface offsets and pitches must be multiples of this
+pub const SURFACE_ALIGN: u32 = 64;
+/// Sizes are 16 bit fields
+const MAX_SIZE: u32 = 0xffff;
+/// The scaler shrinks the source to half its size at most
+const MAX_DOWNSCALE: u64 = 2;
+
+/// Contrast and saturation at 1.0, with no brightness or hue change
+const DEFAULT_LUMINANCE: u32 = 0x1000;
+const DEFAULT_CHROMINANCE: u32 = 0x1000;
+
+/// Only the first buffer is used. The second is for flipping
+/// between surfaces, which nothing needs yet.
+const BUFFER: usize = 0;
+
+/// Packed YUV 4:2:2 surface in video memory, in YUYV byte
+/// order, and where it's scaled to on screen
+#[derive(Copy, Clone, Debug)]
+pub struct Overlay {
+    pub offset: u32,
+    pub width: u32,
+    pub height: u32,
+    /// Bytes from one row to the next
+    pub pitch: u32,
+    /// Area the surface is scaled to, in framebuffer pixels
+    pub dest: Rect,
+    /// Only draw over framebuffer pixels of the color key
+    pub color_keyed: bool,
+}
+
+/// Register values placing an overlay on screen
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+struct Window {
+    /// Top left of the visible source area, 12.4 fixed point
+    point_in: (u32, u32),
+    /// Source pixels per screen pixel, 12.20 fixed point
+    step: (u32, u32),
+    dest: Rect,
+}
+
+/// Checks that the scaler can show `overlay`
+fn validate(overlay: &Overlay) -> Result<(), ()> {
+    let (width, height) = (overlay.width as u64, overlay.height as u64);
+    let (dest_w, dest_h) = (overlay.dest.width as u64, overlay.dest.height as u64);
+
+    if overlay.offset % SURFACE_ALIGN != 0
+        || overlay.pitch % SURFACE_ALIGN != 0
+        || overlay.pitch >= PITCH_LIMIT
+        || overlay.width == 0
+        || overlay.height == 0
+        || overlay.width > MAX_SIZE
+        || overlay.height > MAX_SIZE
+        || dest_w * MAX_DOWNSCALE < width
+        || dest_h * MAX_DOWNSCALE < height
+    {
+        return Err(());
+    }
+
+    Ok(())
+}
+
+/// Clips `overlay` to the screen, scaling the source offset to match.
+/// None if nothing of it is visible.
+fn window(overlay: &Overlay, screen: Rect) -> Option<Window> {
+    let dest = overlay.dest.intersect(&screen);
+    if dest.is_empty() {
+        return None;
+    }
+
+    let (full_w, full_h) = (overlay.dest.width as u64, overlay.dest.height as u64);
+    let (src_w, src_h) = (overlay.width as u64, overlay.height as u64);
+    let (skip_x, skip_y) = (
+        (dest.x - overlay.dest.x) as u64,
+        (dest.y - overlay.dest.y) as u64,
+    );
+
+    Some(Window {
+        point_in: (
+            (skip_x * src_w * 16 / full_w) as u32,
+            (skip_y * src_h * 16 / full_h) as u32,
+        ),
+        step: (
+            ((src_w << 20) / full_w) as u32,
+            ((src_h << 20) / full_h) as u32,
+        ),
+        dest,
+    })
+}
+
+impl PVIDEO {
+    /// Stops the overlay and sets neutral color controls.
+    /// `vram_size` bounds the surfaces it can read.
+    pub unsafe fn init(&mut self, vram_size: u32) {
+        self.stop.write(1);
+        self.intr_en.write(0);
+        self.intr.write(0xffff_ffff);
+
+        for buffer in 0..2 {
+            self.base[buffer].write(0);
+            self.limit[buffer].write(vram_size - 1);
+            self.luminance[buffer].write(DEFAULT_LUMINANCE);
+            self.chrominance[buffer].write(DEFAULT_CHROMINANCE);
+        }
+    }
+
+    /// Shows `overlay`, clipped to `screen`, from the next frame.
+    /// Showing it again with another `dest` moves or rescales it.
+    pub unsafe fn show(&mut self, overlay: &Overlay, screen: Rect) -> Result<(), ()> {
+        validate(overlay)?;
+
+        let Some(window) = window(overlay, screen) else {
+            self.hide();
+            return Ok(());
+        };
+
+        let mut format = overlay.pitch | FORMAT_COLOR_YUYV;
+        if overlay.color_keyed {
+            format |= FORMAT_DISPLAY_COLOR_KEY;
+        }
+
+        let dest = window.dest;
+        self.offset[BUFFER].write(overlay.offset);
+        self.size_in[BUFFER].write(overlay.height << 16 | overlay.width);
+        self.point_in[BUFFER].write(window.point_in.1 << 16 | window.point_in.0);
+        self.ds_dx[BUFFER].write(window.step.0);
+        self.dt_dy[BUFFER].write(window.step.1);
+        self.point_out[BUFFER].write((dest.y as u32) << 16 | dest.x as u32);
+        self.size_out[BUFFER].write(dest.height << 16 | dest.width);
+        self.format[BUFFER].write(format);
+
+        self.stop.write(0);
+        self.buffer.write(1 << (BUFFER * 4));
+        Ok(())
+    }
+
+    pub unsafe fn hide(&mut self) {
+        self.stop.write(1);
+    }
+
+    /// Sets the framebuffer pixel value that color keyed overlays show through
+    pub unsafe fn set_color_key(&mut self, pixel: u32) {
+        self.color_key.write(pixel & 0xff_ffff);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn overlay(dest: Rect) -> Overlay {
+        Overlay {
+            offset: 0,
+            width: 320,
+            height: 240,
+            pitch: 640,
+            dest,
+            color_keyed: false,
+        }
+    }
+
+    #[test]
+    fn scale_steps_are_source_pixels_per_screen_pixel() {
+        let screen = Rect::new(0, 0, 640, 480);
+        let window = window(&overlay(Rect::new(0, 0, 640, 480)), screen).unwrap();
+        assert_eq!(window.point_in, (0, 0));
+        assert_eq!(window.step, (1 << 19, 1 << 19));
+        assert_eq!(window.dest, screen);
+    }
+
+    #[test]
+    fn clipping_moves_the_source_point() {
+        let screen = Rect::new(0, 0, 640, 480);
+        let clipped = window(&overlay(Rect::new(-64, 400, 640, 480)), screen).unwrap();
+        // 64 screen pixels are 32 source pixels, in 12.4 fixed point
+        assert_eq!(clipped.point_in, (32 << 4, 0));
+        assert_eq!(clipped.dest, Rect::new(0, 400, 576, 80));
+
+        assert_eq!(window(&overlay(Rect::new(640, 0, 64, 64)), screen), None);
+    }
+
+    #[test]
+    fn surfaces_the_scaler_cant_read_are_rejected() {
+        let screen = Rect::new(0, 0, 640, 480);
+        assert_eq!(validate(&overlay(screen)), Ok(()));
+
+        let unaligned = Overlay {
+            pitch: 650,
+            ..overlay(screen)
+        };
+        assert_eq!(validate(&unaligned), Err(()));
+
+        let wide_pitch = Overlay {
+            pitch: 0x4000,
+            ..overlay(screen)
+        };
+        assert_eq!(validate(&wide_pitch), Err(()));
+
+        let too_wide = Overlay {
+            width: 0x1_0000,
+            dest: Rect::new(0, 0, 0x1_0000, 480),
+            ..overlay(screen)
+        };
+        assert_eq!(validate(&too_wide), Err(()));
+
+        let too_tall = Overlay {
+            height: 0x1_0000,
+            dest: Rect::new(0, 0, 640, 0x1_0000),
+            ..overlay(screen)
+        };
+        assert_eq!(validate(&too_tall), Err(()));
+    }
+
+    #[test]
+    fn downscaling_is_limited_to_half_size() {
+        // The source is 320x240
+        assert_eq!(validate(&overlay(Rect::new(0, 0, 160, 120))), Ok(()));
+        assert_eq!(validate(&overlay(Rect::new(0, 0, 159, 120))), Err(()));
+        assert_eq!(validate(&overlay(Rect::new(0, 0, 160, 119))), Err(()));
+        assert_eq!(validate(&overlay(Rect::new(0, 0, 0, 0))), Err(()));
+    }
+}
@@ -0,0 +1,189 @@
+use super::{vram, NV2A};
+
+/// Instance memory, which holds the GPU's object and FIFO tables
+pub const SIZE: u32 = 0x10_0000;
+
+/// PRAMIN maps the last megabyte of video memory
+pub const VRAM_OFFSET: u32 = vram::SIZE - SIZE;
+
+/// Hash table from object handles to instances, 512 entries
+pub const RAMHT: u32 = 0x1_0000;
+pub const RAMHT_BITS: u32 = 9;
+const RAMHT_ENTRIES: u32 = 1 << RAMHT_BITS;
+/// Entries PFIFO searches from a handle's slot, as set up by `PFIFO::init`
+const RAMHT_SEARCH: u32 = 128;
+
+/// Channels the per channel tables have room for
+const CHANNELS: u32 = 32;
+
+/// Where PFIFO reports methods it couldn't run
+pub const RAMRO: u32 = 0x1_1200;
+const RAMRO_SIZE: u32 = 0x200;
+/// Per channel PFIFO state, for context switches,
+/// in the 64 byte entries `PFIFO::init` selects
+pub const RAMFC: u32 = 0x1_1400;
+const RAMFC_SIZE: u32 = CHANNELS * 64;
+/// PGRAPH's table of per channel contexts, a word per channel
+pub const CTX_TABLE: u32 = 0x1_1c00;
+const CTX_TABLE_SIZE: u32 = CHANNELS * 4;
+
+/// Objects are allocated after the fixed tables
+const OBJECTS: u32 = 0x1_2000;
+
+const _: () = assert!(RAMHT + RAMHT_ENTRIES * 8 <= RAMRO);
+const _: () = assert!(RAMRO + RAMRO_SIZE <= RAMFC);
+const _: () = assert!(RAMFC + RAMFC_SIZE <= CTX_TABLE);
+const _: () = assert!(CTX_TABLE + CTX_TABLE_SIZE <= OBJECTS);
+
+/// Objects must be 16 byte aligned, as they're addressed in 16 byte units
+const OBJECT_ALIGN: u32 = 0x10;
+
+/// DMA object whose target is video memory
+const DMA_TARGET_VRAM: u32 = 0x3000;
+/// DMA page entry flags for a present, writable page
+const DMA_PAGE_PRESENT: u32 = 1 << 0;
+const DMA_PAGE_WRITABLE: u32 = 1 << 1;
+
+const RAMHT_VALID: u32 = 1 << 31;
+
+/// Class of DMA objects covering a linear range of memory
+pub const CLASS_DMA_IN_MEMORY: u32 = 0x3d;
+
+/// Engine an object's methods are sent to
+#[derive(Copy, Clone)]
+pub enum Engine {
+    Software = 0,
+    Graphics = 1,
+}
+
+/// RAMHT slot for `handle` in channel `chid`
+fn hash(handle: u32, chid: u32) -> u32 {
+    let mut hash = 0;
+    let mut handle = handle;
+    while handle != 0 {
+        hash ^= handle & (RAMHT_ENTRIES - 1);
+        handle >>= RAMHT_BITS;
+    }
+
+    hash ^ (chid << (RAMHT_BITS - 4))
+}
+
+/// Second word of a RAMHT entry, locating the object for PFIFO
+fn ramht_context(instance: u32, engine: Engine, chid: u32) -> u32 {
+    RAMHT_VALID | (chid << 24) | ((engine as u32) << 16) | (instance >> 4)
+}
+
+/// Allocates objects in instance memory and binds them to handles.
+/// Objects live as long as the GPU is initialized, so nothing is freed.
+pub struct Ramin {
+    next: u32,
+}
+
+impl Ramin {
+    /// Clears the fixed tables, leaving every RAMHT entry empty.
+    /// Safety: PFIFO and PGRAPH must not be using instance memory
+    pub unsafe fn new(gpu: &mut NV2A) -> Self {
+        for addr in (RAMHT..OBJECTS).step_by(4) {
+            write(gpu, addr, 0);
+        }
+
+        Self { next: OBJECTS }
+    }
+
+    /// Reserves `size` zeroed bytes, returning their instance address
+    pub unsafe fn alloc(&mut self, gpu: &mut NV2A, size: u32, align: u32) -> Result<u32, ()> {
+        let addr = self.next.next_multiple_of(align.max(OBJECT_ALIGN));
+        let end = addr.checked_add(size).ok_or(())?;
+        if end > SIZE {
+            return Err(());
+        }
+
+        for offset in (addr..end).step_by(4) {
+            write(gpu, offset, 0);
+        }
+
+        self.next = end;
+        Ok(addr)
+    }
+
+    /// Creates a DMA object covering `size` bytes of video memory from `offset`
+    pub unsafe fn new_dma_object(
+        &mut self,
+        gpu: &mut NV2A,
+        offset: u32,
+        size: u32,
+    ) -> Result<u32, ()> {
+        let instance = self.alloc(gpu, 0x10, OBJECT_ALIGN)?;
+
+        // The low bits of the start offset are kept as an adjustment,
+        // the page entry only holds the page
+        let adjust = offset & 0xfff;
+        let page = (offset & !0xfff) | DMA_PAGE_PRESENT | DMA_PAGE_WRITABLE;
+
+        write(
+            gpu,
+            instance,
+            CLASS_DMA_IN_MEMORY | DMA_TARGET_VRAM | (adjust << 20),
+        );
+        write(gpu, instance + 0x4, size - 1);
+        write(gpu, instance + 0x8, page);
+        write(gpu, instance + 0xc, page);
+        Ok(instance)
+    }
+
+    /// Creates a graphics object of `class` in its default state
+    pub unsafe fn new_object(&mut self, gpu: &mut NV2A, class: u32) -> Result<u32, ()> {
+        let instance = self.alloc(gpu, 0x10, OBJECT_ALIGN)?;
+        write(gpu, instance, class);
+        Ok(instance)
+    }
+}
+
+/// Makes the object at `instance` reachable from channel `chid` as `handle`.
+/// Collisions go to the next free entry within PFIFO's search range.
+pub unsafe fn bind(
+    gpu: &mut NV2A,
+    handle: u32,
+    instance: u32,
+    engine: Engine,
+    chid: u32,
+) -> Result<(), ()> {
+    let start = hash(handle, chid);
+    for idx in 0..RAMHT_SEARCH {
+        let entry = RAMHT + ((start + idx) % RAMHT_ENTRIES) * 8;
+        if read(gpu, entry + 4) & RAMHT_VALID == 0 {
+            write(gpu, entry, handle);
+            write(gpu, entry + 4, ramht_context(instance, engine, chid));
+            return Ok(());
+        }
+    }
+
+    Err(())
+}
+
+pub unsafe fn write(gpu: &mut NV2A, addr: u32, value: u32) {
+    gpu.pramin[addr as usize / 4].write(value);
+}
+
+pub fn read(gpu: &NV2A, addr: u32) -> u32 {
+    gpu.pramin[addr as usize / 4].read()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handles_hash_by_folding_and_channel() {
+        assert_eq!(hash(0x1, 0), 0x1);
+        // 9 bits at a time, from the bottom
+        assert_eq!(hash(0x1234_5678, 0), 0x078 ^ 0x02b ^ 0x08d ^ 0x002);
+        assert_eq!(hash(0x1, 1), 0x1 ^ 0x20);
+    }
+
+    #[test]
+    fn ramht_context_locates_the_instance() {
+        assert_eq!(ramht_context(0x1_2340, Engine::Graphics, 0), 0x8001_1234);
+        assert_eq!(ramht_context(0x1_2340, Engine::Software, 2), 0x8200_1234);
+    }
+}
@@ -0,0 +1,119 @@
+use core::future::{poll_fn, Future};
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::task::Poll;
+use core::time::Duration;
+
+use super::NV2A;
+use crate::executor::{self, WakerList};
+use crate::sync::AtomicFlag;
+use crate::thread::WaitQueue;
+use crate::time;
+
+const NO_PENDING_FB: u32 = u32::MAX;
+
+static COUNT: AtomicU32 = AtomicU32::new(0);
+static PENDING_FB: AtomicU32 = AtomicU32::new(NO_PENDING_FB);
+static WAITERS: WaitQueue = WaitQueue::new();
+static WAKERS: WakerList = WakerList::new();
+/// Set on vblanks that leave no scanout address queued
+static FLIPPED: AtomicFlag = AtomicFlag::new(true);
+
+/// Number of vblanks seen since interrupts were enabled
+pub fn count() -> u32 {
+    COUNT.load(Ordering::Acquire)
+}
+
+/// Sleeps until the next vblank, returning the new vblank count
+pub fn wait() -> u32 {
+    let start = count();
+    WAITERS.wait_until(|| count() != start);
+    count()
+}
+
+/// Completes on the next vblank with the new vblank count
+pub fn next() -> impl Future<Output = u32> {
+    let start = count();
+    poll_fn(move |cx| {
+        if count() != start {
+            return Poll::Ready(count());
+        }
+
+        WAKERS.register(cx.waker());
+
+        // The vblank may have arrived before the waker was registered
+        if count() != start {
+            Poll::Ready(count())
+        } else {
+            Poll::Pending
+        }
+    })
+}
+
+/// Vblanks to time when measuring the refresh rate
+const MEASURED_VBLANKS: u32 = 60;
+
+/// Logs the refresh rate measured over MEASURED_VBLANKS vblanks,
+/// or warns if vblank interrupts aren't arriving
+pub async fn report_refresh_rate() {
+    let measure = async {
+        let start = next().await;
+        let start_ticks = time::ticks();
+        while next().await.wrapping_sub(start) < MEASURED_VBLANKS {}
+        time::ticks() - start_ticks
+    };
+
+    match executor::timeout(Duration::from_secs(2), measure).await {
+        Some(ticks) => log::info!(
+            "Display refresh {} Hz",
+            MEASURED_VBLANKS as u64 * time::TICK_HZ as u64 / ticks.max(1)
+        ),
+        None => log::warn!("No vblank interrupts from the NV2A"),
+    }
+}
+
+/// Queues a scanout address to be written to PCRTC on the next vblank,
+/// replacing any address that has not been latched yet
+pub fn queue_fb(addr: u32) {
+    FLIPPED.clear();
+    PENDING_FB.store(addr, Ordering::Release);
+}
+
+/// Whether a queued scanout address is still waiting for vblank
+pub fn fb_pending() -> bool {
+    PENDING_FB.load(Ordering::Acquire) != NO_PENDING_FB
+}
+
+/// Sleeps until the queued scanout address has been latched
+pub fn wait_flip() {
+    while fb_pending() {
+        FLIPPED.wait();
+    }
+}
+
+/// Acknowledges a PCRTC interrupt, returning whether one was pending
+/// Safety: must only be called from the NV2A interrupt handler
+pub(super) unsafe fn handle_irq(gpu: &mut NV2A) -> bool {
+    if gpu.pcrtc.intr.read() & super::PCRTC_INTR_VBLANK == 0 {
+        return false;
+    }
+
+    gpu.pcrtc.intr.write(super::PCRTC_INTR_VBLANK);
+
+    let fb = PENDING_FB.swap(NO_PENDING_FB, Ordering::AcqRel);
+    if fb != NO_PENDING_FB {
+        gpu.set_fb(fb);
+    }
+
+    COUNT.fetch_add(1, Ordering::AcqRel);
+    true
+}
+
+/// Wakes threads in `wait` and `wait_flip` and tasks awaiting `next`
+pub(super) fn wake_waiters() {
+    if !fb_pending() {
+        FLIPPED.notify();
+    }
+
+    WAITERS.wake_all();
+    WAKERS.wake_all();
+}
@@ -0,0 +1,66 @@
+use crate::cpu::mmu::{self, ContiguousPhysicalMemory, MapError, PhysramAllocator};
+use crate::physram::{self, Zone};
+
+use super::VRAM_BASE;
+
+/// The GPU sees all of RAM as video memory.
+/// FIXME: Support 128MB
+pub const SIZE: u32 = 0x400_0000;
+
+/// A physically contiguous block of RAM owned by the GPU, taken from the
+/// allocator's GPU zone and mapped uncached through the memory BAR
+pub struct GpuMemory {
+    mem: ContiguousPhysicalMemory,
+    vaddr: u32,
+}
+
+impl GpuMemory {
+    /// Allocates at least `size` bytes, aligned to `align` bytes in video memory.
+    /// Safety: the kernel mapping must be set up
+    pub unsafe fn alloc(size: u32, align: u32) -> Result<Self, MapError> {
+        let frames = size.div_ceil(mmu::PAGE_SIZE);
+
+        let mem = physram::ALLOCATOR
+            .lock()
+            .alloc_contiguous_in(Zone::Gpu, frames, align.max(mmu::PAGE_SIZE))
+            .ok_or(MapError::OutOfMemory)?;
+
+        match mmu::ioremap(VRAM_BASE | mem.addr(), frames * mmu::PAGE_SIZE) {
+            Ok(vaddr) => Ok(Self { mem, vaddr }),
+            Err(e) => {
+                physram::ALLOCATOR.lock().free_contiguous(mem);
+                Err(e)
+            }
+        }
+    }
+
+    /// Offset of the block in video memory, as programmed into the GPU
+    pub fn offset(&self) -> u32 {
+        self.mem.addr()
+    }
+
+    /// Kernel virtual address of the block
+    pub fn vaddr(&self) -> u32 {
+        self.vaddr
+    }
+
+    pub fn size(&self) -> u32 {
+        self.mem.frames() * mmu::PAGE_SIZE
+    }
+
+    /// Unmaps the block and returns it to the allocator.
+    /// Safety: the GPU must no longer be using the block
+    pub unsafe fn free(self) {
+        mmu::iounmap(self.vaddr, self.size()).expect("GPU memory should be mapped");
+        physram::ALLOCATOR.lock().free_contiguous(self.mem);
+    }
+}
+
+/// Marks video memory that the GPU uses outside of any `GpuMemory`
+/// block, so the allocator never hands it out
+pub fn reserve(offset: u32, size: u32) {
+    let mut pm = physram::ALLOCATOR.lock();
+    for page in (offset..offset + size).step_by(mmu::PAGE_SIZE as usize) {
+        pm.mark_allocated(page);
+    }
+}
@@ -0,0 +1,210 @@
+use core::fmt::{self, Write};
+use core::ops::Range;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::cpu::{self, exception::ExceptionFrame};
+use crate::sync::OnceCell;
+use crate::{encoder, heap, ksyms, logger, nv2a, print, thread};
+
+/// Frames printed before the backtrace is cut short
+const MAX_FRAMES: usize = 24;
+
+/// Before the kernel takes over the page tables, the panic screen
+/// draws at the top of RAM through the bootstrap identity mapping
+const EARLY_PANIC_FB_OFFSET: u32 = 60 * 1024 * 1024;
+
+/// Buffer the panic screen draws into, along with the video mode it was
+/// set up for, so that a panic doesn't have to talk to the encoder
+struct PanicFb {
+    addr: u32,
+    offset: u32,
+    video_mode: encoder::VideoModeInfo,
+}
+
+static PANIC_FB: OnceCell<PanicFb> = OnceCell::new();
+
+/// Set once a panic starts, so that a panic while
+/// printing the report doesn't start another
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+/// Sets the buffer the panic screen draws into, given by its virtual
+/// address and video memory offset. Only the first call has an effect.
+pub fn set_framebuffer(addr: u32, offset: u32, video_mode: &encoder::VideoModeInfo) {
+    let _ = PANIC_FB.set(PanicFb {
+        addr,
+        offset,
+        video_mode: *video_mode,
+    });
+}
+
+/// Clears the panic framebuffer and shows it, returning a printer for it.
+/// Until the kernel takes over the page tables, the bootstrap identity
+/// mapping of video memory is live.
+fn panic_screen() -> Option<print::VGAPrinter> {
+    let (addr, offset, video_mode) = if let Some(fb) = PANIC_FB.get() {
+        (fb.addr, fb.offset, fb.video_mode)
+    } else if cpu::mmu::KERNEL_MAPPING.get().is_none() {
+        // Nothing has touched the encoder yet this early, so asking it is safe
+        let encoder = encoder::Model::detect();
+        let video_mode = encoder::AVMode::detect().get_video_mode(&encoder)?;
+        (
+            nv2a::VRAM_BASE | EARLY_PANIC_FB_OFFSET,
+            EARLY_PANIC_FB_OFFSET,
+            video_mode,
+        )
+    } else {
+        return None;
+    };
+
+    // The panic screen always draws into the first buffer,
+    // which may not be the one currently scanned out
+    crate::clear_screen(addr as *mut u8, &video_mode, print::COLOR_WHITE);
+    if let Some(gpu) = unsafe { nv2a::steal_device() } {
+        unsafe { gpu.set_fb(offset) };
+    }
+
+    let mut printer = print::VGAPrinter::new(addr as *mut u8, &video_mode);
+    printer.set_color(print::COLOR_BLACK);
+    Some(printer)
+}
+
+/// Writes the report to the panic screen and mirrors it to the log
+struct Report {
+    screen: Option<print::VGAPrinter>,
+}
+
+impl fmt::Write for Report {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if let Some(screen) = self.screen.as_mut() {
+            screen.write_str(s)?;
+        }
+
+        unsafe { logger::write_panic(s) };
+        Ok(())
+    }
+}
+
+/// Return addresses found by following the saved frame pointers,
+/// for as long as they stay within the stack
+struct Frames {
+    ebp: u32,
+    stack: Range<u32>,
+}
+
+impl Iterator for Frames {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<u32> {
+        let ebp = self.ebp;
+        let frame_end = ebp.checked_add(8);
+        if ebp % 4 != 0
+            || ebp < self.stack.start
+            || frame_end.map_or(true, |end| end > self.stack.end)
+        {
+            return None;
+        }
+
+        let (saved_ebp, ret) = unsafe { (*(ebp as *const u32), *((ebp + 4) as *const u32)) };
+
+        // Callers' frames are always further up the stack,
+        // anything else means the chain is corrupt or has ended
+        self.ebp = if saved_ebp > ebp { saved_ebp } else { 0 };
+
+        if ret == 0 {
+            None
+        } else {
+            Some(ret)
+        }
+    }
+}
+
+fn write_backtrace(out: &mut impl Write, exception: Option<&ExceptionFrame>) -> fmt::Result {
+    let stack =
+        unsafe { thread::steal_current() }.map_or_else(crate::kernel_stack, |(_, stack)| stack);
+
+    // Start from the faulting code rather than the exception handler
+    let (eip, ebp) = match exception {
+        Some(frame) => (Some(frame.eip), frame.ebp),
+        None => {
+            let ebp: u32;
+            unsafe { core::arch::asm!("mov {}, ebp", out(reg) ebp) };
+            (None, ebp)
+        }
+    };
+
+    let symbols = ksyms::kernel();
+    writeln!(out, "\nBacktrace:")?;
+
+    // Return addresses point past the call, which may
+    // be the last instruction of the calling function
+    let frames = eip
+        .into_iter()
+        .chain(Frames { ebp, stack }.map(|ret| ret - 1))
+        .take(MAX_FRAMES);
+
+    for (idx, addr) in frames.enumerate() {
+        write!(out, "{:>3}: {:08x}", idx, addr)?;
+        match symbols.as_ref().and_then(|symbols| symbols.lookup(addr)) {
+            Some(symbol) => writeln!(out, "  {}+{:#x}", symbol.name, addr - symbol.addr)?,
+            None => writeln!(out)?,
+        }
+    }
+
+    if symbols.is_none() {
+        writeln!(out, "No symbol table, build with build-tool to embed one")?;
+    }
+
+    Ok(())
+}
+
+fn write_report(out: &mut impl Write, info: &PanicInfo) -> fmt::Result {
+    writeln!(out, "Kernel panic!\n")?;
+
+    match info.location() {
+        Some(location) => write!(
+            out,
+            "panicked at {}:{}:{}",
+            location.file(),
+            location.line(),
+            location.column()
+        )?,
+        None => write!(out, "panicked")?,
+    }
+
+    if let Some((name, _)) = unsafe { thread::steal_current() } {
+        write!(out, " in thread '{}'", name)?;
+    }
+    writeln!(out, ":")?;
+
+    if let Some(message) = info.message() {
+        writeln!(out, "{}", message)?;
+    }
+
+    unsafe { heap::dump_failure(out)? };
+
+    let exception = cpu::exception::last_exception();
+    if let Some(frame) = &exception {
+        writeln!(out)?;
+        frame.dump(out)?;
+    }
+
+    write_backtrace(out, exception.as_ref())
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    // Nothing else may run, the system is in an unknown state
+    cpu::cli();
+
+    if PANICKING.swap(true, Ordering::AcqRel) {
+        loop {}
+    }
+
+    let mut report = Report {
+        screen: panic_screen(),
+    };
+    let _ = write_report(&mut report, info);
+
+    loop {}
+}
@@ -1,135 +1,504 @@
 use crate::cpu::mmu::{ContiguousPhysicalMemory, PhysramAllocator};
+use crate::sync::SpinLock;
 
-fn free_bit(int: u32) -> Option<u8> {
-    if int == 0xffff_ffff {
-        return None;
-    }
+const MB_BYTES: u32 = 1 << 20;
+const FRAME_BYTES: u32 = 0x1000;
 
-    for i in 0..32 {
-        if (int >> i) & 1 == 0 {
-            return Some(i);
-        }
-    }
+// FIXME: Account for 128MB
+const FRAME_COUNT: usize = (64 * MB_BYTES / FRAME_BYTES) as usize;
 
-    None
-}
+/// Largest block is 2^MAX_ORDER frames, one 4 MiB large page.
+/// Larger contiguous requests take a run of whole blocks.
+pub const MAX_ORDER: usize = 10;
+const ORDERS: usize = MAX_ORDER + 1;
+const MAX_BLOCK_FRAMES: usize = 1 << MAX_ORDER;
 
-#[inline(always)]
-fn get_bit(val: u32, bit: u8) -> bool {
-    (val >> bit) & 1 == 1
-}
+const NONE: u16 = u16::MAX;
 
-#[inline(always)]
-fn set_bit(val: u32, bit: u8) -> u32 {
-    val | (1 << bit)
-}
+/// Set in `state` for the first frame of a free block
+const FREE: u8 = 0x80;
 
-#[inline(always)]
-fn clear_bit(val: u32, bit: u8) -> u32 {
-    val & !(1 << bit)
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Zone {
+    /// Low memory, reachable by legacy DMA engines
+    Dma,
+    General,
+    /// Top of RAM, reserved for scanout buffers and other GPU memory
+    Gpu,
 }
 
-const MB_BYTES: u32 = 1 << 20;
-const FRAME_BYTES: u32 = 0x1000;
+pub const ZONE_COUNT: usize = 3;
 
-const FRAME_COUNT: usize = (64 * MB_BYTES / FRAME_BYTES) as usize;
-const BITMAP_SIZE: usize = FRAME_COUNT / 32;
+/// Zone boundaries, in bytes. Each zone is a whole number
+/// of maximum-order blocks, so buddies never cross zones.
+const ZONE_RANGES: [(u32, u32); ZONE_COUNT] = [
+    (0, 16 * MB_BYTES),
+    (16 * MB_BYTES, 48 * MB_BYTES),
+    (48 * MB_BYTES, 64 * MB_BYTES),
+];
+
+/// Zones that plain `alloc` draws from, in order of preference
+const DEFAULT_ZONES: [Zone; 2] = [Zone::General, Zone::Dma];
+
+impl Zone {
+    fn of_frame(frame: usize) -> Zone {
+        let paddr = (frame as u32) * FRAME_BYTES;
+        if paddr < ZONE_RANGES[1].0 {
+            Zone::Dma
+        } else if paddr < ZONE_RANGES[2].0 {
+            Zone::General
+        } else {
+            Zone::Gpu
+        }
+    }
+}
 
-pub struct BitmapAlloc {
-    map: [u32; BITMAP_SIZE],
+/// Binary buddy allocator over physical frames. Free blocks of each order
+/// are kept in per-zone doubly linked lists threaded through the
+/// `next`/`prev` arrays, so no physical memory needs to be mapped.
+pub struct BuddyAlloc {
+    state: [u8; FRAME_COUNT],
+    next: [u16; FRAME_COUNT],
+    prev: [u16; FRAME_COUNT],
+    heads: [[u16; ORDERS]; ZONE_COUNT],
+    free_frames: [usize; ZONE_COUNT],
+    ready: bool,
 }
 
-impl BitmapAlloc {
-    pub fn new() -> Self {
+/// Physical frame allocator used by the kernel
+pub static ALLOCATOR: SpinLock<BuddyAlloc> = SpinLock::new(BuddyAlloc::new());
+
+impl BuddyAlloc {
+    /// Creates an allocator with all of RAM free. The free lists are
+    /// built on first use, so the allocator can live in .bss.
+    pub const fn new() -> Self {
         Self {
-            map: [0; BITMAP_SIZE],
+            state: [0; FRAME_COUNT],
+            next: [0; FRAME_COUNT],
+            prev: [0; FRAME_COUNT],
+            heads: [[0; ORDERS]; ZONE_COUNT],
+            free_frames: [0; ZONE_COUNT],
+            ready: false,
         }
     }
 
-    // paddr -> (map index, u32 index)
-    fn addr_to_idx(paddr: u32) -> (usize, u8) {
-        let frame_idx = paddr >> 12;
+    fn ensure_ready(&mut self) {
+        if self.ready {
+            return;
+        }
+
+        self.heads = [[NONE; ORDERS]; ZONE_COUNT];
+        for (start, end) in ZONE_RANGES {
+            let start = (start / FRAME_BYTES) as usize;
+            let end = (end / FRAME_BYTES) as usize;
 
-        let idx = frame_idx / 32;
-        let bit = frame_idx % 32;
-        (idx as usize, bit as u8)
+            // Pushing in reverse keeps each list in address order
+            for frame in (start..end).step_by(MAX_BLOCK_FRAMES).rev() {
+                self.push(frame, MAX_ORDER);
+            }
+        }
+
+        self.ready = true;
     }
-}
 
-unsafe impl PhysramAllocator for BitmapAlloc {
-    fn free(&mut self, paddr: u32) {
-        let (idx, bit) = Self::addr_to_idx(paddr);
+    /// Number of free frames left in a zone
+    pub fn free_frames(&mut self, zone: Zone) -> usize {
+        self.ensure_ready();
+        self.free_frames[zone as usize]
+    }
 
-        let map = &mut self.map[idx];
-        *map = clear_bit(*map, bit);
+    fn is_free_block(&self, frame: usize, order: usize) -> bool {
+        self.state[frame] == FREE | order as u8
     }
 
-    fn alloc(&mut self) -> Option<u32> {
-        for (idx, map) in self.map.iter_mut().enumerate() {
-            if let Some(bit) = free_bit(*map) {
-                *map = set_bit(*map, bit);
-
-                let idx = idx as u32;
-                let bit = bit as u32;
-                let frame_idx = idx * 32 + bit;
-                return Some(frame_idx << 12);
-            }
+    fn push(&mut self, frame: usize, order: usize) {
+        let zone = Zone::of_frame(frame) as usize;
+        let head = self.heads[zone][order];
+
+        self.state[frame] = FREE | order as u8;
+        self.prev[frame] = NONE;
+        self.next[frame] = head;
+        if head != NONE {
+            self.prev[head as usize] = frame as u16;
         }
 
-        None
+        self.heads[zone][order] = frame as u16;
+        self.free_frames[zone] += 1 << order;
     }
 
-    fn mark_allocated(&mut self, paddr: u32) {
-        let (idx, bit) = Self::addr_to_idx(paddr);
+    fn remove(&mut self, frame: usize, order: usize) {
+        let zone = Zone::of_frame(frame) as usize;
+        let (prev, next) = (self.prev[frame], self.next[frame]);
+
+        if prev == NONE {
+            self.heads[zone][order] = next;
+        } else {
+            self.next[prev as usize] = next;
+        }
+        if next != NONE {
+            self.prev[next as usize] = prev;
+        }
 
-        let map = &mut self.map[idx];
-        *map = set_bit(*map, bit);
+        self.state[frame] = 0;
+        self.free_frames[zone] -= 1 << order;
     }
 
-    fn alloc_contiguous(&mut self, frames: u32) -> Option<ContiguousPhysicalMemory> {
-        let mut start: Option<(usize, u8)> = None;
+    /// Takes a block of the given order from a zone, splitting a larger
+    /// block if no block of that size is free
+    fn alloc_order(&mut self, zone: Zone, order: usize) -> Option<usize> {
+        self.ensure_ready();
+        let heads = &self.heads[zone as usize];
+        let found = (order..ORDERS).find(|&o| heads[o] != NONE)?;
 
-        for idx in 0..self.map.len() {
-            let ent = self.map[idx];
-            if ent == 0xffff_ffff {
-                start = None;
-                continue;
+        let frame = heads[found] as usize;
+        self.remove(frame, found);
+
+        // Return the upper halves of the split block to the free lists
+        for o in (order..found).rev() {
+            self.push(frame + (1 << o), o);
+        }
+
+        Some(frame)
+    }
+
+    /// Returns a block to the free lists, merging it with its buddy
+    /// for as long as the buddy is free too
+    fn free_order(&mut self, mut frame: usize, mut order: usize) {
+        while order < MAX_ORDER {
+            let buddy = frame ^ (1 << order);
+            if !self.is_free_block(buddy, order) {
+                break;
             }
 
-            for bit in 0..32 {
-                if get_bit(ent, bit) {
-                    start = None;
-                }
+            self.remove(buddy, order);
+            frame = frame.min(buddy);
+            order += 1;
+        }
 
-                let (start_idx, start_bit) = match start {
-                    None => {
-                        start = Some((idx, bit));
-                        (idx, bit)
-                    }
-                    Some(start) => start,
-                };
-
-                let frames_allocated = (32 * (idx - start_idx)) as u32 + (bit as u32);
-                if frames_allocated == frames {
-                    let start_addr = 32 * start_idx as u32 + start_bit as u32;
-                    let start_addr = start_addr << 12;
-                    return Some(ContiguousPhysicalMemory::new(start_addr, frames));
+        self.push(frame, order);
+    }
+
+    /// Takes `blocks` adjacent free maximum-order blocks from a zone,
+    /// for requests larger than any single block
+    fn alloc_run(&mut self, zone: Zone, blocks: usize) -> Option<usize> {
+        self.ensure_ready();
+        let (start, end) = ZONE_RANGES[zone as usize];
+        let (start, end) = ((start / FRAME_BYTES) as usize, (end / FRAME_BYTES) as usize);
+
+        let mut run_start = start;
+        for frame in (start..end).step_by(MAX_BLOCK_FRAMES) {
+            if !self.is_free_block(frame, MAX_ORDER) {
+                run_start = frame + MAX_BLOCK_FRAMES;
+                continue;
+            }
+
+            if (frame - run_start) / MAX_BLOCK_FRAMES + 1 == blocks {
+                for block in (run_start..=frame).step_by(MAX_BLOCK_FRAMES) {
+                    self.remove(block, MAX_ORDER);
                 }
+                return Some(run_start);
             }
         }
 
         None
     }
 
+    /// Frees `count` frames from `frame` as the largest aligned blocks
+    /// that fit the range
+    fn free_range(&mut self, mut frame: usize, count: usize) {
+        self.ensure_ready();
+        let end = frame + count;
+        while frame < end {
+            let mut order = (frame.trailing_zeros() as usize).min(MAX_ORDER);
+            while frame + (1 << order) > end {
+                order -= 1;
+            }
+
+            self.free_order(frame, order);
+            frame += 1 << order;
+        }
+    }
+
+    /// Allocates `frames` physically contiguous frames from a zone,
+    /// starting at a multiple of `align` bytes
+    pub fn alloc_contiguous_in(
+        &mut self,
+        zone: Zone,
+        frames: u32,
+        align: u32,
+    ) -> Option<ContiguousPhysicalMemory> {
+        if frames == 0 {
+            return None;
+        }
+
+        let align_frames = (align / FRAME_BYTES).max(1);
+        let order = frames
+            .next_power_of_two()
+            .max(align_frames.next_power_of_two())
+            .trailing_zeros() as usize;
+
+        // Blocks are naturally aligned to their size, so only
+        // the unused tail of the block needs to be given back
+        let (frame, taken) = if order <= MAX_ORDER {
+            (self.alloc_order(zone, order)?, 1 << order)
+        } else if align_frames as usize <= MAX_BLOCK_FRAMES {
+            let blocks = (frames as usize).div_ceil(MAX_BLOCK_FRAMES);
+            (self.alloc_run(zone, blocks)?, blocks * MAX_BLOCK_FRAMES)
+        } else {
+            return None;
+        };
+
+        let used = frames as usize;
+        self.free_range(frame + used, taken - used);
+
+        Some(ContiguousPhysicalMemory::new(
+            (frame as u32) * FRAME_BYTES,
+            frames,
+        ))
+    }
+
+    /// Allocates a single frame from a specific zone
+    pub fn alloc_in(&mut self, zone: Zone) -> Option<u32> {
+        let frame = self.alloc_order(zone, 0)?;
+        Some((frame as u32) * FRAME_BYTES)
+    }
+
+    fn frame_index(paddr: u32) -> Option<usize> {
+        let frame = (paddr / FRAME_BYTES) as usize;
+        (frame < FRAME_COUNT).then_some(frame)
+    }
+}
+
+unsafe impl PhysramAllocator for BuddyAlloc {
+    fn alloc(&mut self) -> Option<u32> {
+        DEFAULT_ZONES.iter().find_map(|&zone| self.alloc_in(zone))
+    }
+
+    fn free(&mut self, paddr: u32) {
+        if let Some(frame) = Self::frame_index(paddr) {
+            self.free_range(frame, 1);
+        }
+    }
+
+    /// Removes a frame from the free lists, splitting the
+    /// free block that contains it
+    fn mark_allocated(&mut self, paddr: u32) {
+        let frame = match Self::frame_index(paddr) {
+            Some(frame) => frame,
+            None => return,
+        };
+
+        self.ensure_ready();
+        let found = (0..ORDERS).find(|&o| self.is_free_block(frame & !((1 << o) - 1), o));
+        let mut order = match found {
+            Some(order) => order,
+            // Already allocated
+            None => return,
+        };
+
+        let mut block = frame & !((1 << order) - 1);
+        self.remove(block, order);
+
+        // Keep halving, freeing the half the frame isn't in
+        while order > 0 {
+            order -= 1;
+            let half = 1 << order;
+            if frame < block + half {
+                self.push(block + half, order);
+            } else {
+                self.push(block, order);
+                block += half;
+            }
+        }
+    }
+
+    fn alloc_contiguous(&mut self, frames: u32) -> Option<ContiguousPhysicalMemory> {
+        DEFAULT_ZONES
+            .iter()
+            .find_map(|&zone| self.alloc_contiguous_in(zone, frames, FRAME_BYTES))
+    }
+
     fn free_contiguous(&mut self, mem: ContiguousPhysicalMemory) {
-        let (start_idx, start_bit) = Self::addr_to_idx(mem.addr());
+        if let Some(frame) = Self::frame_index(mem.addr()) {
+            self.free_range(frame, mem.frames() as usize);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn zone_range(zone: Zone) -> (u32, u32) {
+        ZONE_RANGES[zone as usize]
+    }
+
+    fn new_alloc() -> Box<BuddyAlloc> {
+        Box::new(BuddyAlloc::new())
+    }
+
+    fn total_free(alloc: &mut BuddyAlloc) -> usize {
+        [Zone::Dma, Zone::General, Zone::Gpu]
+            .iter()
+            .map(|&zone| alloc.free_frames(zone))
+            .sum()
+    }
+
+    #[test]
+    fn starts_fully_free() {
+        let mut alloc = new_alloc();
+        assert_eq!(total_free(&mut alloc), FRAME_COUNT);
+        assert_eq!(alloc.free_frames(Zone::Dma), 16 * 256);
+        assert_eq!(alloc.free_frames(Zone::Gpu), 16 * 256);
+    }
+
+    #[test]
+    fn alloc_prefers_general_zone() {
+        let mut alloc = new_alloc();
+        let paddr = alloc.alloc().unwrap();
+
+        let (start, end) = zone_range(Zone::General);
+        assert!(paddr >= start && paddr < end);
+        assert_eq!(alloc.free_frames(Zone::General), (32 * 256) - 1);
+    }
+
+    #[test]
+    fn single_frames_are_unique() {
+        let mut alloc = new_alloc();
+        let mut frames: Vec<u32> = (0..2048).map(|_| alloc.alloc().unwrap()).collect();
 
-        for frame in 0..mem.frames() {
-            let idx = start_idx + (frame / 32) as usize;
-            let bit = start_bit + (frame % 32) as u8;
+        frames.sort();
+        frames.dedup();
+        assert_eq!(frames.len(), 2048);
+    }
+
+    #[test]
+    fn free_coalesces_back_to_large_blocks() {
+        let mut alloc = new_alloc();
+        let frames: Vec<u32> = (0..1000).map(|_| alloc.alloc().unwrap()).collect();
 
-            let ent = &mut self.map[idx];
-            *ent = clear_bit(*ent, bit);
+        for paddr in frames.iter().rev() {
+            alloc.free(*paddr);
         }
+
+        assert_eq!(total_free(&mut alloc), FRAME_COUNT);
+        for zone in alloc.heads.iter() {
+            assert!(zone[..MAX_ORDER].iter().all(|&head| head == NONE));
+        }
+    }
+
+    #[test]
+    fn contiguous_survives_fragmentation() {
+        let mut alloc = new_alloc();
+
+        // Free every other frame of the whole zone
+        let (start, end) = zone_range(Zone::General);
+        let mut frames = Vec::new();
+        while let Some(paddr) = alloc.alloc_in(Zone::General) {
+            frames.push(paddr);
+        }
+        assert_eq!(frames.len() as u32, (end - start) / FRAME_BYTES);
+
+        for paddr in frames.iter().step_by(2) {
+            alloc.free(*paddr);
+        }
+
+        // Half the zone is free, but no two frames are adjacent
+        assert!(alloc
+            .alloc_contiguous_in(Zone::General, 2, FRAME_BYTES)
+            .is_none());
+        assert!(alloc.alloc_in(Zone::General).is_some());
+
+        // Releasing a neighbour makes a pair available again
+        alloc.free(frames[1]);
+        let mem = alloc
+            .alloc_contiguous_in(Zone::General, 2, FRAME_BYTES)
+            .unwrap();
+        assert_eq!(mem.addr(), frames[0]);
+    }
+
+    #[test]
+    fn contiguous_is_exact_and_aligned() {
+        let mut alloc = new_alloc();
+
+        let mem = alloc.alloc_contiguous_in(Zone::Dma, 5, 0x10_000).unwrap();
+        assert_eq!(mem.addr() % 0x10_000, 0);
+        assert_eq!(mem.frames(), 5);
+
+        // Only the requested frames are taken out of the zone
+        assert_eq!(alloc.free_frames(Zone::Dma), 16 * 256 - 5);
+
+        alloc.free_contiguous(mem);
+        assert_eq!(alloc.free_frames(Zone::Dma), 16 * 256);
+    }
+
+    #[test]
+    fn contiguous_marks_frames_used() {
+        let mut alloc = new_alloc();
+
+        let mem = alloc
+            .alloc_contiguous_in(Zone::Gpu, 3, FRAME_BYTES)
+            .unwrap();
+        let (start, end) = zone_range(Zone::Gpu);
+        while let Some(paddr) = alloc.alloc_in(Zone::Gpu) {
+            assert!(paddr >= start && paddr < end);
+            assert!(paddr < mem.addr() || paddr >= mem.addr() + 3 * FRAME_BYTES);
+        }
+    }
+
+    #[test]
+    fn mark_allocated_splits_blocks() {
+        let mut alloc = new_alloc();
+
+        alloc.mark_allocated(0);
+        alloc.mark_allocated(0xf000);
+        alloc.mark_allocated(0xf000);
+        assert_eq!(alloc.free_frames(Zone::Dma), 16 * 256 - 2);
+
+        let mut frames = Vec::new();
+        while let Some(paddr) = alloc.alloc_in(Zone::Dma) {
+            frames.push(paddr);
+        }
+        assert!(!frames.contains(&0));
+        assert!(!frames.contains(&0xf000));
+        assert_eq!(frames.len(), 16 * 256 - 2);
+    }
+
+    #[test]
+    fn contiguous_spans_several_blocks() {
+        let mut alloc = new_alloc();
+        let (start, end) = zone_range(Zone::Gpu);
+        // Instance memory at the top of the zone breaks up the last block
+        alloc.mark_allocated(end - FRAME_BYTES);
+
+        // 1920x1080 at 32bpp is about 8 MiB, more than one block
+        let frames = (1920 * 1080 * 4u32).div_ceil(FRAME_BYTES);
+        let mem = alloc
+            .alloc_contiguous_in(Zone::Gpu, frames, FRAME_BYTES)
+            .unwrap();
+        assert_eq!(mem.addr(), start);
+        assert!(alloc
+            .alloc_contiguous_in(Zone::Gpu, frames, FRAME_BYTES)
+            .is_none());
+
+        // The tail of the run is given back
+        let used = frames as usize + 1;
+        assert_eq!(alloc.free_frames(Zone::Gpu), 16 * 256 - used);
+
+        alloc.free_contiguous(mem);
+        assert_eq!(alloc.free_frames(Zone::Gpu), 16 * 256 - 1);
+    }
+
+    #[test]
+    fn oversized_requests_fail() {
+        let mut alloc = new_alloc();
+        let (start, end) = zone_range(Zone::Gpu);
+        let zone_frames = (end - start) / FRAME_BYTES;
+        assert!(alloc
+            .alloc_contiguous_in(Zone::Gpu, zone_frames + 1, FRAME_BYTES)
+            .is_none());
+        assert!(alloc
+            .alloc_contiguous_in(Zone::General, 0, FRAME_BYTES)
+            .is_none());
     }
 }
@@ -1,117 +1,234 @@
-use crate::{encoder, font};
+use core::fmt;
 
-#[derive(Copy, Clone, Debug)]
+use crate::{encoder, font, gfx};
+
+/// Color in the byte order of a 32bpp framebuffer pixel
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub struct RGBA(u8, u8, u8, u8);
 
-pub const COLOR_WHITE: RGBA = RGBA(0xff, 0xff, 0xff, 0xff);
-pub const COLOR_BLACK: RGBA = RGBA(0x0, 0x0, 0x0, 0xff);
+impl RGBA {
+    pub const fn rgb(r: u8, g: u8, b: u8) -> Self {
+        Self(b, g, r, 0xff)
+    }
+
+    pub const fn rgba(r: u8, g: u8, b: u8, a: u8) -> Self {
+        Self(b, g, r, a)
+    }
+
+    pub fn r(self) -> u8 {
+        self.2
+    }
 
-pub unsafe fn print_char2(dest: *mut u32, bytes_per_line: u32, rgba: &RGBA, ch: u8) -> u32 {
-    let mask = if font::VMIRROR > 0 { 0x01 } else { 0x80 };
+    pub fn g(self) -> u8 {
+        self.1
+    }
 
-    let font_loc = (ch as u32) * ((font::WIDTH + 7) / 8) * font::HEIGHT;
-    let dest = dest as *mut u8;
+    pub fn b(self) -> u8 {
+        self.0
+    }
 
-    for y in 0..font::HEIGHT {
-        let font_loc = font_loc + y;
-        let font = font::FONT[font_loc as usize];
+    /// Opacity, from 0 for fully transparent to 0xff for opaque
+    pub fn a(self) -> u8 {
+        self.3
+    }
 
-        let y_offset = y * bytes_per_line;
-        let dest = dest.add(y_offset as usize);
+    /// The color as a 32bpp pixel value
+    pub fn to_pixel(self) -> u32 {
+        u32::from_le_bytes([self.0, self.1, self.2, self.3])
+    }
 
-        for x in 0..font::WIDTH {
-            let dest = dest.add(4 * (x as usize));
-            let mask = if font::VMIRROR > 0 {
-                mask << x
-            } else {
-                mask >> x
-            };
+    pub fn from_pixel(pixel: u32) -> Self {
+        let [b, g, r, a] = pixel.to_le_bytes();
+        Self(b, g, r, a)
+    }
+}
 
-            if font & mask == 0 {
-                continue;
-            }
+pub const COLOR_WHITE: RGBA = RGBA(0xff, 0xff, 0xff, 0xff);
+pub const COLOR_BLACK: RGBA = RGBA(0x0, 0x0, 0x0, 0xff);
+
+/// Draws `ch` scaled up by `scale`, with its top left corner at `dest`
+/// in a framebuffer of `format` pixels with rows `pitch` bytes apart.
+/// The background is left as is. Returns the width drawn in pixels.
+pub unsafe fn draw_char(
+    dest: *mut u8,
+    pitch: usize,
+    format: gfx::PixelFormat,
+    font: &font::Font,
+    scale: u32,
+    rgba: RGBA,
+    ch: char,
+) -> u32 {
+    let glyph = font.glyph(ch);
+    let pixel = format.encode(rgba);
+    let bytes_pp = format.bytes_per_pixel();
 
-            dest.byte_add(0).write(rgba.0);
-            dest.byte_add(1).write(rgba.1);
-            dest.byte_add(2).write(rgba.2);
-            dest.byte_add(3).write(rgba.3);
+    for y in 0..font.height() * scale {
+        let line = dest.add(y as usize * pitch);
+        for x in 0..font.width() * scale {
+            if glyph.is_set(x / scale, y / scale) {
+                format.write(line.add(x as usize * bytes_pp), pixel);
+            }
         }
     }
 
-    font::WIDTH
+    font.width() * scale
 }
 
-unsafe fn print_string_bytes(
-    dest: *mut u32,
-    bytes_per_line: u32,
-    rgba: &RGBA,
-    string: &[u8],
-) -> u32 {
-    let mut width = 0;
+/// Incremental UTF-8 decoder for text that arrives in pieces.
+/// Malformed input decodes to U+FFFD.
+#[derive(Copy, Clone)]
+pub struct Utf8Decoder {
+    code: u32,
+    /// Continuation bytes still expected
+    remaining: u8,
+    /// Smallest code point the current sequence may encode,
+    /// anything below is an overlong encoding
+    min: u32,
+}
+
+impl Utf8Decoder {
+    pub const fn new() -> Self {
+        Self {
+            code: 0,
+            remaining: 0,
+            min: 0,
+        }
+    }
+
+    /// Feeds in a byte, passing any characters it completes to `emit`
+    pub fn push(&mut self, byte: u8, mut emit: impl FnMut(char)) {
+        if self.remaining > 0 {
+            if byte & 0xc0 == 0x80 {
+                self.code = (self.code << 6) | (byte & 0x3f) as u32;
+                self.remaining -= 1;
+                if self.remaining == 0 {
+                    let ch = char::from_u32(self.code).filter(|_| self.code >= self.min);
+                    emit(ch.unwrap_or(char::REPLACEMENT_CHARACTER));
+                }
+                return;
+            }
 
-    for ch in string.iter() {
-        if *ch == 0 || *ch == b'\n' {
-            return width;
+            // The sequence was cut short, and this byte starts something new
+            self.remaining = 0;
+            emit(char::REPLACEMENT_CHARACTER);
         }
 
-        width += print_char2(dest.add(width as usize), bytes_per_line, rgba, *ch);
+        match byte {
+            0x00..=0x7f => emit(byte as char),
+            0xc0..=0xdf => self.start(byte & 0x1f, 1, 0x80),
+            0xe0..=0xef => self.start(byte & 0x0f, 2, 0x800),
+            0xf0..=0xf7 => self.start(byte & 0x07, 3, 0x10000),
+            _ => emit(char::REPLACEMENT_CHARACTER),
+        }
     }
 
-    width
+    fn start(&mut self, bits: u8, remaining: u8, min: u32) {
+        self.code = bits as u32;
+        self.remaining = remaining;
+        self.min = min;
+    }
 }
 
 pub struct VGAPrinter {
-    fb_addr: *mut u32,
+    fb_addr: *mut u8,
     fb_width: u32,
+    fb_height: u32,
     xmargin: u32,
     ymargin: u32,
 
+    format: gfx::PixelFormat,
+    /// Bytes per pixel
     bpp: u32,
     cursor_x: u32,
     cursor_y: u32,
+
+    font: &'static font::Font<'static>,
+    scale: u32,
+    utf8: Utf8Decoder,
+
+    /// Color used for text written through `fmt::Write`
+    color: RGBA,
 }
 
+// The framebuffer pointer is owned by the printer
+unsafe impl Send for VGAPrinter {}
+
 impl VGAPrinter {
-    pub fn new(fb_addr: *mut u32, vm: &encoder::VideoModeInfo) -> Self {
+    pub fn new(fb_addr: *mut u8, vm: &encoder::VideoModeInfo) -> Self {
+        let bpp = vm.bytes_per_pixel();
         Self {
             fb_addr,
             fb_width: vm.width,
+            fb_height: vm.height,
             xmargin: vm.xmargin,
             ymargin: vm.ymargin,
-            bpp: 4,
-            cursor_x: 4 + vm.xmargin * 4,
+            format: vm.pixel_format,
+            bpp,
+            cursor_x: bpp + vm.xmargin * bpp,
             cursor_y: 1 + vm.ymargin,
+            font: font::default(),
+            scale: 1,
+            utf8: Utf8Decoder::new(),
+            color: COLOR_WHITE,
         }
     }
 
+    pub fn set_color(&mut self, rgba: RGBA) {
+        self.color = rgba;
+    }
+
     pub fn reset(&mut self) {
         self.cursor_y = 1 + self.ymargin;
-        self.cursor_x = 4 + self.xmargin * 4;
+        self.cursor_x = self.bpp + self.xmargin * self.bpp;
+    }
+
+    fn new_line(&mut self) {
+        self.cursor_y += self.font.height() * self.scale;
+        self.cursor_x = self.bpp + self.xmargin * self.bpp;
+    }
+
+    fn print_char(&mut self, rgba: RGBA, ch: char) {
+        if ch == '\n' {
+            self.new_line();
+            return;
+        }
+
+        if ch.is_control() {
+            return;
+        }
+
+        let advance = self.font.width() * self.scale * self.bpp;
+        if self.cursor_x + advance > (self.fb_width - self.xmargin) * self.bpp {
+            self.new_line();
+        }
+
+        // Text running off the bottom of the screen is dropped
+        if self.cursor_y + self.font.height() * self.scale > self.fb_height - self.ymargin {
+            return;
+        }
+
+        unsafe {
+            draw_char(
+                self.fb_addr
+                    .add((self.cursor_y * self.fb_width * self.bpp + self.cursor_x) as usize),
+                (self.fb_width * self.bpp) as usize,
+                self.format,
+                self.font,
+                self.scale,
+                rgba,
+                ch,
+            );
+        }
+        self.cursor_x += advance;
     }
 
+    /// Prints UTF-8 text, which may be split over several calls
     pub fn print_string_bytes(&mut self, rgba: RGBA, string: &[u8]) {
-        let mut string_lines = string.split(|ch| *ch == b'\n').peekable();
-
-        while let Some(line) = string_lines.next() {
-            let width = unsafe {
-                print_string_bytes(
-                    self.fb_addr.byte_add(
-                        (self.cursor_y * self.fb_width * self.bpp + self.cursor_x) as usize,
-                    ),
-                    self.fb_width * self.bpp,
-                    &rgba,
-                    line,
-                )
-            };
-
-            self.cursor_x += width * self.bpp;
-            if (string_lines.peek().is_some())
-                || (self.cursor_x > ((self.fb_width - self.xmargin) * self.bpp))
-            {
-                self.cursor_y += font::HEIGHT as u32;
-                self.cursor_x = self.bpp + self.xmargin * self.bpp;
-            }
+        let mut utf8 = self.utf8;
+        for &byte in string {
+            utf8.push(byte, |ch| self.print_char(rgba, ch));
         }
+        self.utf8 = utf8;
     }
 
     pub fn print_hex(&mut self, rgba: RGBA, mut val: u32) {
@@ -140,3 +257,43 @@ impl VGAPrinter {
         self.print_string_bytes(rgba, &buf);
     }
 }
+
+impl fmt::Write for VGAPrinter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.print_string_bytes(self.color, s.as_bytes());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::String;
+
+    fn decode(bytes: &[u8]) -> String {
+        let mut decoder = Utf8Decoder::new();
+        let mut out = String::new();
+        for &byte in bytes {
+            decoder.push(byte, |ch| out.push(ch));
+        }
+        out
+    }
+
+    #[test]
+    fn decodes_multi_byte_characters() {
+        let text = "ascii é ✓ 🎮";
+        assert_eq!(decode(text.as_bytes()), text);
+    }
+
+    #[test]
+    fn malformed_input_becomes_replacement_characters() {
+        // Stray continuation byte
+        assert_eq!(decode(b"a\x80b"), "a\u{fffd}b");
+        // Sequence cut short by ASCII
+        assert_eq!(decode(b"\xe2\x9cb"), "\u{fffd}b");
+        // Overlong encoding of '/'
+        assert_eq!(decode(b"\xc0\xaf"), "\u{fffd}");
+        // Surrogate half
+        assert_eq!(decode(b"\xed\xa0\x80"), "\u{fffd}");
+    }
+}
@@ -0,0 +1,80 @@
+use super::cpu::io;
+
+/// First UART of the SuperIO found on debug hardware and emulators
+pub const COM1: u16 = 0x3f8;
+
+const BAUD_BASE: u32 = 115_200;
+
+// Register offsets from the UART base
+const DATA: u16 = 0;
+const INT_ENABLE: u16 = 1;
+const FIFO_CTRL: u16 = 2;
+const LINE_CTRL: u16 = 3;
+const MODEM_CTRL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+const SCRATCH: u16 = 7;
+
+const LCR_8N1: u8 = 0x03;
+const LCR_DLAB: u8 = 0x80;
+const LSR_THR_EMPTY: u8 = 0x20;
+
+/// Give up on a character if the transmitter stays busy this long,
+/// so that a stuck or absent port can't hang the logger
+const TX_SPINS: u32 = 100_000;
+
+/// Polled 16550 UART, used for output only
+pub struct Serial {
+    base: u16,
+}
+
+impl Serial {
+    /// Sets up the UART at `base` for 8N1 at `baud`, if one responds there
+    pub fn probe(base: u16, baud: u32) -> Option<Self> {
+        unsafe {
+            // Nothing decodes the port if its scratch register doesn't hold a value
+            io::write_u8(base + SCRATCH, 0x5a);
+            if io::read_u8(base + SCRATCH) != 0x5a {
+                return None;
+            }
+
+            let divisor = (BAUD_BASE / baud) as u16;
+
+            io::write_u8(base + INT_ENABLE, 0);
+            io::write_u8(base + LINE_CTRL, LCR_DLAB);
+            io::write_u8(base + DATA, divisor as u8);
+            io::write_u8(base + INT_ENABLE, (divisor >> 8) as u8);
+            io::write_u8(base + LINE_CTRL, LCR_8N1);
+            // Enable and clear the FIFOs
+            io::write_u8(base + FIFO_CTRL, 0xc7);
+            // DTR, RTS and OUT2
+            io::write_u8(base + MODEM_CTRL, 0x0b);
+        }
+
+        Some(Self { base })
+    }
+
+    pub fn write_byte(&mut self, byte: u8) {
+        unsafe {
+            for _ in 0..TX_SPINS {
+                if io::read_u8(self.base + LINE_STATUS) & LSR_THR_EMPTY != 0 {
+                    io::write_u8(self.base + DATA, byte);
+                    return;
+                }
+                core::hint::spin_loop();
+            }
+        }
+    }
+}
+
+impl core::fmt::Write for Serial {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for byte in s.bytes() {
+            if byte == b'\n' {
+                self.write_byte(b'\r');
+            }
+            self.write_byte(byte);
+        }
+
+        Ok(())
+    }
+}
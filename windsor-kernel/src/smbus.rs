@@ -1,7 +1,30 @@
-use super::cpu::io;
+use core::sync::atomic::{AtomicU8, Ordering};
+use core::time::Duration;
+
+use super::cpu::{io, pic};
+use super::executor;
+use super::sync::Mutex;
+use super::time;
 
 const I2C_PORT: u16 = 0xc000;
 
+/// PIC line the controller raises when a transfer completes
+pub const IRQ: u8 = 11;
+
+/// Upper bound on a single transfer, well above what the
+/// controller takes at 100 kHz
+const TIMEOUT: Duration = Duration::from_millis(10);
+
+/// Pause before retrying a transfer the device didn't acknowledge,
+/// giving it time to finish whatever kept it busy
+const RETRY_DELAY_US: u64 = 100;
+
+/// Held for a whole transaction, including retries
+static BUS: Mutex<()> = Mutex::new(());
+
+/// Status of the current async transfer, latched by `handle_irq`
+static IRQ_STATUS: AtomicU8 = AtomicU8::new(0);
+
 #[derive(Copy, Clone)]
 pub enum SMBusSize {
     Byte = 1,
@@ -9,7 +32,92 @@ pub enum SMBusSize {
     DWord = 4,
 }
 
-unsafe fn try_write(addr: u8, reg: u8, size: SMBusSize, val: u32) -> bool {
+/// Waits for the controller to finish with the current transfer
+unsafe fn wait_idle() -> Result<(), ()> {
+    let deadline = time::Deadline::after(TIMEOUT);
+    while io::read_u32(I2C_PORT) & 0x800 != 0 {
+        if deadline.expired() {
+            return Err(());
+        }
+        core::hint::spin_loop();
+    }
+
+    Ok(())
+}
+
+/// Returns the status register once the transfer has completed or failed
+unsafe fn status() -> Option<u8> {
+    let result = io::read_u8(I2C_PORT);
+    if result & 0x36 != 0 {
+        Some(result)
+    } else {
+        None
+    }
+}
+
+/// Polls for completion or an error, returning the status register
+unsafe fn wait_status() -> Option<u8> {
+    let deadline = time::Deadline::after(TIMEOUT);
+    loop {
+        if let Some(result) = status() {
+            return Some(result);
+        }
+
+        if deadline.expired() {
+            return None;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// Latches and acknowledges the status of a finished transfer, so
+/// that the line stops asserting before the waiting task gets to run
+fn handle_irq() {
+    if let Some(result) = unsafe { status() } {
+        IRQ_STATUS.fetch_or(result, Ordering::AcqRel);
+        unsafe { io::write_u8(I2C_PORT, result) };
+    }
+}
+
+/// Attaches `handle_irq` for as long as async transfers are running,
+/// masking the line again once dropped
+struct IrqHandler(pic::HandlerId);
+
+impl IrqHandler {
+    fn attach() -> Result<Self, ()> {
+        pic::register(IRQ, handle_irq).map(Self)
+    }
+}
+
+impl Drop for IrqHandler {
+    fn drop(&mut self) {
+        pic::unregister(self.0);
+    }
+}
+
+/// Waits for completion or an error without blocking the executor
+async fn wait_status_async() -> Option<u8> {
+    // Polled too, in case the transfer finished before the handler was attached
+    let done = || match IRQ_STATUS.swap(0, Ordering::AcqRel) {
+        0 => unsafe { status() },
+        latched => Some(latched),
+    };
+    executor::timeout(TIMEOUT, executor::irq_until(IRQ, done)).await
+}
+
+/// Starts the transfer set up in the other registers. Only async
+/// transfers set `irq`, which raises IRQ when the transfer completes.
+unsafe fn start(size: SMBusSize, irq: bool) {
+    let protocol = match size {
+        SMBusSize::Byte => 0x0a,
+        SMBusSize::Word => 0x0b,
+        SMBusSize::DWord => 0x0d,
+    };
+    let irq_enable = if irq { 0x10 } else { 0 };
+    io::write_u32(I2C_PORT + 2, protocol | irq_enable);
+}
+
+unsafe fn start_write(addr: u8, reg: u8, size: SMBusSize, val: u32, irq: bool) {
     io::write_u32(I2C_PORT + 4, (addr as u32) << 1);
     io::write_u32(I2C_PORT + 8, reg as u32);
 
@@ -33,29 +141,41 @@ unsafe fn try_write(addr: u8, reg: u8, size: SMBusSize, val: u32) -> bool {
     let tmp = io::read_u32(I2C_PORT);
     io::write_u32(I2C_PORT, tmp);
 
-    match size {
-        SMBusSize::Byte => io::write_u32(I2C_PORT + 2, 0x1a),
-        SMBusSize::Word => io::write_u32(I2C_PORT + 2, 0x1b),
-        SMBusSize::DWord => io::write_u32(I2C_PORT + 2, 0x1d),
-    }
+    start(size, irq);
+}
 
-    let mut result = io::read_u8(I2C_PORT);
-    while result & 0x36 == 0 {
-        result = io::read_u8(I2C_PORT);
-        core::hint::spin_loop();
+fn write_succeeded(status: Option<u8>) -> bool {
+    match status {
+        Some(result) => result & 0x10 != 0,
+        None => false,
     }
-
-    result & 0x10 != 0
 }
 
 pub unsafe fn write(addr: u8, reg: u8, size: SMBusSize, val: u32) -> Result<(), ()> {
-    while io::read_u32(I2C_PORT) & 0x800 != 0 {
-        core::hint::spin_loop();
-    }
+    let _bus = BUS.lock();
+    wait_idle()?;
 
     // FIXME: How many tries does this take really?
     for _ in 0..50 {
-        if try_write(addr, reg, size, val) {
+        start_write(addr, reg, size, val, false);
+        if write_succeeded(wait_status()) {
+            return Ok(());
+        }
+        time::delay_us(RETRY_DELAY_US);
+    }
+
+    Err(())
+}
+
+pub async fn write_async(addr: u8, reg: u8, size: SMBusSize, val: u32) -> Result<(), ()> {
+    let _bus = BUS.lock_async().await;
+    unsafe { wait_idle()? };
+    let _irq = IrqHandler::attach()?;
+
+    for _ in 0..50 {
+        IRQ_STATUS.store(0, Ordering::Release);
+        unsafe { start_write(addr, reg, size, val, true) };
+        if write_succeeded(wait_status_async().await) {
             return Ok(());
         }
     }
@@ -63,24 +183,18 @@ pub unsafe fn write(addr: u8, reg: u8, size: SMBusSize, val: u32) -> Result<(),
     Err(())
 }
 
-unsafe fn try_read(addr: u8, reg: u8, size: SMBusSize) -> Option<u32> {
+unsafe fn start_read(addr: u8, reg: u8, size: SMBusSize, irq: bool) {
     io::write_u32(I2C_PORT + 4, ((addr as u32) << 1) | 1);
     io::write_u32(I2C_PORT + 8, reg as u32);
 
     let tmp = io::read_u32(I2C_PORT);
     io::write_u32(I2C_PORT, tmp);
 
-    match size {
-        SMBusSize::Byte => io::write_u32(I2C_PORT + 2, 0xa),
-        SMBusSize::Word => io::write_u32(I2C_PORT + 2, 0xb),
-        SMBusSize::DWord => io::write_u32(I2C_PORT + 2, 0xd),
-    }
+    start(size, irq);
+}
 
-    let mut result = io::read_u8(I2C_PORT);
-    while result & 0x36 == 0 {
-        result = io::read_u8(I2C_PORT);
-        core::hint::spin_loop();
-    }
+unsafe fn finish_read(size: SMBusSize, status: Option<u8>) -> Option<u32> {
+    let result = status?;
 
     // FIXME: Check result & 0x24 != 0?
 
@@ -96,12 +210,30 @@ unsafe fn try_read(addr: u8, reg: u8, size: SMBusSize) -> Option<u32> {
 }
 
 pub unsafe fn read(addr: u8, reg: u8, size: SMBusSize) -> Result<u32, ()> {
-    while io::read_u32(I2C_PORT) & 0x800 != 0 {
-        core::hint::spin_loop();
+    let _bus = BUS.lock();
+    wait_idle()?;
+
+    for _ in 0..50 {
+        start_read(addr, reg, size, false);
+        if let Some(v) = finish_read(size, wait_status()) {
+            return Ok(v);
+        }
+        time::delay_us(RETRY_DELAY_US);
     }
 
+    Err(())
+}
+
+pub async fn read_async(addr: u8, reg: u8, size: SMBusSize) -> Result<u32, ()> {
+    let _bus = BUS.lock_async().await;
+    unsafe { wait_idle()? };
+    let _irq = IrqHandler::attach()?;
+
     for _ in 0..50 {
-        if let Some(v) = try_read(addr, reg, size) {
+        IRQ_STATUS.store(0, Ordering::Release);
+        unsafe { start_read(addr, reg, size, true) };
+        let status = wait_status_async().await;
+        if let Some(v) = unsafe { finish_read(size, status) } {
             return Ok(v);
         }
     }
@@ -0,0 +1,55 @@
+use core::time::Duration;
+
+use super::executor;
+use super::smbus::{self, SMBusSize};
+
+/// SMBus address of the system management controller
+const ADDR: u8 = 0x10;
+
+const REG_LED_MODE: u8 = 0x07;
+const REG_LED_SEQUENCE: u8 = 0x08;
+const REG_CPU_TEMP: u8 = 0x09;
+const REG_BOARD_TEMP: u8 = 0x0a;
+
+/// Plays the LED sequence instead of the SMC's own
+const LED_MODE_CUSTOM: u32 = 1;
+/// Green in all four steps of the sequence, red in none
+const LED_SOLID_GREEN: u32 = 0xf0;
+
+/// CPU temperature in °C above which a warning is logged
+const CPU_TEMP_WARN: u32 = 70;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+async fn read_temps() -> Result<(u32, u32), ()> {
+    let cpu = smbus::read_async(ADDR, REG_CPU_TEMP, SMBusSize::Byte).await?;
+    let board = smbus::read_async(ADDR, REG_BOARD_TEMP, SMBusSize::Byte).await?;
+    Ok((cpu, board))
+}
+
+/// Turns the front LED solid green, then watches the temperatures,
+/// warning each time the CPU gets hotter than CPU_TEMP_WARN
+pub async fn monitor() {
+    let led = async {
+        smbus::write_async(ADDR, REG_LED_SEQUENCE, SMBusSize::Byte, LED_SOLID_GREEN).await?;
+        smbus::write_async(ADDR, REG_LED_MODE, SMBusSize::Byte, LED_MODE_CUSTOM).await
+    };
+    if led.await.is_err() {
+        log::warn!("SMC didn't take the LED sequence");
+    }
+
+    let mut hot = false;
+    loop {
+        match read_temps().await {
+            Ok((cpu, board)) => {
+                if cpu > CPU_TEMP_WARN && !hot {
+                    log::warn!("CPU at {} °C, board at {} °C", cpu, board);
+                }
+                hot = cpu > CPU_TEMP_WARN;
+            }
+            Err(()) => log::warn!("SMC temperature read failed"),
+        }
+
+        executor::sleep(POLL_INTERVAL).await;
+    }
+}
@@ -0,0 +1,100 @@
+use crate::framebuffer::Framebuffer;
+use crate::nv2a::vblank;
+use crate::print::{self, RGBA};
+use crate::{encoder, gfx, image};
+
+/// Image embedded by build-tool, empty if it wasn't given one
+static EMBEDDED: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/splash.img"));
+
+/// Splash images are drawn at their own size on a screen this tall,
+/// and scaled along with the screen height in other modes
+const REFERENCE_HEIGHT: u32 = 480;
+
+/// Vblanks the fade in takes
+const FADE_FRAMES: u32 = 30;
+
+const BACKGROUND: RGBA = print::COLOR_BLACK;
+
+/// Splash image decoded into the framebuffer format, at its on screen size
+pub struct Splash {
+    pixmap: gfx::Pixmap,
+    x: i32,
+    y: i32,
+}
+
+/// Size of a `width` by `height` image on screen, keeping its aspect ratio
+fn fit(width: u32, height: u32, vm: &encoder::VideoModeInfo) -> (u32, u32) {
+    let (mut w, mut h) = (
+        width * vm.height / REFERENCE_HEIGHT,
+        height * vm.height / REFERENCE_HEIGHT,
+    );
+
+    if w > vm.width {
+        h = h * vm.width / w;
+        w = vm.width;
+    }
+    if h > vm.height {
+        w = w * vm.height / h;
+        h = vm.height;
+    }
+
+    (w.max(1), h.max(1))
+}
+
+impl Splash {
+    /// Decodes the embedded image for the video mode, if there is one
+    pub fn load(vm: &encoder::VideoModeInfo, format: gfx::PixelFormat) -> Option<Self> {
+        if EMBEDDED.is_empty() {
+            return None;
+        }
+
+        let Ok(bitmap) = image::decode(EMBEDDED) else {
+            log::warn!("Embedded splash image is invalid");
+            return None;
+        };
+
+        let (width, height) = fit(bitmap.width, bitmap.height, vm);
+        let bitmap = bitmap.scaled(width, height);
+
+        // Transparent parts are blended with the background up front
+        let mut pixmap = gfx::Pixmap::new(width, height, format);
+        let mut surface = pixmap.surface();
+        surface.clear(BACKGROUND);
+        surface.blit(0, 0, &bitmap.image());
+
+        Some(Self {
+            pixmap,
+            x: (vm.width - width) as i32 / 2,
+            y: (vm.height - height) as i32 / 2,
+        })
+    }
+
+    /// Draws the splash centered on the background, with its
+    /// brightness scaled by `level`, from 0 for black to 0xff
+    pub fn draw(&self, surface: &mut gfx::Surface, level: u8) {
+        surface.clear(BACKGROUND);
+        surface.draw_pixmap(self.x, self.y, &self.pixmap, level);
+    }
+}
+
+/// Fades the embedded splash image in, one step per vblank,
+/// leaving it on screen. Does nothing if there isn't one.
+pub fn show(fb: &mut Framebuffer, vm: &encoder::VideoModeInfo) {
+    let Some(splash) = Splash::load(vm, fb.format()) else {
+        return;
+    };
+
+    for frame in 1..=FADE_FRAMES {
+        fb.wait_flip();
+        splash.draw(&mut fb.surface(), (frame * 0xff / FADE_FRAMES) as u8);
+
+        // Flips are latched on vblank, a single buffer has to wait for it
+        if fb.buffer_count() > 1 {
+            fb.flip();
+        } else {
+            vblank::wait();
+        }
+    }
+
+    fb.wait_flip();
+}
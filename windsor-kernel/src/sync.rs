@@ -0,0 +1,351 @@
+use core::cell::UnsafeCell;
+use core::future::poll_fn;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use core::task::Poll;
+
+use crate::cpu;
+use crate::executor::WakerList;
+use crate::thread::{self, WaitQueue};
+
+/// Waits for another context to make progress. Yields to other threads
+/// once the scheduler is running. Before that, the only other contexts
+/// are interrupt handlers, so halt until one has run if interrupts are
+/// enabled.
+pub fn relax() {
+    if thread::current().is_some() {
+        thread::yield_now();
+    } else if cpu::interrupts_enabled() {
+        unsafe { core::arch::asm!("hlt") };
+    } else {
+        core::hint::spin_loop();
+    }
+}
+
+/// Spinlock that disables interrupts while held, so that data
+/// shared with interrupt handlers can't be accessed re-entrantly.
+///
+/// Locks that nest are always taken in the same order: the logger's
+/// `RING` and `SINKS`, `CONSOLE`, `ACCEL`, the NV2A registers, then
+/// `HEAP`, `KERNEL_MAPPING` and `ALLOCATOR`. A `WaitQueue` is taken
+/// before `SCHEDULER`, which comes before `HEAP`. Exceptions can fault
+/// in with any of these held, so their handlers only `try_lock`.
+pub struct SpinLock<T> {
+    locked: AtomicBool,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+unsafe impl<T: Send> Send for SpinLock<T> {}
+
+pub struct SpinLockGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+    irq_enabled: bool,
+}
+
+impl<T> SpinLock<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        let irq_enabled = cpu::interrupts_enabled();
+        cpu::cli();
+
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            core::hint::spin_loop();
+        }
+
+        SpinLockGuard {
+            lock: self,
+            irq_enabled,
+        }
+    }
+
+    /// Takes the lock if it's free, without waiting
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_, T>> {
+        let irq_enabled = cpu::interrupts_enabled();
+        cpu::cli();
+
+        if self
+            .locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            if irq_enabled {
+                cpu::sti();
+            }
+            return None;
+        }
+
+        Some(SpinLockGuard {
+            lock: self,
+            irq_enabled,
+        })
+    }
+
+    /// Pointer to the data, bypassing the lock. Dereferencing it is only
+    /// for paths that can't wait on the owner, like the panic handler,
+    /// and the data must not be used concurrently.
+    pub fn steal(&self) -> *mut T {
+        self.data.get()
+    }
+}
+
+impl<T> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.locked.store(false, Ordering::Release);
+        if self.irq_enabled {
+            cpu::sti();
+        }
+    }
+}
+
+/// Lock that blocks the waiting thread instead of disabling interrupts.
+/// Suitable for long operations, but must never be taken from
+/// an interrupt handler.
+pub struct Mutex<T> {
+    locked: AtomicBool,
+    waiters: WaitQueue,
+    wakers: WakerList,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for Mutex<T> {}
+unsafe impl<T: Send> Send for Mutex<T> {}
+
+pub struct MutexGuard<'a, T> {
+    lock: &'a Mutex<T>,
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            waiters: WaitQueue::new(),
+            wakers: WakerList::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| MutexGuard { lock: self })
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        let mut guard = None;
+        self.waiters.wait_until(|| {
+            guard = self.try_lock();
+            guard.is_some()
+        });
+
+        guard.unwrap()
+    }
+
+    /// Locks from async code, without blocking the thread
+    /// that runs the executor
+    pub async fn lock_async(&self) -> MutexGuard<'_, T> {
+        poll_fn(|cx| {
+            if let Some(guard) = self.try_lock() {
+                return Poll::Ready(guard);
+            }
+
+            self.wakers.register(cx.waker());
+            match self.try_lock() {
+                Some(guard) => Poll::Ready(guard),
+                None => Poll::Pending,
+            }
+        })
+        .await
+    }
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.locked.store(false, Ordering::Release);
+        self.lock.waiters.wake_one();
+        self.lock.wakers.wake_all();
+    }
+}
+
+/// First in, first out queue with room for `N` values, which never
+/// allocates, so interrupt handlers can push to it
+pub struct FixedQueue<T, const N: usize> {
+    values: [Option<T>; N],
+    head: usize,
+    len: usize,
+}
+
+impl<T: Copy, const N: usize> FixedQueue<T, N> {
+    pub const fn new() -> Self {
+        Self {
+            values: [None; N],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Panics if the queue is full. Callers bound how many
+    /// values can be queued at once instead of handling it.
+    pub fn push_back(&mut self, value: T) {
+        assert!(self.len < N, "Queue should have room");
+        self.values[(self.head + self.len) % N] = Some(value);
+        self.len += 1;
+    }
+
+    pub fn pop_front(&mut self) -> Option<T> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let value = self.values[self.head].take();
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        value
+    }
+}
+
+const UNINIT: u8 = 0;
+const INITIALIZING: u8 = 1;
+const READY: u8 = 2;
+
+/// Cell that is written once and then read freely,
+/// for globals that are set up during boot
+pub struct OnceCell<T> {
+    state: AtomicU8,
+    data: UnsafeCell<Option<T>>,
+}
+
+unsafe impl<T: Send + Sync> Sync for OnceCell<T> {}
+unsafe impl<T: Send> Send for OnceCell<T> {}
+
+impl<T> OnceCell<T> {
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU8::new(UNINIT),
+            data: UnsafeCell::new(None),
+        }
+    }
+
+    pub fn get(&self) -> Option<&T> {
+        if self.state.load(Ordering::Acquire) != READY {
+            return None;
+        }
+
+        unsafe { (*self.data.get()).as_ref() }
+    }
+
+    /// Stores `value`, or hands it back if the cell was already set
+    pub fn set(&self, value: T) -> Result<&T, T> {
+        if self
+            .state
+            .compare_exchange(UNINIT, INITIALIZING, Ordering::Acquire, Ordering::Acquire)
+            .is_err()
+        {
+            return Err(value);
+        }
+
+        let data = unsafe {
+            *self.data.get() = Some(value);
+            (*self.data.get()).as_ref().unwrap()
+        };
+
+        self.state.store(READY, Ordering::Release);
+        Ok(data)
+    }
+
+    pub fn get_or_init(&self, f: impl FnOnce() -> T) -> &T {
+        if let Some(data) = self.get() {
+            return data;
+        }
+
+        match self.set(f()) {
+            Ok(data) => data,
+            Err(_) => {
+                // Lost the race, wait for the winner to finish
+                while self.state.load(Ordering::Acquire) != READY {
+                    relax();
+                }
+                self.get().unwrap()
+            }
+        }
+    }
+}
+
+/// Flag that one context can wait on until another sets it,
+/// for example an interrupt handler signalling completion
+pub struct AtomicFlag {
+    flag: AtomicBool,
+    waiters: WaitQueue,
+}
+
+impl AtomicFlag {
+    pub const fn new(set: bool) -> Self {
+        Self {
+            flag: AtomicBool::new(set),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Sets the flag, waking anything in `wait`
+    pub fn notify(&self) {
+        self.flag.store(true, Ordering::Release);
+        self.waiters.wake_all();
+    }
+
+    pub fn clear(&self) {
+        self.flag.store(false, Ordering::Release);
+    }
+
+    /// Clears the flag, returning whether it was set
+    pub fn take(&self) -> bool {
+        self.flag.swap(false, Ordering::AcqRel)
+    }
+
+    /// Blocks until the flag is set, then clears it
+    pub fn wait(&self) {
+        self.waiters.wait_until(|| self.take());
+    }
+}
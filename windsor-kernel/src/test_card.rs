@@ -0,0 +1,180 @@
+use alloc::vec::Vec;
+use core::ptr;
+
+use crate::cpu::mmu::MapError;
+use crate::encoder;
+use crate::gfx::{self, Rect};
+use crate::nv2a::{self, cursor::Cursor, pvideo, vram::GpuMemory};
+use crate::print::{self, RGBA};
+
+/// Framebuffer color that the overlay shows through
+pub const KEY: RGBA = RGBA::rgb(0xff, 0x00, 0xff);
+
+/// Color bars, left to right
+const BARS: [RGBA; 8] = [
+    print::COLOR_WHITE,
+    RGBA::rgb(0xff, 0xff, 0x00),
+    RGBA::rgb(0x00, 0xff, 0xff),
+    RGBA::rgb(0x00, 0xff, 0x00),
+    RGBA::rgb(0xff, 0x00, 0xff),
+    RGBA::rgb(0xff, 0x00, 0x00),
+    RGBA::rgb(0x00, 0x00, 0xff),
+    print::COLOR_BLACK,
+];
+
+/// Width of a bar in source pixels, even so that pixel pairs share a bar
+const BAR_WIDTH: u32 = 16;
+const WIDTH: u32 = BARS.len() as u32 * BAR_WIDTH;
+const HEIGHT: u32 = 64;
+/// Two bytes per pixel in YUV 4:2:2
+const PITCH: u32 = WIDTH * 2;
+const _: () = assert!(PITCH % pvideo::SURFACE_ALIGN == 0);
+
+/// The bars are scaled up on screen
+const SCALE: u32 = 2;
+
+/// Pointer image, with the hotspot at its tip in the top left corner.
+/// 'X' is the outline, '.' the fill and spaces are transparent.
+const POINTER: [&str; 19] = [
+    "X",
+    "XX",
+    "X.X",
+    "X..X",
+    "X...X",
+    "X....X",
+    "X.....X",
+    "X......X",
+    "X.......X",
+    "X........X",
+    "X.....XXXXX",
+    "X..X..X",
+    "X.X X..X",
+    "XX  X..X",
+    "X    X..X",
+    "     X..X",
+    "      X..X",
+    "      X..X",
+    "       XX",
+];
+
+/// Color bars scaled through the PVIDEO overlay, keyed into a window
+/// that the UI fills with KEY, with the hardware cursor pointing at them
+pub struct TestCard {
+    overlay: pvideo::Overlay,
+    pointer: Cursor,
+    /// Holds the bars for as long as the overlay shows them
+    _bars: GpuMemory,
+}
+
+/// BT.601 studio range Y, U and V for `color`
+fn yuv(color: RGBA) -> (u8, u8, u8) {
+    let (r, g, b) = (color.r() as i32, color.g() as i32, color.b() as i32);
+    let y = ((66 * r + 129 * g + 25 * b + 128) >> 8) + 16;
+    let u = ((-38 * r - 74 * g + 112 * b + 128) >> 8) + 128;
+    let v = ((112 * r - 94 * g - 18 * b + 128) >> 8) + 128;
+    (y as u8, u as u8, v as u8)
+}
+
+/// Two pixels of `color` in YUYV byte order
+fn yuyv_pair(color: RGBA) -> u32 {
+    let (y, u, v) = yuv(color);
+    u32::from_le_bytes([y, u, y, v])
+}
+
+/// The pointer's width and pixels
+fn pointer_pixels() -> (usize, Vec<RGBA>) {
+    let width = POINTER.iter().map(|row| row.len()).max().unwrap_or(0);
+    let mut pixels = alloc::vec![RGBA::rgba(0, 0, 0, 0); width * POINTER.len()];
+
+    for (y, row) in POINTER.iter().enumerate() {
+        for (x, ch) in row.bytes().enumerate() {
+            pixels[y * width + x] = match ch {
+                b'X' => print::COLOR_BLACK,
+                b'.' => print::COLOR_WHITE,
+                _ => continue,
+            };
+        }
+    }
+
+    (width, pixels)
+}
+
+impl TestCard {
+    /// Draws the bars and the pointer into GPU memory, placing the bars
+    /// in the top right corner inside the video mode's margins, clear
+    /// of the console at the bottom.
+    /// Safety: the kernel mapping must be set up
+    pub unsafe fn new(vm: &encoder::VideoModeInfo) -> Result<Self, MapError> {
+        let (width, pixels) = pointer_pixels();
+        let image = gfx::Image {
+            width: width as u32,
+            height: POINTER.len() as u32,
+            pixels: &pixels,
+        };
+        let pointer = Cursor::new(&image, (0, 0))?;
+
+        let bars = GpuMemory::alloc(PITCH * HEIGHT, pvideo::SURFACE_ALIGN)?;
+        let dest = bars.vaddr() as *mut u32;
+        for y in 0..HEIGHT {
+            for pair in 0..WIDTH / 2 {
+                let color = BARS[(pair * 2 / BAR_WIDTH) as usize];
+                ptr::write_volatile(dest.add((y * WIDTH / 2 + pair) as usize), yuyv_pair(color));
+            }
+        }
+
+        let (width, height) = (WIDTH * SCALE, HEIGHT * SCALE);
+        let overlay = pvideo::Overlay {
+            offset: bars.offset(),
+            width: WIDTH,
+            height: HEIGHT,
+            pitch: PITCH,
+            dest: Rect::new(
+                (vm.width - vm.xmargin).saturating_sub(width) as i32,
+                vm.ymargin as i32,
+                width,
+                height,
+            ),
+            color_keyed: true,
+        };
+
+        Ok(Self {
+            overlay,
+            pointer,
+            _bars: bars,
+        })
+    }
+
+    /// Area of the screen the bars show through, where it's KEY
+    pub fn window(&self) -> Rect {
+        self.overlay.dest
+    }
+
+    /// Shows the bars and points the hardware cursor at their middle
+    pub fn show(&self, gpu: &mut nv2a::NV2A, vm: &encoder::VideoModeInfo) -> Result<(), ()> {
+        gpu.set_overlay_color_key(vm.pixel_format, KEY);
+        gpu.show_overlay(&self.overlay, vm)?;
+
+        let window = self.window();
+        self.pointer.install(gpu);
+        self.pointer.move_to(
+            gpu,
+            window.x + window.width as i32 / 2,
+            window.y + window.height as i32 / 2,
+        );
+        gpu.show_cursor();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn colors_convert_to_studio_range_yuv() {
+        assert_eq!(yuv(print::COLOR_WHITE), (235, 128, 128));
+        assert_eq!(yuv(print::COLOR_BLACK), (16, 128, 128));
+        assert_eq!(yuv(RGBA::rgb(0xff, 0x00, 0x00)), (82, 90, 240));
+        assert_eq!(yuyv_pair(print::COLOR_BLACK), 0x8010_8010);
+    }
+}
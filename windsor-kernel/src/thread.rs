@@ -0,0 +1,574 @@
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use core::ops::Range;
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::time::Duration;
+
+use crate::cpu::mmu::{self, PhysramAllocator, PAGE_SIZE};
+use crate::cpu::{self, context, pagefault};
+use crate::physram;
+use crate::sync::{AtomicFlag, FixedQueue, OnceCell, SpinLock, SpinLockGuard};
+use crate::time;
+
+/// Stack size of spawned threads
+pub const STACK_SIZE: usize = 16 * 1024;
+
+/// Threads that can exist at once, including the boot and idle threads
+pub const MAX_THREADS: usize = 32;
+
+/// Ticks a thread runs before others of the same priority get a turn
+const TIME_SLICE: u32 = 10;
+
+/// Reserved virtual range for spawned thread stacks, above the heap.
+/// Each slot is an unmapped guard page followed by the stack.
+const STACKS_START: u32 = 0xE800_0000;
+const STACK_SLOT_SIZE: u32 = PAGE_SIZE + STACK_SIZE as u32;
+
+/// Bit n is set while slot n holds a stack
+static STACK_SLOTS: AtomicU32 = AtomicU32::new(0);
+const _: () = assert!(MAX_THREADS <= u32::BITS as usize);
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Priority {
+    Low,
+    Normal,
+}
+
+const PRIORITY_COUNT: usize = 2;
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct ThreadId(u32);
+
+/// A thread is on at most one queue at a time, so MAX_THREADS entries
+/// are always enough
+type IdQueue = FixedQueue<ThreadId, MAX_THREADS>;
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum State {
+    Running,
+    Ready,
+    Blocked,
+    /// Waiting for the tick count to reach the given value
+    Sleeping(u64),
+    Finished,
+}
+
+struct Thread {
+    name: &'static str,
+    priority: Priority,
+    state: State,
+    /// Stack pointer saved by `context::switch` while switched out
+    esp: u32,
+    /// None for the boot thread, which runs on the kernel stack
+    stack: Option<Stack>,
+    entry: Option<Box<dyn FnOnce() + Send>>,
+    /// Notified by `exit`, for the thread's JoinHandle
+    exited: Option<Arc<AtomicFlag>>,
+}
+
+struct Scheduler {
+    threads: BTreeMap<ThreadId, Box<Thread>>,
+    run_queues: [IdQueue; PRIORITY_COUNT],
+    current: ThreadId,
+    idle: ThreadId,
+    next_id: u32,
+    /// Ticks left before the current thread is preempted
+    slice: u32,
+    need_resched: bool,
+    /// Thread that exited on its own stack, freed by the next one to run
+    dead: Option<Box<Thread>>,
+}
+
+static SCHEDULER: OnceCell<SpinLock<Scheduler>> = OnceCell::new();
+
+fn scheduler() -> Option<SpinLockGuard<'static, Scheduler>> {
+    SCHEDULER.get().map(|sched| sched.lock())
+}
+
+impl Scheduler {
+    fn thread(&mut self, id: ThreadId) -> &mut Thread {
+        self.threads.get_mut(&id).expect("Thread should exist")
+    }
+
+    fn add(&mut self, thread: Thread) -> ThreadId {
+        let id = ThreadId(self.next_id);
+        self.next_id += 1;
+        self.threads.insert(id, Box::new(thread));
+        id
+    }
+
+    fn block_current(&mut self, state: State) {
+        let current = self.current;
+        self.thread(current).state = state;
+    }
+
+    /// Moves a blocked or sleeping thread onto its run queue
+    fn make_ready(&mut self, id: ThreadId) {
+        let current = self.current;
+        let current_priority = self.thread(current).priority;
+        let idle = current == self.idle;
+
+        let thread = match self.threads.get_mut(&id) {
+            Some(thread) => thread,
+            None => return,
+        };
+
+        if !matches!(thread.state, State::Blocked | State::Sleeping(_)) {
+            return;
+        }
+
+        thread.state = State::Ready;
+        self.run_queues[thread.priority as usize].push_back(id);
+
+        if idle || thread.priority > current_priority {
+            self.need_resched = true;
+        }
+    }
+
+    /// Picks the thread to run next. Returns where to save the current
+    /// stack pointer and the stack pointer to resume, if it isn't the
+    /// current thread.
+    fn pick_next(&mut self) -> Option<(*mut u32, u32)> {
+        let current = self.current;
+        let idle = self.idle;
+        let thread = self.threads.get_mut(&current).expect("Thread should exist");
+
+        if thread.state == State::Running {
+            thread.state = State::Ready;
+            if current != idle {
+                self.run_queues[thread.priority as usize].push_back(current);
+            }
+        }
+
+        let next = self
+            .run_queues
+            .iter_mut()
+            .rev()
+            .find_map(|queue| queue.pop_front())
+            .unwrap_or(idle);
+
+        self.thread(next).state = State::Running;
+        self.slice = TIME_SLICE;
+        self.need_resched = false;
+
+        if next == current {
+            return None;
+        }
+
+        let old = self.thread(current);
+        let old_esp = &mut old.esp as *mut u32;
+        if old.state == State::Finished {
+            // The stack is still in use until the switch completes
+            self.dead = self.threads.remove(&current);
+        }
+
+        self.current = next;
+        Some((old_esp, self.thread(next).esp))
+    }
+}
+
+/// Turns the running code into the boot thread and starts scheduling.
+/// The heap and the timer tick must be set up first.
+pub fn init() {
+    let mut sched = Scheduler {
+        threads: BTreeMap::new(),
+        run_queues: [IdQueue::new(), IdQueue::new()],
+        current: ThreadId(0),
+        idle: ThreadId(0),
+        next_id: 0,
+        slice: TIME_SLICE,
+        need_resched: false,
+        dead: None,
+    };
+
+    sched.current = sched.add(Thread {
+        name: "kmain",
+        priority: Priority::Normal,
+        state: State::Running,
+        esp: 0,
+        stack: None,
+        entry: None,
+        exited: None,
+    });
+
+    // Runs whenever nothing else can, and never goes on a run queue
+    let (esp, stack) = new_stack().expect("Idle thread stack should map");
+    sched.idle = sched.add(Thread {
+        name: "idle",
+        priority: Priority::Low,
+        state: State::Ready,
+        esp,
+        stack: Some(stack),
+        entry: Some(Box::new(|| loop {
+            cpu::sti_hlt();
+        })),
+        exited: None,
+    });
+
+    if SCHEDULER.set(SpinLock::new(sched)).is_err() {
+        panic!("Scheduler should only be initialized once");
+    }
+}
+
+/// A spawned thread's stack, mapped into its own slot of the stack
+/// range with the guard page below it registered for the fault handler
+struct Stack {
+    slot: u32,
+}
+
+impl Stack {
+    /// Maps a stack into a free slot, None if there is none
+    /// or RAM runs out
+    fn new() -> Option<Self> {
+        let used = STACK_SLOTS
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                let free = (!used).trailing_zeros();
+                (free < MAX_THREADS as u32).then(|| used | 1 << free)
+            })
+            .ok()?;
+
+        // Dropped on failure, releasing whatever was set up so far
+        let stack = Self {
+            slot: (!used).trailing_zeros(),
+        };
+        pagefault::register(
+            stack.guard(),
+            PAGE_SIZE,
+            pagefault::RegionKind::Guard("Kernel thread stack overflow"),
+        )
+        .ok()?;
+
+        let mut mapping = mmu::kernel_mapping();
+        let mut pm = physram::ALLOCATOR.lock();
+        for page in stack.range().step_by(PAGE_SIZE as usize) {
+            let frame = pm.alloc()?;
+            let mapped = unsafe {
+                mapping.map(
+                    page,
+                    frame,
+                    PAGE_SIZE,
+                    true,
+                    mmu::CacheType::WriteBack,
+                    &mut *pm,
+                )
+            };
+            if mapped.is_err() {
+                pm.free(frame);
+                return None;
+            }
+        }
+
+        drop(pm);
+        drop(mapping);
+        Some(stack)
+    }
+
+    fn guard(&self) -> u32 {
+        STACKS_START + self.slot * STACK_SLOT_SIZE
+    }
+
+    fn range(&self) -> Range<u32> {
+        let start = self.guard() + PAGE_SIZE;
+        start..start + STACK_SIZE as u32
+    }
+}
+
+impl Drop for Stack {
+    fn drop(&mut self) {
+        let mut mapping = mmu::kernel_mapping();
+        let mut pm = physram::ALLOCATOR.lock();
+        for page in self.range().step_by(PAGE_SIZE as usize) {
+            if let Some(frame) = mapping.translate(page) {
+                unsafe { mapping.unmap(page, PAGE_SIZE, &mut *pm) }
+                    .expect("Stack page should be mapped");
+                pm.free(frame);
+            }
+        }
+        drop(pm);
+        drop(mapping);
+
+        pagefault::unregister(self.guard());
+        STACK_SLOTS.fetch_and(!(1 << self.slot), Ordering::Release);
+    }
+}
+
+fn new_stack() -> Option<(u32, Stack)> {
+    let stack = Stack::new()?;
+    let range = stack.range();
+    let words = unsafe { core::slice::from_raw_parts_mut(range.start as *mut u32, STACK_SIZE / 4) };
+    let esp = context::init_stack(words, thread_start);
+    Some((esp, stack))
+}
+
+/// Id of the running thread, or None before `init`
+pub fn current() -> Option<ThreadId> {
+    scheduler().map(|sched| sched.current)
+}
+
+/// Name and stack bounds of the running thread, or None before `init`.
+/// Safety: reads the scheduler without locking, for the panic screen only
+pub(crate) unsafe fn steal_current() -> Option<(&'static str, Range<u32>)> {
+    let sched = &*SCHEDULER.get()?.steal();
+    let thread = sched.threads.get(&sched.current)?;
+    let stack = match &thread.stack {
+        Some(stack) => stack.range(),
+        None => crate::kernel_stack(),
+    };
+
+    Some((thread.name, stack))
+}
+
+/// Runs `f` on the locked scheduler, then switches to the next thread
+/// if `f` returns true. `f` decides what happens to the current thread
+/// by leaving it running or blocking it.
+fn reschedule(f: impl FnOnce(&mut Scheduler) -> bool) {
+    // Interrupts stay disabled from the decision until the switch,
+    // and each thread restores its own state once resumed
+    let irq_enabled = cpu::interrupts_enabled();
+    cpu::cli();
+
+    let switch = {
+        let mut sched = scheduler().expect("Scheduler should be initialized");
+        if f(&mut sched) {
+            sched.pick_next()
+        } else {
+            None
+        }
+    };
+
+    if let Some((old_esp, new_esp)) = switch {
+        unsafe { context::switch(old_esp, new_esp) };
+        finish_switch();
+    }
+
+    if irq_enabled {
+        cpu::sti();
+    }
+}
+
+/// Runs on the new thread after every switch
+fn finish_switch() {
+    // Dropped outside the scheduler lock
+    let dead = scheduler().and_then(|mut sched| sched.dead.take());
+    drop(dead);
+}
+
+extern "C" fn thread_start() -> ! {
+    finish_switch();
+    cpu::sti();
+
+    let entry = {
+        let mut sched = scheduler().expect("Scheduler should be initialized");
+        let current = sched.current;
+        sched.thread(current).entry.take()
+    };
+
+    if let Some(entry) = entry {
+        entry();
+    }
+
+    exit();
+}
+
+/// Handle to a spawned thread. Dropping it lets the thread run on
+/// without anyone waiting for it.
+pub struct JoinHandle {
+    id: ThreadId,
+    exited: Arc<AtomicFlag>,
+}
+
+impl JoinHandle {
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    /// Blocks until the thread has exited
+    pub fn join(self) {
+        self.exited.wait();
+    }
+}
+
+/// Starts a thread running `f` with its own stack. Fails if
+/// MAX_THREADS threads already exist or the stack can't be mapped.
+pub fn spawn(
+    name: &'static str,
+    priority: Priority,
+    f: impl FnOnce() + Send + 'static,
+) -> Result<JoinHandle, ()> {
+    if scheduler().map_or(0, |sched| sched.threads.len()) >= MAX_THREADS {
+        return Err(());
+    }
+
+    let (esp, stack) = new_stack().ok_or(())?;
+    let exited = Arc::new(AtomicFlag::new(false));
+    let id = {
+        let mut sched = scheduler().expect("Scheduler should be initialized");
+        let id = sched.add(Thread {
+            name,
+            priority,
+            state: State::Blocked,
+            esp,
+            stack: Some(stack),
+            entry: Some(Box::new(f)),
+            exited: Some(exited.clone()),
+        });
+        sched.make_ready(id);
+        id
+    };
+
+    // Runs the new thread right away if it outranks this one
+    preempt();
+    Ok(JoinHandle { id, exited })
+}
+
+/// Ends the current thread, waking whoever is joining it
+pub fn exit() -> ! {
+    let exited = scheduler().and_then(|mut sched| {
+        let current = sched.current;
+        sched.thread(current).exited.take()
+    });
+
+    // Joiners only need the thread to be done, not switched away from
+    if let Some(exited) = exited {
+        exited.notify();
+    }
+
+    reschedule(|sched| {
+        assert!(sched.current != sched.idle, "Idle thread should not exit");
+        sched.block_current(State::Finished);
+        true
+    });
+
+    unreachable!("Finished thread was resumed");
+}
+
+/// Gives up the rest of the time slice to other threads of the same
+/// or higher priority
+pub fn yield_now() {
+    if current().is_some() {
+        reschedule(|_| true);
+    }
+}
+
+/// Sleeps for at least `duration`. Before the scheduler is running,
+/// halts until the tick count catches up instead.
+pub fn sleep(duration: Duration) {
+    let until = time::ticks() + time::to_ticks(duration);
+
+    while time::ticks() < until {
+        if current().is_none() {
+            cpu::cli();
+            if time::ticks() < until {
+                cpu::sti_hlt();
+            } else {
+                cpu::sti();
+            }
+            continue;
+        }
+
+        reschedule(|sched| {
+            sched.block_current(State::Sleeping(until));
+            true
+        });
+    }
+}
+
+/// Wakes sleeping threads and accounts for the time slice.
+/// Called from the timer interrupt.
+pub(crate) fn tick(now: u64) {
+    let mut sched = match scheduler() {
+        Some(sched) => sched,
+        None => return,
+    };
+
+    // One pass over the threads, resuming after each one woken
+    let mut from = ThreadId(0);
+    while let Some(id) = sched
+        .threads
+        .range(from..)
+        .find(|(_, t)| matches!(t.state, State::Sleeping(until) if until <= now))
+        .map(|(&id, _)| id)
+    {
+        sched.make_ready(id);
+        from = ThreadId(id.0 + 1);
+    }
+
+    sched.slice = sched.slice.saturating_sub(1);
+    if sched.slice == 0 {
+        sched.need_resched = true;
+    }
+}
+
+/// Switches threads if the time slice ran out or a higher priority
+/// thread was woken. Called on the way out of interrupt handlers,
+/// after the interrupt has been acknowledged.
+pub fn preempt() {
+    let need_resched = scheduler().is_some_and(|sched| sched.need_resched);
+    if need_resched {
+        reschedule(|_| true);
+    }
+}
+
+/// Queue of threads waiting for a condition, which interrupt
+/// handlers and other threads signal with `wake_one` or `wake_all`
+pub struct WaitQueue {
+    waiters: SpinLock<IdQueue>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self {
+            waiters: SpinLock::new(IdQueue::new()),
+        }
+    }
+
+    /// Blocks until `cond` returns true. The condition is checked with
+    /// interrupts disabled, so a wakeup can't slip in between checking
+    /// it and blocking. Before the scheduler is running, halts until
+    /// an interrupt arrives instead.
+    pub fn wait_until(&self, mut cond: impl FnMut() -> bool) {
+        let irq_enabled = cpu::interrupts_enabled();
+
+        loop {
+            cpu::cli();
+            if cond() {
+                break;
+            }
+
+            match current() {
+                Some(id) => {
+                    self.waiters.lock().push_back(id);
+                    reschedule(|sched| {
+                        sched.block_current(State::Blocked);
+                        true
+                    });
+                }
+                None => cpu::sti_hlt(),
+            }
+        }
+
+        if irq_enabled {
+            cpu::sti();
+        }
+    }
+
+    /// Wakes the longest waiting thread, returning whether there was one
+    pub fn wake_one(&self) -> bool {
+        let id = self.waiters.lock().pop_front();
+        if let (Some(id), Some(mut sched)) = (id, scheduler()) {
+            sched.make_ready(id);
+        }
+
+        id.is_some()
+    }
+
+    pub fn wake_all(&self) {
+        let mut waiters = core::mem::replace(&mut *self.waiters.lock(), IdQueue::new());
+        if let Some(mut sched) = scheduler() {
+            while let Some(id) = waiters.pop_front() {
+                sched.make_ready(id);
+            }
+        }
+    }
+}
@@ -0,0 +1,338 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::time::Duration;
+
+use crate::cpu::{self, pic, pit};
+use crate::executor;
+use crate::sync::{OnceCell, SpinLock};
+use crate::thread;
+
+/// Rate of the PIT tick that drives sleeps, timeouts and timer callbacks
+pub const TICK_HZ: u32 = 1000;
+
+const MAX_TIMERS: usize = 16;
+
+/// PIT counts to sample while calibrating, about 10 ms
+const CALIBRATION_COUNTS: u32 = pit::BASE_HZ / 100;
+
+static TICKS: SpinLock<u64> = SpinLock::new(0);
+
+/// TSC rate in kHz and the TSC value at calibration
+static TSC: OnceCell<(u64, u64)> = OnceCell::new();
+
+#[derive(Copy, Clone)]
+struct Timer {
+    deadline: u64,
+    /// Reload interval in ticks for periodic timers
+    period: Option<u64>,
+    callback: fn(),
+}
+
+/// Identifies a pending timer, for use with `cancel`
+#[derive(Copy, Clone, Debug)]
+pub struct TimerId(usize);
+
+/// Pending timers, in the slots their TimerIds index
+struct Timers([Option<Timer>; MAX_TIMERS]);
+
+static TIMERS: SpinLock<Timers> = SpinLock::new(Timers::new());
+
+impl Timers {
+    const fn new() -> Self {
+        Self([None; MAX_TIMERS])
+    }
+
+    fn add(
+        &mut self,
+        now: u64,
+        delay: u64,
+        period: Option<u64>,
+        callback: fn(),
+    ) -> Result<TimerId, ()> {
+        let slot = self.0.iter().position(|t| t.is_none()).ok_or(())?;
+        self.0[slot] = Some(Timer {
+            deadline: now + delay,
+            period,
+            callback,
+        });
+        Ok(TimerId(slot))
+    }
+
+    fn cancel(&mut self, id: TimerId) {
+        self.0[id.0] = None;
+    }
+
+    /// Finds the first timer from slot `from` on that is due at `now`,
+    /// re-arming it if it's periodic. Returns its slot and callback.
+    fn take_expired(&mut self, from: usize, now: u64) -> Option<(usize, fn())> {
+        let (idx, timer) = self
+            .0
+            .iter()
+            .enumerate()
+            .skip(from)
+            .find_map(|(idx, t)| t.filter(|t| t.deadline <= now).map(|t| (idx, t)))?;
+
+        self.0[idx] = timer.period.map(|period| Timer {
+            deadline: now + period,
+            ..timer
+        });
+        Some((idx, timer.callback))
+    }
+}
+
+/// Starts the PIT tick and calibrates the TSC against it.
+/// The PIC must be initialized first.
+pub fn init() {
+    pit::set_periodic(TICK_HZ);
+
+    let khz = calibrate_tsc();
+    let _ = TSC.set((khz, cpu::rdtsc()));
+
+    pic::register(pit::IRQ, handle_irq).expect("PIT IRQ line should be free");
+}
+
+/// Measures the TSC rate by polling the PIT counter, which
+/// needs neither interrupts nor the PIT's other channels
+fn calibrate_tsc() -> u64 {
+    let reload = pit::reload() as u32;
+
+    let mut elapsed = 0;
+    let mut last = pit::read_count() as u32;
+    let start = cpu::rdtsc();
+
+    while elapsed < CALIBRATION_COUNTS {
+        let count = pit::read_count() as u32;
+        elapsed += if count <= last {
+            last - count
+        } else {
+            // Counter reloaded
+            last + reload - count
+        };
+        last = count;
+    }
+
+    let cycles = cpu::rdtsc() - start;
+    cycles * pit::BASE_HZ as u64 / elapsed as u64 / 1000
+}
+
+/// TSC rate in kHz, or 0 before calibration
+pub fn tsc_khz() -> u64 {
+    TSC.get().map_or(0, |&(khz, _)| khz)
+}
+
+/// Number of PIT ticks since `init`
+pub fn ticks() -> u64 {
+    *TICKS.lock()
+}
+
+fn tsc_to_us(cycles: u64) -> u64 {
+    let khz = tsc_khz();
+    // Split up to avoid overflowing the multiplication
+    (cycles / khz) * 1000 + (cycles % khz) * 1000 / khz
+}
+
+/// Monotonic time since `init`, with TSC resolution once calibrated
+pub fn uptime() -> Duration {
+    match TSC.get() {
+        Some(&(_, base)) => Duration::from_micros(tsc_to_us(cpu::rdtsc() - base)),
+        None => Duration::from_millis(ticks() * 1000 / TICK_HZ as u64),
+    }
+}
+
+pub fn uptime_ms() -> u64 {
+    uptime().as_millis() as u64
+}
+
+/// A point in time after which a wait should give up
+#[derive(Copy, Clone)]
+pub struct Deadline(u64);
+
+impl Deadline {
+    pub fn after(timeout: Duration) -> Self {
+        Self(uptime().as_micros() as u64 + timeout.as_micros() as u64)
+    }
+
+    /// Deadlines never expire before the clock is running,
+    /// so early callers keep their old unbounded behaviour
+    pub fn expired(&self) -> bool {
+        tsc_khz() != 0 && uptime().as_micros() as u64 >= self.0
+    }
+}
+
+/// Busy-waits for at least `us` microseconds. Before the TSC is
+/// calibrated there is no clock to wait on, so it returns right away.
+pub fn delay_us(us: u64) {
+    let cycles = us * tsc_khz() / 1000;
+    let start = cpu::rdtsc();
+    while cpu::rdtsc() - start < cycles {
+        core::hint::spin_loop();
+    }
+}
+
+pub fn delay_ms(ms: u64) {
+    delay_us(ms * 1000);
+}
+
+/// Sleeps with interrupts enabled for at least `ms` milliseconds,
+/// letting other threads run in the meantime
+pub fn sleep_ms(ms: u64) {
+    thread::sleep(Duration::from_millis(ms));
+}
+
+/// Converts a duration to a whole number of ticks, rounding up
+/// so that waits are never shorter than asked for
+pub fn to_ticks(duration: Duration) -> u64 {
+    (duration.as_micros() as u64 * TICK_HZ as u64)
+        .div_ceil(1_000_000)
+        .max(1)
+}
+
+fn add_timer(delay: Duration, period: Option<Duration>, callback: fn()) -> Result<TimerId, ()> {
+    let now = ticks();
+    TIMERS
+        .lock()
+        .add(now, to_ticks(delay), period.map(to_ticks), callback)
+}
+
+/// Runs `callback` once from the timer interrupt after `delay`
+pub fn after(delay: Duration, callback: fn()) -> Result<TimerId, ()> {
+    add_timer(delay, None, callback)
+}
+
+/// Runs `callback` from the timer interrupt every `period`
+pub fn every(period: Duration, callback: fn()) -> Result<TimerId, ()> {
+    add_timer(period, Some(period), callback)
+}
+
+pub fn cancel(id: TimerId) {
+    TIMERS.lock().cancel(id);
+}
+
+/// Checks that the tick runs one-shot and periodic timers, and that
+/// cancelled ones stop. Interrupts must be enabled.
+pub fn self_check() -> Result<(), &'static str> {
+    static ONE_SHOT: AtomicU32 = AtomicU32::new(0);
+    static PERIODIC: AtomicU32 = AtomicU32::new(0);
+
+    after(Duration::from_millis(2), || {
+        ONE_SHOT.fetch_add(1, Ordering::Relaxed);
+    })
+    .map_err(|()| "No free timer slot")?;
+    let periodic = every(Duration::from_millis(1), || {
+        PERIODIC.fetch_add(1, Ordering::Relaxed);
+    })
+    .map_err(|()| "No free timer slot")?;
+
+    // Spinning and blocking, the tick has to keep coming either way
+    delay_ms(5);
+    sleep_ms(5);
+    cancel(periodic);
+
+    if ONE_SHOT.load(Ordering::Relaxed) != 1 {
+        return Err("One-shot timer didn't fire exactly once");
+    }
+
+    let fired = PERIODIC.load(Ordering::Relaxed);
+    if fired < 2 {
+        return Err("Periodic timer didn't re-arm");
+    }
+
+    delay_ms(2);
+    if PERIODIC.load(Ordering::Relaxed) != fired {
+        return Err("Cancelled timer kept firing");
+    }
+
+    Ok(())
+}
+
+/// Advances the clock, runs expired timers and wakes whatever
+/// is waiting on it, registered on IRQ0
+fn handle_irq() {
+    let now = {
+        let mut ticks = TICKS.lock();
+        *ticks += 1;
+        *ticks
+    };
+
+    let mut from = 0;
+    loop {
+        // Callbacks run unlocked, so that they can add and cancel timers
+        let expired = TIMERS.lock().take_expired(from, now);
+        let Some((idx, callback)) = expired else {
+            break;
+        };
+
+        callback();
+        from = idx + 1;
+    }
+
+    executor::tick(now);
+    thread::tick(now);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn noop() {}
+
+    #[test]
+    fn one_shot_timers_expire_once() {
+        let mut timers = Timers::new();
+        let id = timers.add(10, 5, None, noop).unwrap();
+
+        assert!(timers.take_expired(0, 14).is_none());
+        assert_eq!(timers.take_expired(0, 15).map(|(idx, _)| idx), Some(id.0));
+        assert!(timers.take_expired(0, 100).is_none());
+
+        // The slot is free again
+        assert_eq!(timers.add(100, 1, None, noop).unwrap().0, id.0);
+    }
+
+    #[test]
+    fn periodic_timers_rearm() {
+        let mut timers = Timers::new();
+        timers.add(0, 3, Some(3), noop).unwrap();
+
+        assert!(timers.take_expired(0, 3).is_some());
+        assert!(timers.take_expired(0, 5).is_none());
+        assert!(timers.take_expired(0, 6).is_some());
+
+        // Late ticks re-arm from when the timer ran, not when it was due
+        assert!(timers.take_expired(0, 20).is_some());
+        assert!(timers.take_expired(0, 22).is_none());
+        assert!(timers.take_expired(0, 23).is_some());
+    }
+
+    #[test]
+    fn expired_timers_are_taken_in_slot_order() {
+        let mut timers = Timers::new();
+        let first = timers.add(0, 2, None, noop).unwrap();
+        let second = timers.add(0, 1, Some(1), noop).unwrap();
+
+        assert_eq!(timers.take_expired(0, 2).map(|(idx, _)| idx), Some(first.0));
+        assert_eq!(
+            timers.take_expired(first.0 + 1, 2).map(|(idx, _)| idx),
+            Some(second.0)
+        );
+        // Re-armed, but not due again until the next tick
+        assert!(timers.take_expired(second.0 + 1, 2).is_none());
+        assert!(timers.take_expired(0, 2).is_none());
+    }
+
+    #[test]
+    fn cancelled_timers_never_expire() {
+        let mut timers = Timers::new();
+        let id = timers.add(0, 1, Some(1), noop).unwrap();
+        timers.cancel(id);
+        assert!(timers.take_expired(0, 10).is_none());
+    }
+
+    #[test]
+    fn timer_slots_run_out() {
+        let mut timers = Timers::new();
+        for _ in 0..MAX_TIMERS {
+            timers.add(0, 1, None, noop).unwrap();
+        }
+        assert!(timers.add(0, 1, None, noop).is_err());
+    }
+}